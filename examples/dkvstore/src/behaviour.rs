@@ -0,0 +1,172 @@
+//! Network behaviour of a dkvstore node.
+//!
+//! NAT traversal can be tried out on loopback with a relay and two peers that
+//! cannot see each other directly (no mDNS):
+//!
+//! ```text
+//! dkvstore --relay-server --no-mdns --listen /ip4/127.0.0.1/tcp/4001 --external-address /ip4/127.0.0.1/tcp/4001
+//! dkvstore --mode auto --no-mdns --listen /ip4/127.0.0.1/tcp/0 --relay /ip4/127.0.0.1/tcp/4001/p2p/<relay id>
+//! dkvstore --mode auto --no-mdns --listen /ip4/127.0.0.1/tcp/0 --relay /ip4/127.0.0.1/tcp/4001/p2p/<relay id>
+//! ```
+
+use std::{error::Error, time::Duration};
+
+use clap::ValueEnum;
+use libp2p::{
+    allow_block_list::{self, BlockedPeers},
+    autonat,
+    connection_limits::{self, ConnectionLimits},
+    dcutr, identify,
+    identity::Keypair,
+    kad, mdns, noise, ping, relay,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    tcp, yamux, Swarm, SwarmBuilder,
+};
+
+use crate::{
+    keyspace::Keyspaces,
+    store::{DkvStore, StoreLimits},
+};
+
+pub const PROTOCOL_VERSION: &str = "/dkvstore/0.1.0";
+
+/// Kademlia's default lifetime of records and provider records
+const DEFAULT_RECORD_TTL: Duration = Duration::from_secs(48 * 60 * 60);
+
+/// Connections a single peer may hold open to us at once
+pub const MAX_CONNECTIONS_PER_PEER: u32 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NodeMode {
+    /// Always answer DHT requests and store records for other peers
+    Server,
+    /// Only issue queries through server peers; never store others' records
+    Light,
+    /// Start as a client and switch to server once an external address is confirmed
+    Auto,
+}
+
+/// Which optional parts of [`Behavior`] are switched on.
+#[derive(Debug, Clone, Copy)]
+pub struct BehaviourOptions {
+    pub mode: NodeMode,
+    pub mdns: bool,
+    pub relay_server: bool,
+    /// Republish records owned by this node at this interval, see
+    /// [`crate::ownership`]; `None` keeps Kademlia's own publication job
+    pub republish_interval: Option<Duration>,
+    /// Re-announce provider records at this interval instead of Kademlia's default
+    pub reprovide_interval: Option<Duration>,
+    /// Capacity of the record store
+    pub store: StoreLimits,
+}
+
+// NetworkBehaviour 派生宏：自动实现网络行为的委托和集成
+// 允许组合多个网络协议行为（Kademlia DHT + mDNS 服务发现 + NAT 穿透）
+#[derive(NetworkBehaviour)]
+pub struct Behavior {
+    pub blocked: allow_block_list::Behaviour<BlockedPeers>, // 被封禁的节点：断开并拒绝其连接
+    pub limits: connection_limits::Behaviour,               // 限制单个节点的连接数，防止连接洪泛
+    pub kademlia: kad::Behaviour<DkvStore>, // Kademlia 分布式哈希表：用于节点路由和数据存储
+    pub keyspaces: Keyspaces,               // 各租户独立的 Kademlia 实例，协议名互不相同
+    pub mdns: Toggle<mdns::tokio::Behaviour>, // mDNS 本地服务发现：在局域网内自动发现对等节点
+    pub identify: identify::Behaviour,      // 交换监听地址和观测地址，AutoNAT 和 DCUtR 都依赖它
+    pub ping: ping::Behaviour,              // 定期测量与已连接节点之间的 RTT
+    pub autonat: autonat::Behaviour,        // 请求其他节点回拨，判断自己是否可被公网访问
+    pub relay_client: relay::client::Behaviour, // 通过中继节点预留槽位，使 NAT 后的节点可被连接
+    pub relay: Toggle<relay::Behaviour>,    // 可选：为其他节点充当中继
+    pub dcutr: dcutr::Behaviour,            // 在中继连接上协调打洞，升级为直连
+}
+
+impl Behavior {
+    pub fn new(
+        key: &Keypair,
+        relay_client: relay::client::Behaviour,
+        options: BehaviourOptions,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let peer_id = key.public().to_peer_id();
+
+        let mdns = if options.mdns {
+            Some(mdns::tokio::Behaviour::new(
+                mdns::Config::default(),
+                peer_id,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            blocked: allow_block_list::Behaviour::default(),
+            limits: connection_limits::Behaviour::new(
+                ConnectionLimits::default()
+                    .with_max_established_per_peer(Some(MAX_CONNECTIONS_PER_PEER)),
+            ),
+            //Kademlia 分布式哈希表初始化：节点路由 | 去中心化数据存储 | 点对点服务发现
+            kademlia: kad::Behaviour::with_config(
+                peer_id,                               // 使用公钥生成唯一 PeerID
+                DkvStore::new(peer_id, options.store), // 内存存储 DHT 数据
+                kad_config(&options),
+            ),
+            keyspaces: Keyspaces::default(),
+            mdns: mdns.into(),
+            identify: identify::Behaviour::new(
+                identify::Config::new(PROTOCOL_VERSION.to_string(), key.public())
+                    .with_agent_version(format!("dkvstore/{}", env!("CARGO_PKG_VERSION"))),
+            ),
+            ping: ping::Behaviour::new(ping::Config::new()),
+            autonat: autonat::Behaviour::new(
+                peer_id,
+                autonat::Config {
+                    // 局域网 / 回环地址上也进行探测，便于本地测试
+                    only_global_ips: false,
+                    ..Default::default()
+                },
+            ),
+            relay_client,
+            relay: options
+                .relay_server
+                .then(|| relay::Behaviour::new(peer_id, relay::Config::default()))
+                .into(),
+            dcutr: dcutr::Behaviour::new(peer_id),
+        })
+    }
+}
+
+/// Builds a swarm over TCP and circuit relays for the given identity.
+pub fn new_swarm(
+    key: Keypair,
+    options: BehaviourOptions,
+) -> Result<Swarm<Behavior>, Box<dyn Error + Send + Sync>> {
+    // SwarmBuilder: 构建点对点网络的核心组件
+    let swarm = SwarmBuilder::with_existing_identity(key)
+        .with_tokio() // 使用 Tokio 运行时进行异步网络操作
+        .with_tcp(
+            tcp::Config::default(), // TCP 传输层配置：提供基础网络连接 |处理套接字创建和管理 | 支持 IPv4/IPv6
+            noise::Config::new,     // 安全传输层：Noise 协议，提供加密、身份验证和前向保密
+            yamux::Config::default, //多路复用协议：Yamux，在单个 TCP 连接上复用多个子流，提高网络效率和并发性
+        )?
+        .with_relay_client(noise::Config::new, yamux::Config::default)? // 中继传输：通过 /p2p-circuit 地址拨号和监听
+        .with_behaviour(|key, relay_client| Behavior::new(key, relay_client, options))?
+        // 空闲连接超时：释放未使用资源
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build();
+    Ok(swarm)
+}
+
+fn kad_config(options: &BehaviourOptions) -> kad::Config {
+    let mut config = kad::Config::new(kad::PROTOCOL_NAME);
+    if options.mode == NodeMode::Light {
+        // 轻节点即使收到 PUT 请求也不写入本地存储
+        config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    }
+    // 副本的 TTL 至少覆盖两个发布周期，错过一次重新发布也不会过期
+    if let Some(interval) = options.republish_interval {
+        config.set_publication_interval(None);
+        config.set_record_ttl(Some(DEFAULT_RECORD_TTL.max(interval * 2)));
+    }
+    if let Some(interval) = options.reprovide_interval {
+        config.set_provider_publication_interval(Some(interval));
+        config.set_provider_record_ttl(Some(DEFAULT_RECORD_TTL.max(interval * 2)));
+    }
+    config
+}
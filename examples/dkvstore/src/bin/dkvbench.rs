@@ -0,0 +1,596 @@
+//! Load generator for dkvstore: spawns a cluster in-process, waits for the
+//! routing tables to converge and runs PUT/GET workloads against it.
+//!
+//! ```text
+//! cargo run --release -p dkvstore --bin dkvbench -- --nodes 50 --keys 10000 --distribution zipf --json report.json
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use libp2p::{identity::Keypair, kad};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Serialize;
+use tracing_subscriber::EnvFilter;
+
+use dkvstore::{
+    behaviour::{new_swarm, BehaviourOptions, NodeMode},
+    cluster::ClusterNode,
+    gateway::{parse_quorum, GatewayError},
+    store::{Rejections, StoreLimits, DEFAULT_MAX_PROVIDED_KEYS},
+};
+
+#[derive(Debug, Parser, Serialize)]
+#[command(name = "dkvbench", about = "Benchmark an in-process dkvstore cluster")]
+struct Args {
+    /// Number of nodes in the cluster
+    #[arg(long, default_value_t = 20)]
+    nodes: usize,
+
+    /// Number of distinct keys, all written once before the workloads run
+    #[arg(long, default_value_t = 1000)]
+    keys: usize,
+
+    /// Operations per workload; one workload runs per quorum
+    #[arg(long, default_value_t = 2000)]
+    ops: usize,
+
+    /// Operations in flight at the same time
+    #[arg(long, default_value_t = 32)]
+    concurrency: usize,
+
+    /// Share of GETs among the operations, the rest are PUTs
+    #[arg(long, default_value_t = 0.8)]
+    read_ratio: f64,
+
+    /// How keys are picked for each operation
+    #[arg(long, value_enum, default_value_t = Distribution::Uniform)]
+    distribution: Distribution,
+
+    /// Exponent of the zipf distribution; higher means fewer, hotter keys
+    #[arg(long, default_value_t = 1.0)]
+    zipf_exponent: f64,
+
+    /// Size of every written value in bytes
+    #[arg(long, default_value_t = 64)]
+    value_size: usize,
+
+    /// PUT quorums to run a workload with: one, majority, all or a number.
+    /// Kademlia counts majority and all against its replication factor (20),
+    /// so clusters smaller than that can never reach all
+    #[arg(long, value_delimiter = ',', default_value = "one,majority,all")]
+    quorums: Vec<String>,
+
+    /// Records each node's store holds at most. A node keeps about
+    /// `20 * keys / nodes` replicas; past this limit it refuses them, which
+    /// shows up as store rejections instead of DHT behaviour
+    #[arg(long, default_value_t = 65_536)]
+    max_records: usize,
+
+    /// Seconds a single operation may take before it counts as timed out
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+
+    /// Seconds to wait for the routing tables to converge
+    #[arg(long, default_value_t = 60)]
+    converge_timeout: u64,
+
+    /// Seed for the workload, so that runs can be compared
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+
+    /// Also write the report as JSON to this file
+    #[arg(long, value_name = "PATH")]
+    json: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Distribution {
+    Uniform,
+    Zipf,
+}
+
+#[derive(Debug, Serialize)]
+struct Report {
+    config: Args,
+    convergence: Convergence,
+    phases: Vec<PhaseReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct Convergence {
+    /// Routing table size every node has to reach
+    target_peers: usize,
+    /// `None` if the target was not reached in time
+    converged_ms: Option<u64>,
+    min_peers: usize,
+    avg_peers: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct PhaseReport {
+    name: String,
+    ops: usize,
+    duration_ms: u64,
+    ops_per_sec: f64,
+    get: OpStats,
+    put: OpStats,
+    /// Writes the nodes' stores refused during the phase, summed over all
+    /// nodes; a refusing replica makes a PUT fail its quorum
+    store_rejections: Rejections,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct OpStats {
+    count: usize,
+    success: usize,
+    success_rate: f64,
+    latency_ms: Percentiles,
+    /// Failed operations by cause
+    errors: BTreeMap<&'static str, usize>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+    max: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum OpKind {
+    Get,
+    Put,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Op {
+    node: usize,
+    kind: OpKind,
+    key: usize,
+}
+
+struct Sample {
+    kind: OpKind,
+    latency: Duration,
+    result: Result<(), &'static str>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let args = Args::parse();
+    if args.nodes < 2 || args.keys == 0 || args.concurrency == 0 {
+        return Err(anyhow!(
+            "need at least 2 nodes, 1 key and 1 operation in flight"
+        ));
+    }
+    let quorums = args
+        .quorums
+        .iter()
+        .map(|q| {
+            parse_quorum(Some(q))
+                .map(|quorum| (q.clone(), quorum))
+                .map_err(|_| anyhow!("invalid quorum {:?}", q))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let timeout = Duration::from_secs(args.timeout);
+
+    println!("Starting {} nodes", args.nodes);
+    let nodes = Arc::new(start_cluster(args.nodes, args.max_records).await?);
+    let convergence =
+        wait_for_convergence(&nodes, Duration::from_secs(args.converge_timeout)).await;
+    match convergence.converged_ms {
+        Some(ms) => println!(
+            "Routing tables converged to {} peers in {} ms",
+            convergence.target_peers, ms
+        ),
+        None => println!(
+            "Routing tables did not converge to {} peers (min {}, avg {:.1})",
+            convergence.target_peers, convergence.min_peers, convergence.avg_peers
+        ),
+    }
+
+    let mut rng = StdRng::seed_from_u64(args.seed);
+    let keys = KeyChooser::new(&args);
+    let mut phases = Vec::new();
+
+    // 先写入全部 key，之后的 GET 才有数据可读
+    let load: Vec<Op> = (0..args.keys)
+        .map(|key| Op {
+            node: key % args.nodes,
+            kind: OpKind::Put,
+            key,
+        })
+        .collect();
+    phases.push(
+        run_phase(
+            "load (quorum one)",
+            &nodes,
+            load,
+            kad::Quorum::One,
+            &args,
+            timeout,
+        )
+        .await,
+    );
+
+    for (name, quorum) in quorums {
+        let ops = (0..args.ops)
+            .map(|_| Op {
+                node: rng.gen_range(0..args.nodes),
+                kind: if rng.gen_bool(args.read_ratio.clamp(0.0, 1.0)) {
+                    OpKind::Get
+                } else {
+                    OpKind::Put
+                },
+                key: keys.sample(&mut rng),
+            })
+            .collect();
+        let label = format!("{:?} (quorum {})", args.distribution, name).to_lowercase();
+        phases.push(run_phase(&label, &nodes, ops, quorum, &args, timeout).await);
+    }
+
+    let report = Report {
+        config: args,
+        convergence,
+        phases,
+    };
+    print_report(&report);
+    if let Some(path) = &report.config.json {
+        std::fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+        println!("\nJSON report written to {}", path.display());
+    }
+    Ok(())
+}
+
+async fn start_cluster(count: usize, max_records: usize) -> Result<Vec<ClusterNode>> {
+    let mut nodes: Vec<ClusterNode> = Vec::with_capacity(count);
+    for _ in 0..count {
+        let swarm = new_swarm(
+            Keypair::generate_ed25519(),
+            BehaviourOptions {
+                mode: NodeMode::Server,
+                mdns: false,
+                relay_server: false,
+                republish_interval: None,
+                reprovide_interval: None,
+                store: StoreLimits {
+                    max_records,
+                    max_provided_keys: DEFAULT_MAX_PROVIDED_KEYS,
+                },
+            },
+        )
+        .map_err(|e| anyhow!(e))?;
+        // 所有节点都只认识第一个节点，路由表靠 Kademlia 自己补全
+        let bootstrap: Vec<_> = nodes
+            .first()
+            .map(|first| (first.peer_id, first.addr.clone()))
+            .into_iter()
+            .collect();
+        nodes.push(ClusterNode::spawn(swarm, "/ip4/127.0.0.1/tcp/0".parse()?, &bootstrap).await?);
+    }
+    Ok(nodes)
+}
+
+async fn wait_for_convergence(nodes: &[ClusterNode], timeout: Duration) -> Convergence {
+    // Kademlia 的桶大小，小路由表一定容得下这么多节点
+    let target_peers = (nodes.len() - 1).min(kad::K_VALUE.get());
+    let started = Instant::now();
+    loop {
+        let mut sizes = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let size = node
+                .routing_table(Duration::from_secs(5))
+                .await
+                .map_or(0, |peers| peers.len());
+            sizes.push(size);
+        }
+        let min_peers = sizes.iter().copied().min().unwrap_or(0);
+        let converged = min_peers >= target_peers;
+        if converged || started.elapsed() >= timeout {
+            return Convergence {
+                target_peers,
+                converged_ms: converged.then(|| started.elapsed().as_millis() as u64),
+                min_peers,
+                avg_peers: sizes.iter().sum::<usize>() as f64 / sizes.len() as f64,
+            };
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn run_phase(
+    name: &str,
+    nodes: &Arc<Vec<ClusterNode>>,
+    ops: Vec<Op>,
+    quorum: kad::Quorum,
+    args: &Args,
+    timeout: Duration,
+) -> PhaseReport {
+    println!("Running {} ({} operations)", name, ops.len());
+    let ops = Arc::new(ops);
+    let next = Arc::new(AtomicUsize::new(0));
+    let value = vec![b'x'; args.value_size];
+    let rejected_before = store_rejections(nodes).await;
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..args.concurrency)
+        .map(|_| {
+            let (nodes, ops, next, value) =
+                (nodes.clone(), ops.clone(), next.clone(), value.clone());
+            tokio::spawn(async move {
+                let mut samples = Vec::new();
+                loop {
+                    let Some(op) = ops.get(next.fetch_add(1, Ordering::Relaxed)).copied() else {
+                        return samples;
+                    };
+                    let node = &nodes[op.node];
+                    let key = kad::RecordKey::new(&format!("key-{}", op.key));
+                    let op_started = Instant::now();
+                    let result = match op.kind {
+                        OpKind::Get => node.get(key, timeout).await.map(|_| ()),
+                        OpKind::Put => {
+                            let record = kad::Record::new(key, value.clone());
+                            node.put(record, quorum, timeout).await
+                        }
+                    };
+                    samples.push(Sample {
+                        kind: op.kind,
+                        latency: op_started.elapsed(),
+                        result: result.map_err(|e| error_name(&e)),
+                    });
+                }
+            })
+        })
+        .collect();
+
+    let mut gets = Vec::new();
+    let mut puts = Vec::new();
+    for worker in workers {
+        for sample in worker.await.unwrap_or_default() {
+            match sample.kind {
+                OpKind::Get => gets.push(sample),
+                OpKind::Put => puts.push(sample),
+            }
+        }
+    }
+    let duration = started.elapsed();
+    let rejected_after = store_rejections(nodes).await;
+    PhaseReport {
+        name: name.to_string(),
+        ops: ops.len(),
+        duration_ms: duration.as_millis() as u64,
+        ops_per_sec: ops.len() as f64 / duration.as_secs_f64(),
+        get: op_stats(&gets),
+        put: op_stats(&puts),
+        store_rejections: Rejections {
+            full: rejected_after.full - rejected_before.full,
+            leased: rejected_after.leased - rejected_before.leased,
+        },
+    }
+}
+
+/// Writes refused by the stores of all nodes so far.
+async fn store_rejections(nodes: &[ClusterNode]) -> Rejections {
+    let mut total = Rejections::default();
+    for node in nodes {
+        if let Ok(status) = node.store_status(Duration::from_secs(5)).await {
+            total.full += status.rejections.full;
+            total.leased += status.rejections.leased;
+        }
+    }
+    total
+}
+
+fn error_name(error: &GatewayError) -> &'static str {
+    match error {
+        GatewayError::NotFound => "not_found",
+        GatewayError::QuorumFailed => "quorum_failed",
+        GatewayError::Timeout => "timeout",
+        GatewayError::BadRequest(_) => "bad_request",
+        GatewayError::Leased => "leased",
+        GatewayError::StoreFull => "store_full",
+        GatewayError::Internal(_) => "internal",
+    }
+}
+
+fn op_stats(samples: &[Sample]) -> OpStats {
+    if samples.is_empty() {
+        return OpStats::default();
+    }
+    let latencies = samples
+        .iter()
+        .map(|s| s.latency.as_secs_f64() * 1000.0)
+        .collect();
+
+    let mut errors = BTreeMap::new();
+    for sample in samples {
+        if let Err(name) = sample.result {
+            *errors.entry(name).or_default() += 1;
+        }
+    }
+    let success = samples.iter().filter(|s| s.result.is_ok()).count();
+    OpStats {
+        count: samples.len(),
+        success,
+        success_rate: success as f64 / samples.len() as f64,
+        latency_ms: percentiles(latencies),
+        errors,
+    }
+}
+
+/// Nearest-rank percentiles of `latencies`, all zero without any.
+fn percentiles(mut latencies: Vec<f64>) -> Percentiles {
+    if latencies.is_empty() {
+        return Percentiles::default();
+    }
+    latencies.sort_by(f64::total_cmp);
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+    Percentiles {
+        p50: percentile(0.5),
+        p90: percentile(0.9),
+        p99: percentile(0.99),
+        max: percentile(1.0),
+    }
+}
+
+fn print_report(report: &Report) {
+    println!();
+    println!(
+        "{:<24} {:>6} {:>9} {:>4} {:>7} {:>8} {:>8} {:>8} {:>8}",
+        "phase", "ops", "ops/s", "op", "success", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for phase in &report.phases {
+        for (op, stats) in [("GET", &phase.get), ("PUT", &phase.put)] {
+            if stats.count == 0 {
+                continue;
+            }
+            println!(
+                "{:<24} {:>6} {:>9.1} {:>4} {:>6.1}% {:>8.1} {:>8.1} {:>8.1} {:>8.1}",
+                phase.name,
+                stats.count,
+                phase.ops_per_sec,
+                op,
+                stats.success_rate * 100.0,
+                stats.latency_ms.p50,
+                stats.latency_ms.p90,
+                stats.latency_ms.p99,
+                stats.latency_ms.max
+            );
+            if !stats.errors.is_empty() {
+                println!("{:<24} errors: {:?}", "", stats.errors);
+            }
+        }
+        let rejected = phase.store_rejections;
+        if rejected.full > 0 || rejected.leased > 0 {
+            println!(
+                "{:<24} store rejections: {} full, {} leased",
+                "", rejected.full, rejected.leased
+            );
+        }
+    }
+}
+
+/// Picks key indices; index 0 is the hottest key under zipf.
+enum KeyChooser {
+    Uniform(usize),
+    /// Cumulative probabilities of the keys
+    Zipf(Vec<f64>),
+}
+
+impl KeyChooser {
+    fn new(args: &Args) -> Self {
+        match args.distribution {
+            Distribution::Uniform => KeyChooser::Uniform(args.keys),
+            Distribution::Zipf => {
+                let weights: Vec<f64> = (1..=args.keys)
+                    .map(|rank| 1.0 / (rank as f64).powf(args.zipf_exponent))
+                    .collect();
+                let total: f64 = weights.iter().sum();
+                let mut cumulative = 0.0;
+                KeyChooser::Zipf(
+                    weights
+                        .iter()
+                        .map(|w| {
+                            cumulative += w / total;
+                            cumulative
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+
+    fn sample(&self, rng: &mut StdRng) -> usize {
+        match self {
+            KeyChooser::Uniform(keys) => rng.gen_range(0..*keys),
+            KeyChooser::Zipf(cdf) => {
+                let u: f64 = rng.gen();
+                cdf.partition_point(|&c| c < u).min(cdf.len() - 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chooser(distribution: &str, keys: usize) -> KeyChooser {
+        let keys = keys.to_string();
+        let args = Args::parse_from(["dkvbench", "--distribution", distribution, "--keys", &keys]);
+        KeyChooser::new(&args)
+    }
+
+    fn counts(chooser: &KeyChooser, keys: usize, samples: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut counts = vec![0; keys];
+        for _ in 0..samples {
+            counts[chooser.sample(&mut rng)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn uniform_keys_are_spread_evenly() {
+        let counts = counts(&chooser("uniform", 10), 10, 10_000);
+        assert!(
+            counts.iter().all(|&c| (800..1200).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn zipf_keys_favour_the_first() {
+        let zipf = chooser("zipf", 10);
+        let KeyChooser::Zipf(cdf) = &zipf else {
+            panic!("not zipf");
+        };
+        assert!(cdf.windows(2).all(|w| w[0] < w[1]));
+        assert!((cdf[9] - 1.0).abs() < 1e-9);
+
+        let hits = counts(&zipf, 10, 10_000);
+        assert!(hits.windows(2).all(|w| w[0] > w[1]), "{:?}", hits);
+        // 指数为 1 时第一个 key 占 1/H(10)，约 34%
+        assert!((3000..3800).contains(&hits[0]), "{:?}", hits);
+
+        assert_eq!(counts(&chooser("zipf", 1), 1, 100), [100]);
+    }
+
+    #[test]
+    fn percentiles_pick_the_nearest_rank() {
+        let empty = percentiles(Vec::new());
+        assert_eq!((empty.p50, empty.p99, empty.max), (0.0, 0.0, 0.0));
+
+        let single = percentiles(vec![4.0]);
+        assert_eq!(
+            (single.p50, single.p90, single.p99, single.max),
+            (4.0, 4.0, 4.0, 4.0)
+        );
+
+        let mut latencies: Vec<f64> = (1..=100).map(f64::from).collect();
+        latencies.reverse();
+        let hundred = percentiles(latencies);
+        assert_eq!(
+            (hundred.p50, hundred.p90, hundred.p99, hundred.max),
+            (51.0, 90.0, 99.0, 100.0)
+        );
+        assert_eq!(op_stats(&[]).latency_ms.max, 0.0);
+    }
+}
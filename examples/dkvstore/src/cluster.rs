@@ -0,0 +1,180 @@
+//! Nodes running in-process, driven through the same requests as the HTTP
+//! gateway. Used by the benchmark and test scenario binaries.
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{identify, kad, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::{
+    behaviour::{Behavior, BehaviorEvent},
+    gateway::{GatewayError, GatewayRequest, PeerEntry, PendingQueries, Reply, StoreStatus},
+    ownership::Ownership,
+};
+
+/// Handle to a node running on its own task; dropping it stops the node.
+pub struct ClusterNode {
+    pub peer_id: PeerId,
+    pub addr: Multiaddr,
+    requests: mpsc::Sender<GatewayRequest>,
+    task: JoinHandle<()>,
+}
+
+impl ClusterNode {
+    /// Starts listening on `listen`, learns the `bootstrap` peers and runs the
+    /// node until the handle is dropped.
+    pub async fn spawn(
+        mut swarm: Swarm<Behavior>,
+        listen: Multiaddr,
+        bootstrap: &[(PeerId, Multiaddr)],
+    ) -> anyhow::Result<Self> {
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .set_mode(Some(kad::Mode::Server));
+        swarm.listen_on(listen)?;
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+        let peer_id = *swarm.local_peer_id();
+        for (peer, addr) in bootstrap {
+            if *peer != peer_id {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(peer, addr.clone());
+            }
+        }
+        if !bootstrap.is_empty() {
+            swarm.behaviour_mut().kademlia.bootstrap()?;
+        }
+
+        let (requests, rx) = mpsc::channel(256);
+        let task = tokio::spawn(run(swarm, rx));
+        Ok(Self {
+            peer_id,
+            addr,
+            requests,
+            task,
+        })
+    }
+
+    pub async fn call<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> GatewayRequest,
+        timeout: Duration,
+    ) -> Result<T, GatewayError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(request(tx))
+            .await
+            .map_err(|_| GatewayError::Internal("node stopped".to_string()))?;
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(GatewayError::Internal("request dropped".to_string())),
+            Err(_) => Err(GatewayError::Timeout),
+        }
+    }
+
+    pub async fn put(
+        &self,
+        record: kad::Record,
+        quorum: kad::Quorum,
+        timeout: Duration,
+    ) -> Result<(), GatewayError> {
+        self.call(
+            |reply| GatewayRequest::PutRecord {
+                record,
+                quorum,
+                reply,
+            },
+            timeout,
+        )
+        .await
+    }
+
+    pub async fn get(
+        &self,
+        key: kad::RecordKey,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, GatewayError> {
+        self.call(
+            |reply| GatewayRequest::GetRecord {
+                key,
+                quorum: kad::Quorum::One,
+                reply,
+            },
+            timeout,
+        )
+        .await
+    }
+
+    pub async fn routing_table(&self, timeout: Duration) -> Result<Vec<PeerEntry>, GatewayError> {
+        self.call(|reply| GatewayRequest::Peers { reply }, timeout)
+            .await
+    }
+
+    pub async fn store_status(&self, timeout: Duration) -> Result<StoreStatus, GatewayError> {
+        self.call(|reply| GatewayRequest::Store { reply }, timeout)
+            .await
+    }
+
+    /// Stops the node and waits until its swarm, and so its listeners, are gone.
+    pub async fn stop(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for ClusterNode {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn run(mut swarm: Swarm<Behavior>, mut requests: mpsc::Receiver<GatewayRequest>) {
+    let mut pending = PendingQueries::default();
+    // 集群节点不跑重新发布的定时器，这里只为网关的写入记账
+    let mut ownership = Ownership::new(
+        Duration::from_secs(3600),
+        Duration::from_secs(3600),
+        kad::Quorum::One,
+    );
+    loop {
+        select! {
+            request = requests.recv() => match request {
+                Some(request) => pending.start(&mut swarm, &mut ownership, request),
+                None => return,
+            },
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                    id,
+                    result,
+                    step,
+                    ..
+                })) => {
+                    ownership.on_query_progressed(id, &result);
+                    let kademlia = &mut swarm.behaviour_mut().kademlia;
+                    pending.on_query_progressed(kademlia, id, &result, &step);
+                }
+                SwarmEvent::Behaviour(BehaviorEvent::Identify(identify::Event::Received {
+                    peer_id,
+                    info,
+                    ..
+                })) if info.protocols.contains(&kad::PROTOCOL_NAME) => {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                    }
+                }
+                _ => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,613 @@
+//! Conflict-free replicated values stored inside `kad::Record`s.
+//!
+//! Every update reads all replicas reachable through a `GET` query, merges
+//! them, applies the operation and puts the merged state back, so concurrent
+//! writers never lose each other's updates. A plain `GET` on a CRDT key merges
+//! and republishes as well, repairing replicas that diverged.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::kad;
+use serde::{Deserialize, Serialize};
+
+use crate::{errln, outln, ownership::Ownership, store::DkvStore};
+
+/// Grow-only counter: one monotonically increasing slot per actor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, actor: &str, by: u64) {
+        let slot = self.counts.entry(actor.to_string()).or_default();
+        *slot = slot.saturating_add(by);
+    }
+
+    /// The sum of all slots, saturating at `u64::MAX`.
+    pub fn value(&self) -> u64 {
+        self.counts
+            .values()
+            .fold(0u64, |sum, &count| sum.saturating_add(count))
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (actor, &count) in &other.counts {
+            let slot = self.counts.entry(actor.clone()).or_default();
+            *slot = (*slot).max(count);
+        }
+    }
+}
+
+/// Counter supporting decrements, built from two grow-only counters.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PNCounter {
+    p: GCounter,
+    n: GCounter,
+}
+
+impl PNCounter {
+    pub fn add(&mut self, actor: &str, delta: i64) {
+        if delta >= 0 {
+            self.p.increment(actor, delta as u64);
+        } else {
+            self.n.increment(actor, delta.unsigned_abs());
+        }
+    }
+
+    /// The difference of increments and decrements, clamped to `i64`.
+    pub fn value(&self) -> i64 {
+        let value = i128::from(self.p.value()) - i128::from(self.n.value());
+        value.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.p.merge(&other.p);
+        self.n.merge(&other.n);
+    }
+}
+
+/// Observed-remove set: every add gets a unique tag, a remove tombstones the
+/// tags it has seen, so an add concurrent with a remove wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ORSet {
+    adds: BTreeMap<String, BTreeSet<String>>,
+    removed: BTreeSet<String>,
+    clock: BTreeMap<String, u64>,
+}
+
+impl ORSet {
+    pub fn add(&mut self, actor: &str, member: &str) {
+        let seq = self.clock.entry(actor.to_string()).or_default();
+        *seq = seq.saturating_add(1);
+        self.adds
+            .entry(member.to_string())
+            .or_default()
+            .insert(format!("{}:{}", actor, seq));
+    }
+
+    pub fn remove(&mut self, member: &str) {
+        if let Some(tags) = self.adds.get(member) {
+            self.removed.extend(tags.iter().cloned());
+        }
+    }
+
+    pub fn members(&self) -> Vec<&str> {
+        self.adds
+            .iter()
+            .filter(|(_, tags)| tags.iter().any(|t| !self.removed.contains(t)))
+            .map(|(member, _)| member.as_str())
+            .collect()
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (member, tags) in &other.adds {
+            self.adds
+                .entry(member.clone())
+                .or_default()
+                .extend(tags.iter().cloned());
+        }
+        self.removed.extend(other.removed.iter().cloned());
+        for (actor, &seq) in &other.clock {
+            let slot = self.clock.entry(actor.clone()).or_default();
+            *slot = (*slot).max(seq);
+        }
+    }
+}
+
+/// Last-writer-wins register; ties on the timestamp are broken by actor.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LWWRegister {
+    value: String,
+    timestamp: u64,
+    actor: String,
+}
+
+impl LWWRegister {
+    pub fn set(&mut self, actor: &str, value: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        // 本地时钟回拨时仍保证新写入覆盖旧值
+        self.timestamp = now.max(self.timestamp.saturating_add(1));
+        self.actor = actor.to_string();
+        self.value = value.to_string();
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        if (other.timestamp, &other.actor) > (self.timestamp, &self.actor) {
+            *self = other.clone();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "crdt", rename_all = "snake_case")]
+pub enum CrdtValue {
+    GCounter(GCounter),
+    PnCounter(PNCounter),
+    OrSet(ORSet),
+    LwwRegister(LWWRegister),
+}
+
+const TAG: &[u8] = b"{\"crdt\":";
+
+impl CrdtValue {
+    /// Returns `None` for plain values that were not written as a CRDT.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if !bytes.starts_with(TAG) {
+            return None;
+        }
+        serde_json::from_slice(bytes).ok()
+    }
+
+    /// Whether `bytes` claim to be a CRDT value but do not decode as one.
+    pub fn is_malformed(bytes: &[u8]) -> bool {
+        bytes.starts_with(TAG) && Self::decode(bytes).is_none()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("CRDT values always serialize")
+    }
+
+    fn type_name(&self) -> &'static str {
+        match self {
+            CrdtValue::GCounter(_) => "g-counter",
+            CrdtValue::PnCounter(_) => "pn-counter",
+            CrdtValue::OrSet(_) => "or-set",
+            CrdtValue::LwwRegister(_) => "lww-register",
+        }
+    }
+
+    /// Merges a replica of the same type; replicas of another type are ignored.
+    pub fn merge(&mut self, other: &Self) -> bool {
+        match (self, other) {
+            (CrdtValue::GCounter(a), CrdtValue::GCounter(b)) => a.merge(b),
+            (CrdtValue::PnCounter(a), CrdtValue::PnCounter(b)) => a.merge(b),
+            (CrdtValue::OrSet(a), CrdtValue::OrSet(b)) => a.merge(b),
+            (CrdtValue::LwwRegister(a), CrdtValue::LwwRegister(b)) => a.merge(b),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn display(&self) -> String {
+        match self {
+            CrdtValue::GCounter(c) => c.value().to_string(),
+            CrdtValue::PnCounter(c) => c.value().to_string(),
+            CrdtValue::OrSet(s) => format!("{{{}}}", s.members().join(", ")),
+            CrdtValue::LwwRegister(r) => r.value().to_string(),
+        }
+    }
+}
+
+/// An update applied to the merged state once all replicas were read.
+#[derive(Debug, Clone)]
+pub enum CrdtOp {
+    /// `GINCR <key> [n]`
+    GrowCounter(u64),
+    /// `INCR <key> [n]` / `DECR <key> [n]`
+    AddCounter(i64),
+    /// `SADD <key> <member>`
+    SetAdd(String),
+    /// `SREM <key> <member>`
+    SetRemove(String),
+    /// `LWWSET <key> <value>`
+    RegisterSet(String),
+}
+
+impl CrdtOp {
+    fn command(&self) -> &'static str {
+        match self {
+            CrdtOp::GrowCounter(_) => "GINCR",
+            CrdtOp::AddCounter(n) if *n < 0 => "DECR",
+            CrdtOp::AddCounter(_) => "INCR",
+            CrdtOp::SetAdd(_) => "SADD",
+            CrdtOp::SetRemove(_) => "SREM",
+            CrdtOp::RegisterSet(_) => "LWWSET",
+        }
+    }
+
+    fn empty_value(&self) -> CrdtValue {
+        match self {
+            CrdtOp::GrowCounter(_) => CrdtValue::GCounter(GCounter::default()),
+            CrdtOp::AddCounter(_) => CrdtValue::PnCounter(PNCounter::default()),
+            CrdtOp::SetAdd(_) | CrdtOp::SetRemove(_) => CrdtValue::OrSet(ORSet::default()),
+            CrdtOp::RegisterSet(_) => CrdtValue::LwwRegister(LWWRegister::default()),
+        }
+    }
+
+    fn apply(&self, actor: &str, value: &mut CrdtValue) -> Result<(), String> {
+        match (self, value) {
+            (CrdtOp::GrowCounter(n), CrdtValue::GCounter(c)) => c.increment(actor, *n),
+            (CrdtOp::AddCounter(n), CrdtValue::PnCounter(c)) => c.add(actor, *n),
+            (CrdtOp::SetAdd(m), CrdtValue::OrSet(s)) => s.add(actor, m),
+            (CrdtOp::SetRemove(m), CrdtValue::OrSet(s)) => s.remove(m),
+            (CrdtOp::RegisterSet(v), CrdtValue::LwwRegister(r)) => r.set(actor, v),
+            (op, value) => {
+                return Err(format!(
+                    "{} cannot be applied to a {}",
+                    op.command(),
+                    value.type_name()
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+struct PendingRead {
+    key: kad::RecordKey,
+    op: Option<CrdtOp>,
+    replicas: Vec<CrdtValue>,
+    saw_plain_value: bool,
+}
+
+/// `GET` queries whose replicas are merged when the query completes.
+#[derive(Default)]
+pub struct CrdtQueries {
+    pending: HashMap<kad::QueryId, PendingRead>,
+}
+
+impl CrdtQueries {
+    /// Starts reading all replicas of `key`; `op` is applied to the merged state.
+    pub fn read(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        key: kad::RecordKey,
+        op: Option<CrdtOp>,
+    ) {
+        let id = kademlia.get_record(key.clone());
+        self.pending.insert(
+            id,
+            PendingRead {
+                key,
+                op,
+                replicas: Vec::new(),
+                saw_plain_value: false,
+            },
+        );
+    }
+
+    /// Returns `true` if the result was consumed here; plain values are left
+    /// to the regular GET output. Updates are written back through
+    /// `ownership`, repaired replicas are not.
+    pub fn on_query_progressed(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        ownership: &mut Ownership,
+        id: kad::QueryId,
+        result: &kad::QueryResult,
+        step: &kad::ProgressStep,
+    ) -> bool {
+        let kad::QueryResult::GetRecord(result) = result else {
+            return false;
+        };
+        let Some(pending) = self.pending.get_mut(&id) else {
+            return false;
+        };
+
+        let mut consumed = false;
+        if let Ok(kad::GetRecordOk::FoundRecord(peer_record)) = result {
+            match CrdtValue::decode(&peer_record.record.value) {
+                Some(value) => {
+                    pending.replicas.push(value);
+                    consumed = true;
+                }
+                None => pending.saw_plain_value = true,
+            }
+        }
+        if !step.last {
+            return consumed;
+        }
+
+        let pending = self.pending.remove(&id).expect("checked above");
+        if pending.replicas.is_empty() && pending.op.is_none() {
+            return consumed;
+        }
+        finish(kademlia, ownership, pending);
+        true
+    }
+}
+
+fn finish(
+    kademlia: &mut kad::Behaviour<DkvStore>,
+    ownership: &mut Ownership,
+    pending: PendingRead,
+) {
+    let key_str = String::from_utf8_lossy(pending.key.as_ref()).into_owned();
+    if pending.saw_plain_value && pending.op.is_some() {
+        errln!("Key {} holds a plain value, not a CRDT", key_str);
+        return;
+    }
+
+    let (mut merged, diverged) = match (merge_replicas(&key_str, pending.replicas), &pending.op) {
+        (Some(merged), _) => merged,
+        (None, Some(op)) => (op.empty_value(), false),
+        (None, None) => return,
+    };
+
+    if let Some(op) = &pending.op {
+        let actor = kademlia.store_mut().local_peer_id().to_string();
+        if let Err(e) = op.apply(&actor, &mut merged) {
+            errln!("Key {}: {}", key_str, e);
+            return;
+        }
+    }
+
+    outln!("{} {} = {}", merged.type_name(), key_str, merged.display());
+
+    // 有更新，或者各副本不一致时，把合并后的状态写回网络
+    if pending.op.is_some() || diverged {
+        let record = kad::Record::new(pending.key, merged.encode());
+        let result = if pending.op.is_some() {
+            ownership.put_record(kademlia, record, kad::Quorum::One)
+        } else {
+            kademlia.put_record(record, kad::Quorum::One)
+        };
+        if let Err(e) = result {
+            errln!("Failed to store merged state of {}: {:?}", key_str, e);
+        }
+    }
+}
+
+/// Merges the replicas of `key` into the first one; `true` if they differed,
+/// so that the merged state should be written back. Replicas of another type
+/// than the first are ignored.
+pub fn merge_replicas(key: &str, replicas: Vec<CrdtValue>) -> Option<(CrdtValue, bool)> {
+    let mut replicas = replicas.into_iter();
+    let mut merged = replicas.next()?;
+    let mut diverged = false;
+    for replica in replicas {
+        if replica != merged {
+            diverged = true;
+        }
+        if !merged.merge(&replica) {
+            errln!(
+                "Ignoring a {} replica of {} holding a {}",
+                replica.type_name(),
+                key,
+                merged.type_name()
+            );
+        }
+    }
+    Some((merged, diverged))
+}
+
+/// Parses the arguments of a CRDT command into its operation.
+pub fn parse_op<'a>(
+    command: &str,
+    mut args: impl Iterator<Item = &'a str>,
+) -> Result<CrdtOp, String> {
+    let count = |arg: Option<&str>| -> Result<u64, String> {
+        arg.map_or(Ok(1), |n| {
+            n.parse().map_err(|_| format!("invalid count {}", n))
+        })
+    };
+    // INCR/DECR 的计数要能表示成 i64，否则转换后会变成反方向的增量
+    let signed = |arg: Option<&str>| -> Result<i64, String> {
+        let n = count(arg)?;
+        i64::try_from(n).map_err(|_| format!("count {} is too large", n))
+    };
+    Ok(match command {
+        "GINCR" => CrdtOp::GrowCounter(count(args.next())?),
+        "INCR" => CrdtOp::AddCounter(signed(args.next())?),
+        "DECR" => CrdtOp::AddCounter(-signed(args.next())?),
+        "SADD" => CrdtOp::SetAdd(args.next().ok_or("Expected a member")?.to_string()),
+        "SREM" => CrdtOp::SetRemove(args.next().ok_or("Expected a member")?.to_string()),
+        "LWWSET" => CrdtOp::RegisterSet(args.next().ok_or("Expected a value")?.to_string()),
+        _ => return Err(format!("unknown CRDT command {}", command)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Merges `replicas` into the first one, in order.
+    fn merged(replicas: &[&CrdtValue]) -> CrdtValue {
+        let mut merged = replicas[0].clone();
+        for replica in &replicas[1..] {
+            assert!(merged.merge(replica));
+        }
+        merged
+    }
+
+    #[test]
+    fn counters_merge_every_actor_once() {
+        let mut a = PNCounter::default();
+        let mut b = PNCounter::default();
+        a.add("a", 5);
+        b.add("b", 3);
+        b.add("b", -4);
+        let (a, b) = (CrdtValue::PnCounter(a), CrdtValue::PnCounter(b));
+
+        // 合并可交换、幂等
+        assert_eq!(merged(&[&a, &b]), merged(&[&b, &a]));
+        assert_eq!(merged(&[&a, &b, &b, &a]), merged(&[&a, &b]));
+        assert_eq!(merged(&[&a, &b]).display(), "4");
+
+        let mut g = GCounter::default();
+        g.increment("a", 2);
+        let mut stale = g.clone();
+        g.increment("a", 1);
+        stale.merge(&g);
+        g.merge(&stale);
+        assert_eq!(g.value(), 3);
+        assert_eq!(stale, g);
+    }
+
+    #[test]
+    fn counters_saturate_across_merges() {
+        let mut a = GCounter::default();
+        let mut b = GCounter::default();
+        a.increment("a", u64::MAX);
+        a.increment("a", u64::MAX);
+        b.increment("b", u64::MAX);
+        a.merge(&b);
+        assert_eq!(a.value(), u64::MAX);
+
+        let mut p = PNCounter::default();
+        let mut q = PNCounter::default();
+        p.add("a", i64::MAX);
+        q.add("b", i64::MAX);
+        p.merge(&q);
+        // 累计的增量超过 i64 时取上限，而不是回绕成负数
+        assert_eq!(p.value(), i64::MAX);
+        let mut n = PNCounter::default();
+        n.add("c", -i64::MAX);
+        n.add("c", -i64::MAX);
+        assert_eq!(n.value(), i64::MIN);
+        p.merge(&n);
+        assert_eq!(p.value(), 0);
+    }
+
+    #[test]
+    fn or_set_keeps_concurrent_adds() {
+        let mut base = ORSet::default();
+        base.add("a", "x");
+        let mut removed = base.clone();
+        removed.remove("x");
+        // 另一个副本在没看到删除时再次添加
+        let mut readded = base.clone();
+        readded.add("b", "x");
+        readded.add("b", "y");
+
+        let (removed, readded) = (CrdtValue::OrSet(removed), CrdtValue::OrSet(readded));
+        let merged = merged(&[&removed, &readded]);
+        assert_eq!(merged, self::merged(&[&readded, &removed]));
+        assert_eq!(merged.display(), "{x, y}");
+
+        let CrdtValue::OrSet(mut set) = merged else {
+            unreachable!()
+        };
+        set.remove("x");
+        assert_eq!(set.members(), vec!["y"]);
+    }
+
+    #[test]
+    fn lww_register_breaks_ties_by_actor() {
+        let a = LWWRegister {
+            value: "a".to_string(),
+            timestamp: 10,
+            actor: "a".to_string(),
+        };
+        let b = LWWRegister {
+            value: "b".to_string(),
+            timestamp: 10,
+            actor: "b".to_string(),
+        };
+        let older = LWWRegister {
+            value: "old".to_string(),
+            timestamp: 9,
+            actor: "z".to_string(),
+        };
+        let (a, b, older) = (
+            CrdtValue::LwwRegister(a),
+            CrdtValue::LwwRegister(b),
+            CrdtValue::LwwRegister(older),
+        );
+        assert_eq!(merged(&[&a, &b, &older]).display(), "b");
+        assert_eq!(merged(&[&older, &b, &a]).display(), "b");
+
+        let CrdtValue::LwwRegister(mut register) = older else {
+            unreachable!()
+        };
+        // 本地时钟落后于已存的时间戳时，新写入仍然胜出
+        register.timestamp = u64::MAX / 2;
+        register.set("a", "new");
+        assert_eq!(register.value(), "new");
+        assert!(register.timestamp > u64::MAX / 2);
+
+        // 远端副本带来最大的时间戳时也不会溢出
+        let remote = LWWRegister {
+            timestamp: u64::MAX,
+            ..Default::default()
+        };
+        register.merge(&remote);
+        register.set("a", "last");
+        assert_eq!(register.value(), "last");
+        assert_eq!(register.timestamp, u64::MAX);
+    }
+
+    #[test]
+    fn merge_ignores_other_types() {
+        let mut counter = CrdtValue::GCounter(GCounter::default());
+        assert!(!counter.merge(&CrdtValue::OrSet(ORSet::default())));
+        assert_eq!(counter, CrdtValue::GCounter(GCounter::default()));
+    }
+
+    #[test]
+    fn values_round_trip_and_plain_values_are_not_crdts() {
+        let mut set = ORSet::default();
+        set.add("a", "x");
+        let value = CrdtValue::OrSet(set);
+        assert_eq!(CrdtValue::decode(&value.encode()), Some(value));
+        assert_eq!(CrdtValue::decode(b"plain"), None);
+        assert!(!CrdtValue::is_malformed(b"plain"));
+        assert!(CrdtValue::is_malformed(b"{\"crdt\":\"nope\"}"));
+    }
+
+    #[test]
+    fn parse_op_reads_counts() {
+        let parse = |command: &str, args: &[&str]| parse_op(command, args.iter().copied());
+        assert!(matches!(parse("INCR", &[]), Ok(CrdtOp::AddCounter(1))));
+        assert!(matches!(parse("DECR", &["3"]), Ok(CrdtOp::AddCounter(-3))));
+        assert!(matches!(
+            parse("GINCR", &["18446744073709551615"]),
+            Ok(CrdtOp::GrowCounter(u64::MAX))
+        ));
+        assert!(matches!(
+            parse("INCR", &["9223372036854775807"]),
+            Ok(CrdtOp::AddCounter(i64::MAX))
+        ));
+        assert!(matches!(
+            parse("DECR", &["9223372036854775807"]),
+            Ok(CrdtOp::AddCounter(n)) if n == -i64::MAX
+        ));
+        // 超出 i64 的计数不能悄悄变成负数
+        assert!(parse("INCR", &["9223372036854775808"]).is_err());
+        assert!(parse("DECR", &["18446744073709551615"]).is_err());
+        assert!(parse("INCR", &["-1"]).is_err());
+        assert!(parse("INCR", &["x"]).is_err());
+    }
+
+    #[test]
+    fn parse_op_reads_members_and_values() {
+        let parse = |command: &str, args: &[&str]| parse_op(command, args.iter().copied());
+        assert!(matches!(parse("SADD", &["m"]), Ok(CrdtOp::SetAdd(m)) if m == "m"));
+        assert!(matches!(parse("SREM", &["m"]), Ok(CrdtOp::SetRemove(m)) if m == "m"));
+        assert!(matches!(parse("LWWSET", &["v"]), Ok(CrdtOp::RegisterSet(v)) if v == "v"));
+        assert!(parse("SADD", &[]).is_err());
+        assert!(parse("LWWSET", &[]).is_err());
+        assert!(parse("NOPE", &["1"]).is_err());
+    }
+}
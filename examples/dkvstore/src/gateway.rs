@@ -0,0 +1,713 @@
+//! Optional HTTP gateway so that non-Rust services can use the store.
+//!
+//! - `GET /kv/{key}`: `200` with the raw value, `404` if not found. CRDT
+//!   values are read from up to `?quorum=` replicas, `majority` by default,
+//!   merged, and written back if the replicas differed
+//! - `PUT /kv/{key}`: stores the request body, `204` on success; the optional
+//!   `?quorum=` is `one` (default), `majority`, `all` or a number
+//! - `GET /providers/{key}`: `200` with a JSON list of peer ids, `404` if none
+//! - `GET /peers`: `200` with the routing table as JSON
+//! - `GET /store`: `200` with the number of records held and the writes the
+//!   store refused, as JSON
+//!
+//! Quorum failures are answered with `503`, timeouts with `504`, writes over a
+//! held lease with `409` and writes to a full store with `507`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use libp2p::{
+    kad::{self, store::RecordStore},
+    PeerId, Swarm,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    behaviour::Behavior,
+    crdt::{self, CrdtValue},
+    errln,
+    keyspace::KeyDerivation,
+    logging, outln,
+    ownership::Ownership,
+    store::{DkvStore, Rejections},
+};
+
+pub type Reply<T> = oneshot::Sender<Result<T, GatewayError>>;
+
+/// A request forwarded from the HTTP server to the node's event loop.
+pub enum GatewayRequest {
+    GetRecord {
+        key: kad::RecordKey,
+        /// Replicas of a CRDT value to merge before answering
+        quorum: kad::Quorum,
+        reply: Reply<Vec<u8>>,
+    },
+    PutRecord {
+        record: kad::Record,
+        quorum: kad::Quorum,
+        reply: Reply<()>,
+    },
+    GetProviders {
+        key: kad::RecordKey,
+        reply: Reply<Vec<PeerId>>,
+    },
+    Peers {
+        reply: Reply<Vec<PeerEntry>>,
+    },
+    Store {
+        reply: Reply<StoreStatus>,
+    },
+}
+
+impl GatewayRequest {
+    /// Span the request is handled in, like a command read from stdin.
+    pub fn span(&self) -> tracing::Span {
+        let (command, key) = match self {
+            GatewayRequest::GetRecord { key, .. } => ("GET", Some(key)),
+            GatewayRequest::PutRecord { record, .. } => ("PUT", Some(&record.key)),
+            GatewayRequest::GetProviders { key, .. } => ("GET_PROVIDERS", Some(key)),
+            GatewayRequest::Peers { .. } => ("PEERS", None),
+            GatewayRequest::Store { .. } => ("STORE", None),
+        };
+        tracing::info_span!(
+            "command",
+            source = "http",
+            command,
+            key = key.map(logging::printable),
+        )
+    }
+}
+
+/// What the node's store holds and refused.
+#[derive(Debug, Serialize)]
+pub struct StoreStatus {
+    pub records: usize,
+    pub rejections: Rejections,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerEntry {
+    peer_id: String,
+    addresses: Vec<String>,
+    connected: bool,
+}
+
+#[derive(Debug)]
+pub enum GatewayError {
+    NotFound,
+    QuorumFailed,
+    Timeout,
+    BadRequest(String),
+    /// The key is held by a live lease, see [`crate::lease`]
+    Leased,
+    /// The local store is at its limits, see [`crate::store::StoreLimits`]
+    StoreFull,
+    Internal(String),
+}
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            GatewayError::NotFound => (StatusCode::NOT_FOUND, "not found".to_string()),
+            GatewayError::QuorumFailed => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "quorum not reached".to_string(),
+            ),
+            GatewayError::Timeout => (StatusCode::GATEWAY_TIMEOUT, "timed out".to_string()),
+            GatewayError::BadRequest(m) => (StatusCode::BAD_REQUEST, m),
+            GatewayError::Leased => (
+                StatusCode::CONFLICT,
+                "key is held by a live lease".to_string(),
+            ),
+            GatewayError::StoreFull => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                "store is full".to_string(),
+            ),
+            GatewayError::Internal(m) => (StatusCode::INTERNAL_SERVER_ERROR, m),
+        };
+        (status, message).into_response()
+    }
+}
+
+/// A `GET` collecting the replicas of a CRDT value.
+struct PendingGet {
+    key: kad::RecordKey,
+    /// Replicas to merge before answering
+    quorum: usize,
+    replicas: Vec<CrdtValue>,
+    reply: Reply<Vec<u8>>,
+}
+
+/// Queries started on behalf of the gateway, waiting for their Kademlia result.
+#[derive(Default)]
+pub struct PendingQueries {
+    get_record: HashMap<kad::QueryId, PendingGet>,
+    put_record: HashMap<kad::QueryId, Reply<()>>,
+    get_providers: HashMap<kad::QueryId, (Reply<Vec<PeerId>>, HashSet<PeerId>)>,
+    /// Already answered, but the query still reports progress until its last step
+    answered: HashSet<kad::QueryId>,
+}
+
+impl PendingQueries {
+    /// Starts the queries answering `request`; writes go through `ownership`.
+    pub fn start(
+        &mut self,
+        swarm: &mut Swarm<Behavior>,
+        ownership: &mut Ownership,
+        request: GatewayRequest,
+    ) {
+        let kademlia = &mut swarm.behaviour_mut().kademlia;
+        match request {
+            GatewayRequest::GetRecord { key, quorum, reply } => {
+                let id = kademlia.get_record(key.clone());
+                let pending = PendingGet {
+                    key,
+                    quorum: replicas(quorum),
+                    replicas: Vec::new(),
+                    reply,
+                };
+                self.get_record.insert(id, pending);
+            }
+            GatewayRequest::PutRecord { record, reply, .. }
+                if !kademlia.store_mut().admits(&record) =>
+            {
+                let _ = reply.send(Err(GatewayError::Leased));
+            }
+            GatewayRequest::PutRecord {
+                record,
+                quorum,
+                reply,
+            } => match ownership.put_record(kademlia, record, quorum) {
+                Ok(id) => {
+                    self.put_record.insert(id, reply);
+                }
+                Err(e) => {
+                    let _ = reply.send(Err(store_error(e)));
+                }
+            },
+            GatewayRequest::GetProviders { key, reply } => {
+                let id = kademlia.get_providers(key);
+                self.get_providers.insert(id, (reply, HashSet::new()));
+            }
+            GatewayRequest::Peers { reply } => {
+                let routing_table: Vec<(PeerId, Vec<String>)> = kademlia
+                    .kbuckets()
+                    .flat_map(|bucket| {
+                        bucket
+                            .iter()
+                            .map(|entry| {
+                                let addresses = entry.node.value.iter().map(|a| a.to_string());
+                                (*entry.node.key.preimage(), addresses.collect())
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                let peers = routing_table
+                    .into_iter()
+                    .map(|(peer_id, addresses)| PeerEntry {
+                        peer_id: peer_id.to_string(),
+                        addresses,
+                        connected: swarm.is_connected(&peer_id),
+                    })
+                    .collect();
+                let _ = reply.send(Ok(peers));
+            }
+            GatewayRequest::Store { reply } => {
+                let store = kademlia.store_mut();
+                let status = StoreStatus {
+                    records: store.records().count(),
+                    rejections: store.rejections(),
+                };
+                let _ = reply.send(Ok(status));
+            }
+        }
+    }
+
+    /// Resolves the HTTP request waiting on `id`, if any. Returns `false` for
+    /// queries the gateway did not start.
+    pub fn on_query_progressed(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        id: kad::QueryId,
+        result: &kad::QueryResult,
+        step: &kad::ProgressStep,
+    ) -> bool {
+        if self.answered.contains(&id) {
+            if step.last {
+                self.answered.remove(&id);
+            }
+            return true;
+        }
+        match result {
+            kad::QueryResult::GetRecord(result) => {
+                let Some(mut pending) = self.get_record.remove(&id) else {
+                    return false;
+                };
+                match result {
+                    Ok(kad::GetRecordOk::FoundRecord(peer_record)) => {
+                        let value = &peer_record.record.value;
+                        match CrdtValue::decode(value) {
+                            Some(replica) => pending.replicas.push(replica),
+                            // 普通值不需要合并，第一个结果即可
+                            None if pending.replicas.is_empty() => {
+                                let _ = pending.reply.send(Ok(value.clone()));
+                                self.finish(kademlia, id, step);
+                                return true;
+                            }
+                            None => {}
+                        }
+                        if pending.replicas.len() < pending.quorum && !step.last {
+                            self.get_record.insert(id, pending);
+                            return true;
+                        }
+                        answer_merged(kademlia, pending);
+                        self.finish(kademlia, id, step);
+                    }
+                    // 副本不足法定数量时，合并已经找到的
+                    _ if !pending.replicas.is_empty() => answer_merged(kademlia, pending),
+                    Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { .. }) => {
+                        let _ = pending.reply.send(Err(GatewayError::NotFound));
+                    }
+                    Err(e) => {
+                        let _ = pending.reply.send(Err(get_error(e)));
+                    }
+                }
+                true
+            }
+            kad::QueryResult::PutRecord(result) => {
+                let Some(reply) = self.put_record.remove(&id) else {
+                    return false;
+                };
+                let _ = reply.send(result.as_ref().map(|_| ()).map_err(put_error));
+                true
+            }
+            kad::QueryResult::GetProviders(result) => {
+                let Some((reply, mut found)) = self.get_providers.remove(&id) else {
+                    return false;
+                };
+                let outcome = match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        found.extend(providers.iter().copied());
+                        None
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => None,
+                    Err(kad::GetProvidersError::Timeout { .. }) if found.is_empty() => {
+                        Some(Err(GatewayError::Timeout))
+                    }
+                    Err(kad::GetProvidersError::Timeout { .. }) => None,
+                };
+                match outcome {
+                    Some(err) => {
+                        let _ = reply.send(err);
+                    }
+                    None if step.last => {
+                        let _ = reply.send(if found.is_empty() {
+                            Err(GatewayError::NotFound)
+                        } else {
+                            Ok(found.into_iter().collect())
+                        });
+                    }
+                    None => {
+                        self.get_providers.insert(id, (reply, found));
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Ends a query answered before its last step; its remaining progress
+    /// is swallowed.
+    fn finish(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        id: kad::QueryId,
+        step: &kad::ProgressStep,
+    ) {
+        if let Some(mut query) = kademlia.query_mut(&id) {
+            query.finish();
+        }
+        if !step.last {
+            self.answered.insert(id);
+        }
+    }
+}
+
+/// Answers `pending` with the merge of its replicas, and writes the merged
+/// state back if they differed, like the `GET` command does.
+fn answer_merged(kademlia: &mut kad::Behaviour<DkvStore>, pending: PendingGet) {
+    let key = String::from_utf8_lossy(pending.key.as_ref()).into_owned();
+    let Some((merged, diverged)) = crdt::merge_replicas(&key, pending.replicas) else {
+        let _ = pending.reply.send(Err(GatewayError::NotFound));
+        return;
+    };
+    let value = merged.encode();
+    if diverged {
+        let record = kad::Record::new(pending.key, value.clone());
+        if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+            errln!("Failed to store merged state of {}: {:?}", key, e);
+        }
+    }
+    let _ = pending.reply.send(Ok(value));
+}
+
+/// How many replicas `quorum` asks for out of the replication factor.
+fn replicas(quorum: kad::Quorum) -> usize {
+    let k = kad::K_VALUE.get();
+    match quorum {
+        kad::Quorum::One => 1,
+        kad::Quorum::Majority => k / 2 + 1,
+        kad::Quorum::All => k,
+        kad::Quorum::N(n) => n.get().min(k),
+    }
+}
+
+fn store_error(e: kad::store::Error) -> GatewayError {
+    match e {
+        kad::store::Error::MaxRecords => GatewayError::StoreFull,
+        e => GatewayError::Internal(format!("{:?}", e)),
+    }
+}
+
+fn get_error(e: &kad::GetRecordError) -> GatewayError {
+    match e {
+        kad::GetRecordError::NotFound { .. } => GatewayError::NotFound,
+        kad::GetRecordError::QuorumFailed { .. } => GatewayError::QuorumFailed,
+        kad::GetRecordError::Timeout { .. } => GatewayError::Timeout,
+    }
+}
+
+fn put_error(e: &kad::PutRecordError) -> GatewayError {
+    match e {
+        kad::PutRecordError::QuorumFailed { .. } => GatewayError::QuorumFailed,
+        kad::PutRecordError::Timeout { .. } => GatewayError::Timeout,
+    }
+}
+
+#[derive(Clone)]
+struct GatewayState {
+    requests: mpsc::Sender<GatewayRequest>,
+    timeout: Duration,
+    keys: KeyDerivation,
+}
+
+impl GatewayState {
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> GatewayRequest,
+    ) -> Result<T, GatewayError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(request(tx))
+            .await
+            .map_err(|_| GatewayError::Internal("node is shutting down".to_string()))?;
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(GatewayError::Internal("request dropped".to_string())),
+            Err(_) => Err(GatewayError::Timeout),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct QuorumParams {
+    quorum: Option<String>,
+}
+
+/// Serves the gateway until the node's request channel is closed.
+pub async fn serve(
+    addr: SocketAddr,
+    requests: mpsc::Sender<GatewayRequest>,
+    timeout: Duration,
+    keys: KeyDerivation,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/kv/{key}", get(get_record).put(put_record))
+        .route("/providers/{key}", get(get_providers))
+        .route("/peers", get(peers))
+        .route("/store", get(store))
+        .with_state(GatewayState {
+            requests,
+            timeout,
+            keys,
+        });
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    outln!("HTTP gateway listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn get_record(
+    State(state): State<GatewayState>,
+    Path(key): Path<String>,
+    Query(params): Query<QuorumParams>,
+) -> Result<impl IntoResponse, GatewayError> {
+    let quorum = parse_quorum(Some(params.quorum.as_deref().unwrap_or("majority")))?;
+    let key = state.keys.record_key(&key);
+    let value = state
+        .call(|reply| GatewayRequest::GetRecord { key, quorum, reply })
+        .await?;
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], value))
+}
+
+async fn put_record(
+    State(state): State<GatewayState>,
+    Path(key): Path<String>,
+    Query(params): Query<QuorumParams>,
+    body: Bytes,
+) -> Result<StatusCode, GatewayError> {
+    let quorum = parse_quorum(params.quorum.as_deref())?;
+    let record = kad::Record::new(state.keys.record_key(&key), body.to_vec());
+    state
+        .call(|reply| GatewayRequest::PutRecord {
+            record,
+            quorum,
+            reply,
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_providers(
+    State(state): State<GatewayState>,
+    Path(key): Path<String>,
+) -> Result<Json<Vec<String>>, GatewayError> {
+    let key = state.keys.record_key(&key);
+    let providers = state
+        .call(|reply| GatewayRequest::GetProviders { key, reply })
+        .await?;
+    Ok(Json(providers.iter().map(|p| p.to_string()).collect()))
+}
+
+async fn peers(State(state): State<GatewayState>) -> Result<Json<Vec<PeerEntry>>, GatewayError> {
+    let peers = state.call(|reply| GatewayRequest::Peers { reply }).await?;
+    Ok(Json(peers))
+}
+
+async fn store(State(state): State<GatewayState>) -> Result<Json<StoreStatus>, GatewayError> {
+    let status = state.call(|reply| GatewayRequest::Store { reply }).await?;
+    Ok(Json(status))
+}
+
+pub fn parse_quorum(quorum: Option<&str>) -> Result<kad::Quorum, GatewayError> {
+    Ok(match quorum {
+        None | Some("one") => kad::Quorum::One,
+        Some("majority") => kad::Quorum::Majority,
+        Some("all") => kad::Quorum::All,
+        Some(n) => n
+            .parse::<NonZeroUsize>()
+            .map(kad::Quorum::N)
+            .map_err(|_| GatewayError::BadRequest(format!("invalid quorum {:?}", n)))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crdt::GCounter;
+
+    use super::*;
+
+    fn kademlia() -> kad::Behaviour<DkvStore> {
+        let local = PeerId::random();
+        kad::Behaviour::new(
+            local,
+            DkvStore::new(local, crate::store::StoreLimits::default()),
+        )
+    }
+
+    fn step(count: usize, last: bool) -> kad::ProgressStep {
+        kad::ProgressStep {
+            count: NonZeroUsize::new(count).unwrap(),
+            last,
+        }
+    }
+
+    fn found(key: &kad::RecordKey, value: Vec<u8>) -> kad::QueryResult {
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+            peer: Some(PeerId::random()),
+            record: kad::Record::new(key.clone(), value),
+        })))
+    }
+
+    fn counter(actor: &str, by: u64) -> CrdtValue {
+        let mut counter = GCounter::default();
+        counter.increment(actor, by);
+        CrdtValue::GCounter(counter)
+    }
+
+    /// Starts a `GET` of `key` waiting for `quorum` replicas.
+    fn get(
+        queries: &mut PendingQueries,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        key: &kad::RecordKey,
+        quorum: usize,
+    ) -> (
+        kad::QueryId,
+        oneshot::Receiver<Result<Vec<u8>, GatewayError>>,
+    ) {
+        let (reply, rx) = oneshot::channel();
+        let id = kademlia.get_record(key.clone());
+        let pending = PendingGet {
+            key: key.clone(),
+            quorum,
+            replicas: Vec::new(),
+            reply,
+        };
+        queries.get_record.insert(id, pending);
+        (id, rx)
+    }
+
+    #[test]
+    fn crdt_replicas_are_merged_up_to_the_quorum() {
+        let mut kademlia = kademlia();
+        let mut queries = PendingQueries::default();
+        let key = kad::RecordKey::new(&"counter");
+        let (id, mut rx) = get(&mut queries, &mut kademlia, &key, 2);
+
+        let first = found(&key, counter("a", 1).encode());
+        assert!(queries.on_query_progressed(&mut kademlia, id, &first, &step(1, false)));
+        assert!(rx.try_recv().is_err());
+        let second = found(&key, counter("b", 2).encode());
+        assert!(queries.on_query_progressed(&mut kademlia, id, &second, &step(2, false)));
+
+        let merged = CrdtValue::decode(&rx.try_recv().unwrap().unwrap()).unwrap();
+        assert_eq!(merged.display(), "3");
+        // 副本不一致，合并后的状态写回
+        let stored = kademlia.store_mut().get(&key).unwrap().into_owned();
+        assert_eq!(CrdtValue::decode(&stored.value), Some(merged));
+        // 查询已提前结束，剩下的进度不再交给别处
+        let third = found(&key, counter("c", 3).encode());
+        assert!(queries.on_query_progressed(&mut kademlia, id, &third, &step(3, true)));
+        assert!(queries.answered.is_empty());
+    }
+
+    #[test]
+    fn short_of_the_quorum_what_was_found_is_merged() {
+        let mut kademlia = kademlia();
+        let mut queries = PendingQueries::default();
+        let key = kad::RecordKey::new(&"counter");
+        let (id, mut rx) = get(&mut queries, &mut kademlia, &key, 11);
+
+        let first = found(&key, counter("a", 1).encode());
+        queries.on_query_progressed(&mut kademlia, id, &first, &step(1, false));
+        let finished =
+            kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord {
+                cache_candidates: Default::default(),
+            }));
+        queries.on_query_progressed(&mut kademlia, id, &finished, &step(2, true));
+        let merged = CrdtValue::decode(&rx.try_recv().unwrap().unwrap()).unwrap();
+        assert_eq!(merged, counter("a", 1));
+    }
+
+    #[test]
+    fn plain_values_are_answered_at_once() {
+        let mut kademlia = kademlia();
+        let mut queries = PendingQueries::default();
+        let key = kad::RecordKey::new(&"plain");
+        let (id, mut rx) = get(&mut queries, &mut kademlia, &key, 11);
+
+        let first = found(&key, b"value".to_vec());
+        queries.on_query_progressed(&mut kademlia, id, &first, &step(1, false));
+        assert_eq!(rx.try_recv().unwrap().unwrap(), b"value");
+
+        let (id, mut rx) = get(&mut queries, &mut kademlia, &key, 1);
+        let timeout =
+            kad::QueryResult::GetRecord(Err(kad::GetRecordError::Timeout { key: key.clone() }));
+        queries.on_query_progressed(&mut kademlia, id, &timeout, &step(1, true));
+        assert!(matches!(rx.try_recv().unwrap(), Err(GatewayError::Timeout)));
+    }
+
+    #[test]
+    fn quorums_parse() {
+        assert!(matches!(parse_quorum(None), Ok(kad::Quorum::One)));
+        assert!(matches!(parse_quorum(Some("one")), Ok(kad::Quorum::One)));
+        assert!(matches!(
+            parse_quorum(Some("majority")),
+            Ok(kad::Quorum::Majority)
+        ));
+        assert!(matches!(parse_quorum(Some("all")), Ok(kad::Quorum::All)));
+        assert!(matches!(
+            parse_quorum(Some("3")),
+            Ok(kad::Quorum::N(n)) if n.get() == 3
+        ));
+        for invalid in ["0", "-1", "most", ""] {
+            assert!(matches!(
+                parse_quorum(Some(invalid)),
+                Err(GatewayError::BadRequest(_))
+            ));
+        }
+
+        assert_eq!(replicas(kad::Quorum::One), 1);
+        assert_eq!(replicas(kad::Quorum::Majority), 11);
+        assert_eq!(replicas(kad::Quorum::All), 20);
+        assert_eq!(replicas(kad::Quorum::N(NonZeroUsize::new(50).unwrap())), 20);
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        let status = |e: GatewayError| e.into_response().status();
+        let key = kad::RecordKey::new(&"k");
+        let quorum = NonZeroUsize::new(2).unwrap();
+
+        assert_eq!(
+            status(store_error(kad::store::Error::MaxRecords)),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            status(store_error(kad::store::Error::ValueTooLarge)),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(status(GatewayError::Leased), StatusCode::CONFLICT);
+        assert_eq!(
+            status(GatewayError::BadRequest("x".into())),
+            StatusCode::BAD_REQUEST
+        );
+
+        let not_found = kad::GetRecordError::NotFound {
+            key: key.clone(),
+            closest_peers: Vec::new(),
+        };
+        let quorum_failed = kad::GetRecordError::QuorumFailed {
+            key: key.clone(),
+            records: Vec::new(),
+            quorum,
+        };
+        let timeout = kad::GetRecordError::Timeout { key: key.clone() };
+        assert_eq!(status(get_error(&not_found)), StatusCode::NOT_FOUND);
+        assert_eq!(
+            status(get_error(&quorum_failed)),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(get_error(&timeout)), StatusCode::GATEWAY_TIMEOUT);
+
+        let quorum_failed = kad::PutRecordError::QuorumFailed {
+            key: key.clone(),
+            success: Vec::new(),
+            quorum,
+        };
+        let timeout = kad::PutRecordError::Timeout {
+            key,
+            success: Vec::new(),
+            quorum,
+        };
+        assert_eq!(
+            status(put_error(&quorum_failed)),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(status(put_error(&timeout)), StatusCode::GATEWAY_TIMEOUT);
+    }
+}
@@ -0,0 +1,378 @@
+//! Key derivation and additional DHTs for tenants.
+//!
+//! Keys typed on the command line, sent to the HTTP gateway or used as lease
+//! names are turned into record keys by a [`KeyDerivation`] (`--key-hash`):
+//!
+//! - `raw`: the key's UTF-8 bytes, the default
+//! - `sha256`: the SHA-256 digest of the key, which spreads similar keys
+//!   evenly over the DHT and keeps them from the peers storing them
+//! - `namespaced`: the SHA-256 digest of the namespace, a zero byte and the
+//!   key, so that equal keys of different namespaces never meet
+//!
+//! Besides the main DHT a node can join one more DHT per keyspace
+//! (`--keyspace`). Each speaks Kademlia under its own protocol name,
+//! `/dkvstore/kad/<name>/1.0.0`, keeps its own routing table and store, and
+//! only meets peers that joined the same keyspace, so tenants sharing a
+//! process never see each other's records. Keyspaces offer the plain record
+//! and provider commands (`IN <keyspace> GET <key>`); CRDT values, leases and
+//! the republication of owned records stay on the main DHT, and records in a
+//! keyspace are republished by Kademlia itself.
+
+use std::{
+    collections::BTreeMap,
+    task::{Context, Poll},
+};
+
+use clap::ValueEnum;
+use libp2p::{
+    core::{transport::PortUse, Endpoint},
+    kad,
+    swarm::{
+        handler::multi::MultiHandler, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour,
+        THandler, THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId, StreamProtocol,
+};
+use sha2::{Digest, Sha256};
+
+use crate::store::{DkvStore, StoreLimits};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum KeyHash {
+    /// Use keys as they are
+    Raw,
+    /// Hash keys with SHA-256
+    Sha256,
+    /// Hash the namespace together with the key
+    Namespaced,
+}
+
+/// Turns user keys into record keys.
+#[derive(Debug, Clone)]
+pub struct KeyDerivation {
+    hash: KeyHash,
+    namespace: String,
+}
+
+impl Default for KeyDerivation {
+    fn default() -> Self {
+        Self::new(KeyHash::Raw, "")
+    }
+}
+
+impl KeyDerivation {
+    pub fn new(hash: KeyHash, namespace: impl Into<String>) -> Self {
+        Self {
+            hash,
+            namespace: namespace.into(),
+        }
+    }
+
+    /// The same hashing within another namespace.
+    pub fn with_namespace(&self, namespace: impl Into<String>) -> Self {
+        Self::new(self.hash, namespace)
+    }
+
+    pub fn record_key(&self, key: &str) -> kad::RecordKey {
+        match self.hash {
+            KeyHash::Raw => kad::RecordKey::new(&key),
+            KeyHash::Sha256 => Sha256::digest(key.as_bytes()).to_vec().into(),
+            KeyHash::Namespaced => Sha256::new()
+                .chain_update(self.namespace.as_bytes())
+                .chain_update([0])
+                .chain_update(key.as_bytes())
+                .finalize()
+                .to_vec()
+                .into(),
+        }
+    }
+}
+
+/// Kademlia protocol name of the keyspace `name`.
+pub fn protocol_name(name: &str) -> Result<StreamProtocol, String> {
+    if name.is_empty() || name.contains(|c: char| c == '/' || c.is_whitespace()) {
+        return Err(format!("invalid keyspace name {:?}", name));
+    }
+    StreamProtocol::try_from_owned(format!("/dkvstore/kad/{}/1.0.0", name))
+        .map_err(|e| e.to_string())
+}
+
+/// Event of the Kademlia instance of one keyspace.
+#[derive(Debug)]
+pub struct KeyspaceEvent {
+    pub keyspace: String,
+    pub event: kad::Event,
+}
+
+/// One Kademlia instance per keyspace, sharing the node's connections.
+#[derive(Default)]
+pub struct Keyspaces {
+    instances: BTreeMap<String, kad::Behaviour<DkvStore>>,
+}
+
+impl Keyspaces {
+    /// Joins the keyspace `name` with its own store.
+    pub fn add(
+        &mut self,
+        name: &str,
+        local_peer_id: PeerId,
+        limits: StoreLimits,
+    ) -> Result<(), String> {
+        let protocol = protocol_name(name)?;
+        if self.instances.contains_key(name) {
+            return Err(format!("keyspace {} given twice", name));
+        }
+        let kademlia = kad::Behaviour::with_config(
+            local_peer_id,
+            DkvStore::new(local_peer_id, limits),
+            kad::Config::new(protocol),
+        );
+        self.instances.insert(name.to_string(), kademlia);
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut kad::Behaviour<DkvStore>> {
+        self.instances.get_mut(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &kad::Behaviour<DkvStore>)> {
+        self.instances.iter().map(|(name, k)| (name.as_str(), k))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut kad::Behaviour<DkvStore>)> {
+        self.instances
+            .iter_mut()
+            .map(|(name, k)| (name.as_str(), k))
+    }
+
+    /// Adds `peer`'s addresses to every keyspace among `protocols`.
+    pub fn add_peer(&mut self, peer: &PeerId, protocols: &[StreamProtocol], addrs: &[Multiaddr]) {
+        for (name, kademlia) in &mut self.instances {
+            let supported = protocol_name(name).is_ok_and(|p| protocols.contains(&p));
+            if supported {
+                for addr in addrs {
+                    kademlia.add_address(peer, addr.clone());
+                }
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        for kademlia in self.instances.values_mut() {
+            kademlia.remove_peer(peer);
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Option<kad::Mode>) {
+        for kademlia in self.instances.values_mut() {
+            kademlia.set_mode(mode);
+        }
+    }
+}
+
+impl NetworkBehaviour for Keyspaces {
+    type ConnectionHandler = MultiHandler<String, THandler<kad::Behaviour<DkvStore>>>;
+    type ToSwarm = KeyspaceEvent;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        for kademlia in self.instances.values_mut() {
+            kademlia.handle_pending_inbound_connection(connection_id, local_addr, remote_addr)?;
+        }
+        Ok(())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let mut handlers = Vec::with_capacity(self.instances.len());
+        for (name, kademlia) in &mut self.instances {
+            let handler = kademlia.handle_established_inbound_connection(
+                connection_id,
+                peer,
+                local_addr,
+                remote_addr,
+            )?;
+            handlers.push((name.clone(), handler));
+        }
+        MultiHandler::try_from_iter(handlers).map_err(ConnectionDenied::new)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        let mut found = Vec::new();
+        for kademlia in self.instances.values_mut() {
+            for addr in kademlia.handle_pending_outbound_connection(
+                connection_id,
+                maybe_peer,
+                addresses,
+                effective_role,
+            )? {
+                if !found.contains(&addr) {
+                    found.push(addr);
+                }
+            }
+        }
+        Ok(found)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let mut handlers = Vec::with_capacity(self.instances.len());
+        for (name, kademlia) in &mut self.instances {
+            let handler = kademlia.handle_established_outbound_connection(
+                connection_id,
+                peer,
+                addr,
+                role_override,
+                port_use,
+            )?;
+            handlers.push((name.clone(), handler));
+        }
+        MultiHandler::try_from_iter(handlers).map_err(ConnectionDenied::new)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        for kademlia in self.instances.values_mut() {
+            kademlia.on_swarm_event(event);
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        (name, event): THandlerOutEvent<Self>,
+    ) {
+        if let Some(kademlia) = self.instances.get_mut(&name) {
+            kademlia.on_connection_handler_event(peer_id, connection_id, event);
+        }
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        for (name, kademlia) in &mut self.instances {
+            if let Poll::Ready(event) = kademlia.poll(cx) {
+                return Poll::Ready(
+                    event
+                        .map_out(|event| KeyspaceEvent {
+                            keyspace: name.clone(),
+                            event,
+                        })
+                        .map_in(|event| (name.clone(), event)),
+                );
+            }
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive(hash: KeyHash, namespace: &str, key: &str) -> Vec<u8> {
+        KeyDerivation::new(hash, namespace).record_key(key).to_vec()
+    }
+
+    #[test]
+    fn keys_are_derived_stably() {
+        assert_eq!(derive(KeyHash::Raw, "tenant", "abc"), b"abc");
+        assert_eq!(KeyDerivation::default().record_key("abc").to_vec(), b"abc");
+        // SHA-256("abc")，与命名空间无关
+        let digest = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+            0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+            0xf2, 0x00, 0x15, 0xad,
+        ];
+        assert_eq!(derive(KeyHash::Sha256, "", "abc"), digest);
+        assert_eq!(derive(KeyHash::Sha256, "tenant", "abc"), digest);
+        let namespaced: Vec<u8> = Sha256::digest(b"tenant\0abc").to_vec();
+        assert_eq!(derive(KeyHash::Namespaced, "tenant", "abc"), namespaced);
+        assert_eq!(
+            derive(KeyHash::Namespaced, "tenant", "abc"),
+            derive(KeyHash::Namespaced, "tenant", "abc")
+        );
+    }
+
+    #[test]
+    fn derived_keys_are_distinct() {
+        let modes = [
+            derive(KeyHash::Raw, "a", "key"),
+            derive(KeyHash::Sha256, "a", "key"),
+            derive(KeyHash::Namespaced, "a", "key"),
+        ];
+        assert_ne!(modes[0], modes[1]);
+        assert_ne!(modes[1], modes[2]);
+        assert_ne!(modes[0], modes[2]);
+
+        assert_ne!(
+            derive(KeyHash::Sha256, "", "key"),
+            derive(KeyHash::Sha256, "", "key2")
+        );
+        assert_ne!(
+            derive(KeyHash::Namespaced, "a", "key"),
+            derive(KeyHash::Namespaced, "b", "key")
+        );
+        // 零字节分隔命名空间和键，拼接相同也不会撞
+        assert_ne!(
+            derive(KeyHash::Namespaced, "ab", "c"),
+            derive(KeyHash::Namespaced, "a", "bc")
+        );
+
+        let tenant = KeyDerivation::new(KeyHash::Namespaced, "a").with_namespace("b");
+        assert_eq!(
+            tenant.record_key("key").to_vec(),
+            derive(KeyHash::Namespaced, "b", "key")
+        );
+    }
+
+    #[test]
+    fn keyspaces_get_their_own_protocol() {
+        assert_eq!(
+            protocol_name("alpha").unwrap().as_ref(),
+            "/dkvstore/kad/alpha/1.0.0"
+        );
+        assert_ne!(protocol_name("alpha"), protocol_name("beta"));
+        assert_ne!(protocol_name("alpha").unwrap(), kad::PROTOCOL_NAME);
+        for invalid in ["", "a/b", "two words", "tab\t"] {
+            assert!(protocol_name(invalid).is_err(), "{:?}", invalid);
+        }
+
+        let local = PeerId::random();
+        let mut keyspaces = Keyspaces::default();
+        keyspaces
+            .add("alpha", local, StoreLimits::default())
+            .unwrap();
+        keyspaces
+            .add("beta", local, StoreLimits::default())
+            .unwrap();
+        assert!(keyspaces
+            .add("alpha", local, StoreLimits::default())
+            .is_err());
+        assert!(keyspaces.add("a/b", local, StoreLimits::default()).is_err());
+        let names: Vec<_> = keyspaces.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["alpha", "beta"]);
+    }
+}
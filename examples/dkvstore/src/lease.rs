@@ -0,0 +1,595 @@
+//! Leases ("only one worker holds X") built on versioned, expiring records.
+//!
+//! A lease on `<name>` is stored under the key `lock/<name>` (the name passes
+//! through the node's [`KeyDerivation`] first) as
+//! `{"lease":{"holder":..,"token":..,"expires_at":..}}`, `expires_at` being a
+//! unix timestamp in milliseconds.
+//!
+//! - `LOCK <name> <ttl>` reads all replicas of the key. If one of them holds a
+//!   live lease of another peer the lock is busy. Otherwise the lease is
+//!   written with `token = highest token seen + 1` to the K closest peers and
+//!   is acquired only if a majority of them accepted it. On failure the
+//!   partial writes are released again. A losing contender only learns that
+//!   it lost once the replicas that refused it time out (Kademlia's substream
+//!   timeout, 10 seconds by default), so under contention the TTL should be
+//!   well above that.
+//! - `RENEW <name> <ttl>` pushes the expiry of a held lease out, keeping its
+//!   token, again with a majority.
+//! - `UNLOCK <name>` writes the lease back as already expired. It is
+//!   confirmed once a majority took the write; until the other replicas did,
+//!   a `LOCK` of another node may still find the lease and report it busy.
+//!
+//! Every replica checks writes to lease records in [`admits`] (called by
+//! [`DkvStore`]): a lease of another holder is only replaced once it has
+//! expired and only by a higher token, and a holder can never go back to an
+//! older token. Rejected writes are not acknowledged, so they do not count
+//! towards the writer's majority.
+//!
+//! # Safety guarantees
+//!
+//! Two majorities of the same replica set share at least one peer, and that
+//! peer accepts only one of two competing leases. So as long as contenders
+//! agree on the K closest peers of the key, at most one of them holds a given
+//! lease at any time. This is weaker than consensus:
+//!
+//! - Replica sets come from each node's own routing table. During churn or a
+//!   network partition two contenders may see different closest peers and
+//!   both acquire the lease.
+//! - Expiry uses wall clocks. Clock drift between holder and replicas shortens
+//!   or lengthens the lease by that drift, so a holder should stop working
+//!   well before `expires_at`.
+//! - Replicas keep leases in memory only. If a majority of them restart, the
+//!   lease is forgotten and can be acquired again while still held.
+//! - A holder that pauses (GC, swap, a stopped VM) past its expiry does not
+//!   notice that it lost the lease.
+//!
+//! The token grows with every acquisition of the same lease, as long as any
+//! replica still remembers it (the record TTL is 48 hours). Pass it along
+//! with every write to the guarded resource and reject writes carrying an
+//! older token than the last one seen; this fencing is the only protection
+//! against the last two cases above.
+//!
+//! Contention between several nodes is exercised by the `lease_contention`
+//! test (`cargo test -p dkvstore --test lease_contention`).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{kad, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{keyspace::KeyDerivation, store::DkvStore};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    #[serde(with = "peer_id_string")]
+    pub holder: PeerId,
+    /// Fencing token, incremented by every acquisition
+    pub token: u64,
+    /// Unix timestamp in milliseconds
+    pub expires_at: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Tagged {
+    Lease(Lease),
+}
+
+const TAG: &[u8] = b"{\"lease\":";
+
+impl Lease {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if !bytes.starts_with(TAG) {
+            return None;
+        }
+        let Tagged::Lease(lease) = serde_json::from_slice(bytes).ok()?;
+        Some(lease)
+    }
+
+    /// Whether `bytes` claim to be a lease but do not decode as one.
+    pub fn is_malformed(bytes: &[u8]) -> bool {
+        bytes.starts_with(TAG) && Self::decode(bytes).is_none()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&Tagged::Lease(self.clone())).expect("leases always serialize")
+    }
+
+    pub fn is_live(&self, now: u64) -> bool {
+        self.expires_at > now
+    }
+
+    fn record(&self, key: kad::RecordKey) -> kad::Record {
+        let mut record = kad::Record::new(key, self.encode());
+        record.publisher = Some(self.holder);
+        record
+    }
+
+    /// The same lease, expiring right now.
+    fn released(&self) -> Self {
+        Self {
+            expires_at: now_millis().min(self.expires_at),
+            ..self.clone()
+        }
+    }
+}
+
+pub(crate) mod peer_id_string {
+    use libp2p::PeerId;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(peer_id: &PeerId, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(peer_id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<PeerId, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+const KEY_PREFIX: &str = "lock/";
+
+pub fn key(keys: &KeyDerivation, name: &str) -> kad::RecordKey {
+    let mut key = KEY_PREFIX.as_bytes().to_vec();
+    key.extend_from_slice(keys.record_key(name).as_ref());
+    key.into()
+}
+
+/// Whether `key` is where some lease is stored.
+pub fn is_key(key: &kad::RecordKey) -> bool {
+    key.as_ref().starts_with(KEY_PREFIX.as_bytes())
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// When a lease taken at `now` for `ttl` runs out; huge TTLs saturate
+/// instead of overflowing.
+fn expires_at(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+/// Whether a replica holding `stored` may overwrite it with `new`.
+pub fn admits(stored: Option<&kad::Record>, new: &kad::Record) -> bool {
+    let Some(stored) = stored.and_then(|r| Lease::decode(&r.value)) else {
+        return true;
+    };
+    let now = now_millis();
+    let Some(new) = Lease::decode(&new.value) else {
+        // 普通 PUT 不能覆盖仍然有效的租约
+        return !stored.is_live(now);
+    };
+    if new.holder == stored.holder {
+        new.token >= stored.token
+    } else {
+        new.token > stored.token && !stored.is_live(now)
+    }
+}
+
+/// Outcome of a lease command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaseEvent {
+    Acquired { name: String, lease: Lease },
+    Busy { name: String, lease: Lease },
+    Renewed { name: String, lease: Lease },
+    Released { name: String, lease: Lease },
+    Failed { name: String, reason: String },
+}
+
+impl fmt::Display for LeaseEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let remaining =
+            |lease: &Lease| lease.expires_at.saturating_sub(now_millis()) as f64 / 1000.0;
+        match self {
+            LeaseEvent::Acquired { name, lease } => write!(
+                f,
+                "Acquired lock {} (token {}) for {:.1}s",
+                name,
+                lease.token,
+                remaining(lease)
+            ),
+            LeaseEvent::Busy { name, lease } => write!(
+                f,
+                "Lock {} is held by {} (token {}) for another {:.1}s",
+                name,
+                lease.holder,
+                lease.token,
+                remaining(lease)
+            ),
+            LeaseEvent::Renewed { name, lease } => write!(
+                f,
+                "Renewed lock {} (token {}) for {:.1}s",
+                name,
+                lease.token,
+                remaining(lease)
+            ),
+            LeaseEvent::Released { name, lease } => {
+                write!(f, "Released lock {} (token {})", name, lease.token)
+            }
+            LeaseEvent::Failed { name, reason } => write!(f, "Lock {}: {}", name, reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LeaseOp {
+    Lock,
+    Renew,
+    Unlock,
+}
+
+enum Phase {
+    /// Collecting all replicas of the lease record
+    Read { ttl: Duration, found: Vec<Lease> },
+    /// Looking up the replica set the lease is written to
+    Closest { lease: Lease },
+    /// Waiting for a majority of the replica set to accept the lease
+    Write { lease: Lease },
+    /// Releasing the partial writes of a failed acquisition
+    Rollback,
+}
+
+struct Pending {
+    name: String,
+    op: LeaseOp,
+    phase: Phase,
+}
+
+/// Lease commands in flight and the leases held by this node.
+#[derive(Default)]
+pub struct LeaseManager {
+    pending: HashMap<kad::QueryId, Pending>,
+    held: HashMap<String, Lease>,
+    events: Vec<LeaseEvent>,
+    keys: KeyDerivation,
+}
+
+impl LeaseManager {
+    pub fn new(keys: KeyDerivation) -> Self {
+        Self {
+            keys,
+            ..Default::default()
+        }
+    }
+
+    /// The lease on `name` held by this node, if it has not expired yet.
+    pub fn held(&self, name: &str) -> Option<&Lease> {
+        self.held
+            .get(name)
+            .filter(|lease| lease.is_live(now_millis()))
+    }
+
+    /// Outcomes of the commands finished since the last call.
+    pub fn drain_events(&mut self) -> Vec<LeaseEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    pub fn lock(&mut self, kademlia: &mut kad::Behaviour<DkvStore>, name: &str, ttl: Duration) {
+        let id = kademlia.get_record(key(&self.keys, name));
+        self.pending.insert(
+            id,
+            Pending {
+                name: name.to_string(),
+                op: LeaseOp::Lock,
+                phase: Phase::Read {
+                    ttl,
+                    found: Vec::new(),
+                },
+            },
+        );
+    }
+
+    pub fn renew(&mut self, kademlia: &mut kad::Behaviour<DkvStore>, name: &str, ttl: Duration) {
+        let Some(held) = self.held(name) else {
+            self.fail(name, "not held by this node, LOCK it first");
+            return;
+        };
+        let lease = Lease {
+            expires_at: expires_at(now_millis(), ttl),
+            ..held.clone()
+        };
+        self.write_to_closest(kademlia, name, LeaseOp::Renew, lease);
+    }
+
+    pub fn unlock(&mut self, kademlia: &mut kad::Behaviour<DkvStore>, name: &str) {
+        let Some(held) = self.held.remove(name) else {
+            self.fail(name, "not held by this node");
+            return;
+        };
+        self.write_to_closest(kademlia, name, LeaseOp::Unlock, held.released());
+    }
+
+    fn fail(&mut self, name: &str, reason: impl Into<String>) {
+        self.events.push(LeaseEvent::Failed {
+            name: name.to_string(),
+            reason: reason.into(),
+        });
+    }
+
+    fn write_to_closest(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        name: &str,
+        op: LeaseOp,
+        lease: Lease,
+    ) {
+        let id = kademlia.get_closest_peers(key(&self.keys, name).to_vec());
+        self.pending.insert(
+            id,
+            Pending {
+                name: name.to_string(),
+                op,
+                phase: Phase::Closest { lease },
+            },
+        );
+    }
+
+    /// Returns `true` if the query belongs to a lease command.
+    pub fn on_query_progressed(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        id: kad::QueryId,
+        result: &kad::QueryResult,
+        step: &kad::ProgressStep,
+    ) -> bool {
+        let Some(mut pending) = self.pending.remove(&id) else {
+            return false;
+        };
+        match (&mut pending.phase, result) {
+            (Phase::Read { found, .. }, kad::QueryResult::GetRecord(result)) => {
+                if let Ok(kad::GetRecordOk::FoundRecord(peer_record)) = result {
+                    found.extend(Lease::decode(&peer_record.record.value));
+                }
+                if step.last {
+                    self.on_read(kademlia, pending);
+                } else {
+                    self.pending.insert(id, pending);
+                }
+            }
+            (Phase::Closest { .. }, kad::QueryResult::GetClosestPeers(result)) => {
+                let peers = match result {
+                    Ok(kad::GetClosestPeersOk { peers, .. }) => peers,
+                    Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                let peers: Vec<PeerId> = peers.iter().map(|p| p.peer_id).collect();
+                self.on_closest(kademlia, pending, peers);
+            }
+            (Phase::Write { .. }, kad::QueryResult::PutRecord(result)) => {
+                self.on_written(kademlia, pending, result);
+            }
+            (Phase::Rollback, _) => {}
+            _ => {
+                // 不会发生：每个阶段只发起一种查询
+                self.fail(&pending.name, "unexpected query result");
+            }
+        }
+        true
+    }
+
+    fn on_read(&mut self, kademlia: &mut kad::Behaviour<DkvStore>, pending: Pending) {
+        let Phase::Read { ttl, found } = pending.phase else {
+            unreachable!("called for the read phase only");
+        };
+        let now = now_millis();
+        let local = kademlia.store_mut().local_peer_id();
+        if let Some(lease) = found
+            .iter()
+            .filter(|l| l.holder != local && l.is_live(now))
+            .max_by_key(|l| l.token)
+        {
+            self.events.push(LeaseEvent::Busy {
+                name: pending.name,
+                lease: lease.clone(),
+            });
+            return;
+        }
+        let token = found
+            .iter()
+            .map(|l| l.token)
+            .max()
+            .unwrap_or(0)
+            .saturating_add(1);
+        let lease = Lease {
+            holder: local,
+            token,
+            expires_at: expires_at(now, ttl),
+        };
+        self.write_to_closest(kademlia, &pending.name, LeaseOp::Lock, lease);
+    }
+
+    fn on_closest(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        pending: Pending,
+        peers: Vec<PeerId>,
+    ) {
+        let Phase::Closest { lease } = pending.phase else {
+            unreachable!("called for the closest peers phase only");
+        };
+        if peers.is_empty() {
+            self.fail(&pending.name, "no peers to replicate the lease to");
+            return;
+        }
+        // Majority 按传入的节点数计算，即 K 个最近节点中的多数
+        let id = kademlia.put_record_to(
+            lease.record(key(&self.keys, &pending.name)),
+            peers.into_iter(),
+            kad::Quorum::Majority,
+        );
+        self.pending.insert(
+            id,
+            Pending {
+                phase: Phase::Write { lease },
+                ..pending
+            },
+        );
+    }
+
+    fn on_written(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        pending: Pending,
+        result: &kad::PutRecordResult,
+    ) {
+        let Phase::Write { lease } = pending.phase else {
+            unreachable!("called for the write phase only");
+        };
+        let name = pending.name;
+        let success = match result {
+            Ok(_) => {
+                self.events.push(match pending.op {
+                    LeaseOp::Lock => {
+                        self.held.insert(name.clone(), lease.clone());
+                        LeaseEvent::Acquired { name, lease }
+                    }
+                    LeaseOp::Renew => {
+                        self.held.insert(name.clone(), lease.clone());
+                        LeaseEvent::Renewed { name, lease }
+                    }
+                    LeaseOp::Unlock => LeaseEvent::Released { name, lease },
+                });
+                return;
+            }
+            Err(kad::PutRecordError::QuorumFailed { success, .. })
+            | Err(kad::PutRecordError::Timeout { success, .. }) => success,
+        };
+
+        match pending.op {
+            LeaseOp::Lock => {
+                self.fail(
+                    &name,
+                    format!(
+                        "only {} replicas accepted token {}, not acquired",
+                        success.len(),
+                        lease.token
+                    ),
+                );
+                if !success.is_empty() {
+                    // 释放已写入的少数副本，避免它们阻塞其他竞争者直到过期
+                    let id = kademlia.put_record_to(
+                        lease.released().record(key(&self.keys, &name)),
+                        success.clone().into_iter(),
+                        kad::Quorum::One,
+                    );
+                    self.pending.insert(
+                        id,
+                        Pending {
+                            name,
+                            op: LeaseOp::Unlock,
+                            phase: Phase::Rollback,
+                        },
+                    );
+                }
+            }
+            LeaseOp::Renew => {
+                let reason = match self.held(&name) {
+                    Some(held) => format!(
+                        "renewal not accepted by a majority, still held for {:.1}s",
+                        held.expires_at.saturating_sub(now_millis()) as f64 / 1000.0
+                    ),
+                    None => "renewal not accepted by a majority and the lease expired".to_string(),
+                };
+                self.fail(&name, reason);
+            }
+            LeaseOp::Unlock => self.fail(
+                &name,
+                "release not confirmed by a majority, the lease will expire on its own",
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lease(holder: PeerId, token: u64, expires_at: u64) -> kad::Record {
+        Lease {
+            holder,
+            token,
+            expires_at,
+        }
+        .record(kad::RecordKey::new(&"lock/job"))
+    }
+
+    fn plain(value: &str) -> kad::Record {
+        kad::Record::new(kad::RecordKey::new(&"lock/job"), value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn live_lease_of_another_holder_is_kept() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let live = now_millis() + 60_000;
+        let held = lease(a, 3, live);
+        assert!(!admits(Some(&held), &lease(b, 4, live)));
+        assert!(!admits(Some(&held), &lease(b, 3, live)));
+        // 普通 PUT 同样不能覆盖
+        assert!(!admits(Some(&held), &plain("x")));
+    }
+
+    #[test]
+    fn expired_lease_goes_to_a_higher_token_only() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let expired = lease(a, 3, now_millis() - 1);
+        let live = now_millis() + 60_000;
+        assert!(admits(Some(&expired), &lease(b, 4, live)));
+        assert!(!admits(Some(&expired), &lease(b, 3, live)));
+        assert!(!admits(Some(&expired), &lease(b, 2, live)));
+        assert!(admits(Some(&expired), &plain("x")));
+    }
+
+    #[test]
+    fn holder_never_goes_back_to_an_older_token() {
+        let a = PeerId::random();
+        let live = now_millis() + 60_000;
+        let held = lease(a, 3, live);
+        assert!(admits(Some(&held), &lease(a, 3, live + 1000)));
+        assert!(admits(Some(&held), &lease(a, 3, now_millis())));
+        assert!(admits(Some(&held), &lease(a, 4, live)));
+        assert!(!admits(Some(&held), &lease(a, 2, live)));
+    }
+
+    #[test]
+    fn plain_values_and_empty_replicas_admit_anything() {
+        let a = PeerId::random();
+        let live = now_millis() + 60_000;
+        assert!(admits(None, &lease(a, 1, live)));
+        assert!(admits(None, &plain("x")));
+        assert!(admits(Some(&plain("x")), &lease(a, 1, live)));
+        assert!(admits(Some(&plain("x")), &plain("y")));
+    }
+
+    #[test]
+    fn huge_ttls_saturate() {
+        let now = now_millis();
+        assert_eq!(expires_at(now, Duration::from_secs(60)), now + 60_000);
+        // LOCK x 18446744073709551615
+        let forever = expires_at(now, Duration::from_secs(u64::MAX));
+        assert_eq!(forever, u64::MAX);
+        assert_eq!(expires_at(u64::MAX, Duration::from_millis(1)), u64::MAX);
+        let held = lease(PeerId::random(), 1, forever);
+        assert!(!admits(Some(&held), &lease(PeerId::random(), 2, forever)));
+    }
+
+    #[test]
+    fn leases_round_trip() {
+        let lease = Lease {
+            holder: PeerId::random(),
+            token: 7,
+            expires_at: 42,
+        };
+        assert_eq!(Lease::decode(&lease.encode()), Some(lease.clone()));
+        assert_eq!(Lease::decode(b"plain"), None);
+        assert!(Lease::is_malformed(b"{\"lease\":{}}"));
+        assert!(!lease.is_live(42));
+        assert!(lease.is_live(41));
+    }
+}
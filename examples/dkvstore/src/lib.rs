@@ -0,0 +1,19 @@
+//! A tiny distributed key-value store on Kademlia.
+//!
+//! The `dkvstore` binary is the interactive node; the modules are exposed so
+//! that other binaries can run nodes in-process.
+
+pub mod behaviour;
+pub mod cluster;
+pub mod crdt;
+pub mod gateway;
+pub mod keyspace;
+pub mod lease;
+pub mod logging;
+pub mod ownership;
+pub mod peers;
+pub mod reputation;
+pub mod sim;
+pub mod snapshot;
+pub mod store;
+pub mod tui;
@@ -0,0 +1,322 @@
+//! Console output and structured logs of a node.
+//!
+//! Replies to commands and notable events are printed with [`outln!`] and
+//! [`errln!`]; diagnostics go through `tracing`. With `--log-format json`
+//! both end up on stdout as one JSON object per line, and the node's own
+//! events use the same fields:
+//!
+//! - `kind`: what happened, e.g. `output`, `connection_established`,
+//!   `query_started` or `query_finished`
+//! - `peer_id`: the remote peer
+//! - `key`: the record key, in hex if it is not UTF-8
+//! - `query_id`: the Kademlia query
+//! - `duration_ms`: how long a query ran
+//!
+//! A command read from stdin or received by the HTTP gateway opens a
+//! `command` span. The Kademlia queries it starts, including the follow-up
+//! queries of CRDT updates and leases, log within that span until they
+//! finish, and the span logs its own lifetime when the last one is done.
+//!
+//! While a dashboard is open everything is captured for its event pane
+//! instead of being printed.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use clap::ValueEnum;
+use libp2p::kad;
+use tracing::Span;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+use crate::store::DkvStore;
+
+/// Lines kept for the dashboard's event pane
+const CAPTURE_CAPACITY: usize = 1000;
+
+/// Filter of the JSON mode when `RUST_LOG` is not set
+const DEFAULT_JSON_FILTER: &str = "warn,dkvstore=info";
+
+/// Captured output while a dashboard is open, `None` otherwise.
+pub(crate) static CAPTURED: Mutex<Option<VecDeque<LogLine>>> = Mutex::new(None);
+
+/// Whether [`outln!`] and [`errln!`] go through `tracing`
+static STRUCTURED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) struct LogLine {
+    pub(crate) text: String,
+    pub(crate) error: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines for people
+    Text,
+    /// One JSON object per line for log pipelines
+    Json,
+}
+
+/// Prints a line to stdout, or to the dashboard's event pane while it is open.
+#[macro_export]
+macro_rules! outln {
+    ($($arg:tt)*) => {
+        $crate::logging::emit(false, format_args!($($arg)*))
+    };
+}
+
+/// Prints a line to stderr, or to the dashboard's event pane while it is open.
+#[macro_export]
+macro_rules! errln {
+    ($($arg:tt)*) => {
+        $crate::logging::emit(true, format_args!($($arg)*))
+    };
+}
+
+/// Installs the global `tracing` subscriber; `RUST_LOG` selects what is logged.
+pub fn init(format: LogFormat, ansi: bool) {
+    let builder = tracing_subscriber::fmt().with_writer(log_writer);
+    let _ = match format {
+        LogFormat::Text => builder
+            .with_env_filter(EnvFilter::from_default_env())
+            .with_ansi(ansi)
+            .try_init(),
+        LogFormat::Json => {
+            STRUCTURED.store(true, Ordering::Relaxed);
+            builder
+                .json()
+                .with_env_filter(
+                    EnvFilter::try_from_default_env()
+                        .unwrap_or_else(|_| EnvFilter::new(DEFAULT_JSON_FILTER)),
+                )
+                .with_span_events(FmtSpan::CLOSE)
+                .try_init()
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn emit(error: bool, args: fmt::Arguments) {
+    if capture(error, &args) {
+        return;
+    }
+    match (STRUCTURED.load(Ordering::Relaxed), error) {
+        (true, true) => tracing::warn!(kind = "output", "{}", args),
+        (true, false) => tracing::info!(kind = "output", "{}", args),
+        (false, true) => eprintln!("{}", args),
+        (false, false) => println!("{}", args),
+    }
+}
+
+/// Keeps `args` for the event pane if a dashboard is open.
+fn capture(error: bool, args: &dyn fmt::Display) -> bool {
+    let mut captured = CAPTURED.lock().unwrap();
+    let Some(captured) = captured.as_mut() else {
+        return false;
+    };
+    for text in args.to_string().lines() {
+        if captured.len() == CAPTURE_CAPACITY {
+            captured.pop_front();
+        }
+        captured.push_back(LogLine {
+            text: text.to_string(),
+            error,
+        });
+    }
+    true
+}
+
+fn log_writer() -> LogWriter {
+    LogWriter
+}
+
+/// Writer of the `tracing` subscriber: stdout, or the event pane.
+struct LogWriter;
+
+impl io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        if !capture(false, &text.trim_end()) {
+            io::stdout().write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// Kademlia queries running at some point, see [`QueryTracer::adopt`].
+pub struct QueryMark(HashSet<kad::QueryId>);
+
+impl QueryMark {
+    pub fn new(kademlia: &kad::Behaviour<DkvStore>) -> Self {
+        Self(kademlia.iter_queries().map(|q| q.id()).collect())
+    }
+}
+
+struct Traced {
+    span: Span,
+    key: Option<String>,
+}
+
+/// Spans of the running Kademlia queries that were started within one.
+#[derive(Default)]
+pub struct QueryTracer {
+    queries: HashMap<kad::QueryId, Traced>,
+}
+
+impl QueryTracer {
+    /// Logs the queries started since `mark` and attaches them to `span`.
+    pub fn adopt(&mut self, mark: QueryMark, kademlia: &kad::Behaviour<DkvStore>, span: &Span) {
+        for query in kademlia.iter_queries() {
+            let id = query.id();
+            if mark.0.contains(&id) {
+                continue;
+            }
+            let (name, key) = describe_info(query.info());
+            tracing::info!(
+                parent: span,
+                kind = "query_started",
+                query_id = ?id,
+                query = name,
+                key = key.as_deref(),
+            );
+            if !span.is_disabled() {
+                self.queries.insert(
+                    id,
+                    Traced {
+                        span: span.clone(),
+                        key,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Logs the progress of query `id` and returns the span it belongs to,
+    /// which is released once the query finished.
+    pub fn on_query_progressed(
+        &mut self,
+        id: kad::QueryId,
+        result: &kad::QueryResult,
+        stats: &kad::QueryStats,
+        step: &kad::ProgressStep,
+    ) -> Span {
+        let (name, key, error) = describe_result(result);
+        let (span, key) = match self.queries.get(&id) {
+            Some(traced) => (
+                traced.span.clone(),
+                traced.key.clone().or(key.map(printable)),
+            ),
+            None => (Span::none(), key.map(printable)),
+        };
+        let duration_ms = stats.duration().map(|d| d.as_millis() as u64);
+        if !step.last {
+            tracing::debug!(
+                parent: &span,
+                kind = "query_progressed",
+                query_id = ?id,
+                query = name,
+                key = key.as_deref(),
+                step = step.count.get(),
+                duration_ms,
+            );
+            return span;
+        }
+        self.queries.remove(&id);
+        match error {
+            Some(error) => tracing::warn!(
+                parent: &span,
+                kind = "query_finished",
+                query_id = ?id,
+                query = name,
+                key = key.as_deref(),
+                duration_ms,
+                requests = stats.num_requests(),
+                error,
+            ),
+            None => tracing::info!(
+                parent: &span,
+                kind = "query_finished",
+                query_id = ?id,
+                query = name,
+                key = key.as_deref(),
+                duration_ms,
+                requests = stats.num_requests(),
+            ),
+        }
+        span
+    }
+}
+
+fn describe_info(info: &kad::QueryInfo) -> (&'static str, Option<String>) {
+    match info {
+        kad::QueryInfo::Bootstrap { .. } => ("bootstrap", None),
+        kad::QueryInfo::GetClosestPeers { key, .. } => ("get_closest_peers", Some(printable(key))),
+        kad::QueryInfo::GetProviders { key, .. } => ("get_providers", Some(printable(key))),
+        kad::QueryInfo::AddProvider { key, .. } => ("add_provider", Some(printable(key))),
+        kad::QueryInfo::PutRecord { record, .. } => ("put_record", Some(printable(&record.key))),
+        kad::QueryInfo::GetRecord { key, .. } => ("get_record", Some(printable(key))),
+    }
+}
+
+/// Name, key if the result carries one, and error of a query result.
+fn describe_result(result: &kad::QueryResult) -> (&'static str, Option<&[u8]>, Option<String>) {
+    fn error(e: &dyn fmt::Debug) -> Option<String> {
+        Some(format!("{:?}", e))
+    }
+    match result {
+        kad::QueryResult::Bootstrap(Ok(_)) => ("bootstrap", None, None),
+        kad::QueryResult::Bootstrap(Err(e)) => ("bootstrap", None, error(e)),
+        kad::QueryResult::GetClosestPeers(Ok(ok)) => ("get_closest_peers", Some(&ok.key), None),
+        kad::QueryResult::GetClosestPeers(Err(e)) => ("get_closest_peers", Some(e.key()), error(e)),
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, .. })) => {
+            ("get_providers", Some(key.as_ref()), None)
+        }
+        kad::QueryResult::GetProviders(Ok(_)) => ("get_providers", None, None),
+        kad::QueryResult::GetProviders(Err(e)) => {
+            ("get_providers", Some(e.key().as_ref()), error(e))
+        }
+        kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
+            ("add_provider", Some(key.as_ref()), None)
+        }
+        kad::QueryResult::StartProviding(Err(e)) => {
+            ("add_provider", Some(e.key().as_ref()), error(e))
+        }
+        kad::QueryResult::RepublishProvider(Ok(kad::AddProviderOk { key })) => {
+            ("republish_provider", Some(key.as_ref()), None)
+        }
+        kad::QueryResult::RepublishProvider(Err(e)) => {
+            ("republish_provider", Some(e.key().as_ref()), error(e))
+        }
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(found))) => {
+            ("get_record", Some(found.record.key.as_ref()), None)
+        }
+        kad::QueryResult::GetRecord(Ok(_)) => ("get_record", None, None),
+        kad::QueryResult::GetRecord(Err(e)) => ("get_record", Some(e.key().as_ref()), error(e)),
+        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+            ("put_record", Some(key.as_ref()), None)
+        }
+        kad::QueryResult::PutRecord(Err(e)) => ("put_record", Some(e.key().as_ref()), error(e)),
+        kad::QueryResult::RepublishRecord(Ok(kad::PutRecordOk { key })) => {
+            ("republish_record", Some(key.as_ref()), None)
+        }
+        kad::QueryResult::RepublishRecord(Err(e)) => {
+            ("republish_record", Some(e.key().as_ref()), error(e))
+        }
+    }
+}
+
+/// A record key as text: itself if it is UTF-8, hex otherwise (hashed keys).
+pub fn printable(key: impl AsRef<[u8]>) -> String {
+    match std::str::from_utf8(key.as_ref()) {
+        Ok(key) => key.to_string(),
+        Err(_) => key.as_ref().iter().map(|b| format!("{:02x}", b)).collect(),
+    }
+}
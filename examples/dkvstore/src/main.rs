@@ -1,182 +1,949 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use std::time::Duration;
-
+use clap::Parser;
 use futures::StreamExt;
 // use anyhow::{Ok, Result};
 use libp2p::{
-    kad::{self, store::MemoryStore},
-    mdns, noise,
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, SwarmBuilder,
+    autonat, dcutr, identify,
+    identity::Keypair,
+    kad::{self, store::RecordStore},
+    mdns,
+    multiaddr::Protocol,
+    ping, relay,
+    swarm::{DialError, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
 };
 use tokio::{
     self,
     io::{self, AsyncBufReadExt},
     select,
+    sync::mpsc,
+};
+use tracing::Span;
+
+use dkvstore::{
+    behaviour::{new_swarm, Behavior, BehaviorEvent, BehaviourOptions, NodeMode},
+    crdt::{self, CrdtQueries},
+    errln,
+    gateway::{self, PendingQueries},
+    keyspace::{KeyDerivation, KeyHash, KeyspaceEvent},
+    lease::LeaseManager,
+    logging::{self, printable, LogFormat, QueryMark, QueryTracer},
+    outln,
+    ownership::Ownership,
+    peers::PeerBook,
+    reputation::{self, Offence, Reputation},
+    snapshot::{Import, Snapshot},
+    store::{DkvStore, StoreLimits, DEFAULT_MAX_PROVIDED_KEYS, DEFAULT_MAX_RECORDS},
+    tui::{Action, Dashboard},
 };
-use tracing::event;
-use tracing_subscriber::EnvFilter;
 
-// NetworkBehaviour 派生宏：自动实现网络行为的委托和集成
-// 允许组合多个网络协议行为（Kademlia DHT + mDNS 服务发现）
-#[derive(NetworkBehaviour)]
-struct Behavior {
-    kademlia: kad::Behaviour<MemoryStore>, // Kademlia 分布式哈希表：用于节点路由和数据存储
-    mdns: mdns::tokio::Behaviour,          // mDNS 本地服务发现：在局域网内自动发现对等节点
+#[derive(Debug, Parser)]
+#[command(
+    name = "dkvstore",
+    about = "A tiny distributed key-value store on Kademlia"
+)]
+struct Cli {
+    /// Seed the local store from a snapshot file on startup
+    #[arg(long, value_name = "PATH")]
+    import: Option<PathBuf>,
+
+    /// Publish the imported records to the network once connected
+    #[arg(long, requires = "import")]
+    republish: bool,
+
+    /// Periodically dump the local store to a snapshot file
+    #[arg(long, value_name = "PATH")]
+    export: Option<PathBuf>,
+
+    /// Seconds between two periodic exports
+    #[arg(long, default_value_t = 60, requires = "export")]
+    export_interval: u64,
+
+    /// Node profile: full DHT server, light client, or decided by reachability
+    #[arg(long, value_enum, default_value_t = NodeMode::Server)]
+    mode: NodeMode,
+
+    /// Known peer to bootstrap from, e.g. /ip4/1.2.3.4/tcp/4001/p2p/<peer id>
+    #[arg(long = "peer", value_name = "MULTIADDR")]
+    peers: Vec<Multiaddr>,
+
+    /// Address under which this node is reachable from the outside
+    #[arg(long = "external-address", value_name = "MULTIADDR")]
+    external_addresses: Vec<Multiaddr>,
+
+    /// Address to listen on; defaults to all interfaces on a random port
+    #[arg(long = "listen", value_name = "MULTIADDR")]
+    listen_addresses: Vec<Multiaddr>,
+
+    /// Disable mDNS, e.g. to simulate peers that cannot see each other directly
+    #[arg(long)]
+    no_mdns: bool,
+
+    /// Act as a circuit relay v2 server for peers behind NAT; combine with
+    /// --external-address so reservations carry a dialable address
+    #[arg(long)]
+    relay_server: bool,
+
+    /// Relay to reserve a slot on and listen through, e.g. /ip4/1.2.3.4/tcp/4001/p2p/<peer id>
+    #[arg(long = "relay", value_name = "MULTIADDR")]
+    relays: Vec<Multiaddr>,
+
+    /// Hand locally stored records off to the closest peers when stopped by a signal
+    #[arg(long)]
+    handoff_on_exit: bool,
+
+    /// Seconds to wait for hand-off and disconnects before exiting anyway
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Serve the HTTP gateway on this address, e.g. 127.0.0.1:8080
+    #[arg(long, value_name = "ADDR")]
+    http: Option<SocketAddr>,
+
+    /// Seconds an HTTP request may wait for its Kademlia query
+    #[arg(long, default_value_t = 30, requires = "http")]
+    http_timeout: u64,
+
+    /// Show a terminal dashboard with a command line instead of reading stdin
+    #[arg(long)]
+    tui: bool,
+
+    /// Keep the ban list in this file so bans survive restarts
+    #[arg(long, value_name = "PATH")]
+    ban_file: Option<PathBuf>,
+
+    /// Seconds between two republications of the records this node wrote
+    #[arg(long, default_value_t = 3600)]
+    republish_interval: u64,
+
+    /// Quorum republications have to reach: one, majority, all or a number
+    #[arg(long, default_value = "one")]
+    republish_quorum: String,
+
+    /// Seconds between two re-announcements of this node's provider records
+    #[arg(long, default_value_t = 12 * 3600)]
+    reprovide_interval: u64,
+
+    /// Log lines for people, or JSON objects with peer, key and query fields
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// How keys become record keys: as they are, hashed, or hashed within a namespace
+    #[arg(long, value_enum, default_value_t = KeyHash::Raw)]
+    key_hash: KeyHash,
+
+    /// Namespace of the main DHT's keys with --key-hash namespaced; keyspaces use their name
+    #[arg(long, default_value = "dkvstore")]
+    key_namespace: String,
+
+    /// Also join the separate DHT of this keyspace, e.g. one per tenant
+    #[arg(long = "keyspace", value_name = "NAME")]
+    keyspaces: Vec<String>,
+
+    /// Records the store holds at most, replicas of other peers' records included
+    #[arg(long, default_value_t = DEFAULT_MAX_RECORDS)]
+    max_records: usize,
+
+    /// Keys this node provides at most
+    #[arg(long, default_value_t = DEFAULT_MAX_PROVIDED_KEYS)]
+    max_provided_keys: usize,
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let _ = tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .try_init();
-
-    // SwarmBuilder: 构建点对点网络的核心组件
-    let mut swarm = SwarmBuilder::with_new_identity()
-        .with_tokio() // 使用 Tokio 运行时进行异步网络操作
-        .with_tcp(
-            tcp::Config::default(), // TCP 传输层配置：提供基础网络连接 |处理套接字创建和管理 | 支持 IPv4/IPv6
-            noise::Config::new,     // 安全传输层：Noise 协议，提供加密、身份验证和前向保密
-            yamux::Config::default, //多路复用协议：Yamux，在单个 TCP 连接上复用多个子流，提高网络效率和并发性
-        )?
-        .with_behaviour(|key| {
-            // 网络行为配置
-            Ok(Behavior {
-                //Kademlia 分布式哈希表初始化：节点路由 | 去中心化数据存储 | 点对点服务发现
-                kademlia: kad::Behaviour::new(
-                    key.public().to_peer_id(),                   // 使用公钥生成唯一 PeerID
-                    MemoryStore::new(key.public().to_peer_id()), // 内存存储 DHT 数据
-                ),
-                // mDNS 本地服务发现：自动发现同一局域网内的节点
-                mdns: mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )?,
-            })
-        })?
-        // with_swarm_config configuration: <details: What? When? Why?>
-        .with_swarm_config(
-            |c| 
-            // 空闲连接超时：释放未使用资源
-            c.with_idle_connection_timeout(Duration::from_secs(60)), // 连接池大小控制等等..
-                                                                     // .with_max_negotiating_inbound_streams(10)
-        )
-        .build();
-
-    swarm
-        .behaviour_mut()
-        .kademlia
-        .set_mode(Some(kad::Mode::Server)); // <details: What? When? Why?>
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let cli = Cli::parse();
+
+    logging::init(cli.log_format, !cli.tui);
+    let store = StoreLimits {
+        max_records: cli.max_records,
+        max_provided_keys: cli.max_provided_keys,
+    };
+
+    let mut swarm = new_swarm(
+        Keypair::generate_ed25519(),
+        BehaviourOptions {
+            mode: cli.mode,
+            mdns: !cli.no_mdns,
+            relay_server: cli.relay_server,
+            republish_interval: Some(Duration::from_secs(cli.republish_interval.max(1))),
+            reprovide_interval: Some(Duration::from_secs(cli.reprovide_interval.max(1))),
+            store,
+        },
+    )?;
+
+    outln!("Local peer id: {}", swarm.local_peer_id());
+
+    let local_peer_id = *swarm.local_peer_id();
+    for name in &cli.keyspaces {
+        swarm
+            .behaviour_mut()
+            .keyspaces
+            .add(name, local_peer_id, store)?;
+    }
+
+    // Server: 固定为服务端，接受并存储其他节点的记录
+    // Light: 固定为客户端，不在路由表中宣告自己，只通过服务端节点读写
+    // Auto: 由 libp2p 根据是否存在已确认的外部地址自动切换
+    let kad_mode = match cli.mode {
+        NodeMode::Server => Some(kad::Mode::Server),
+        NodeMode::Light => Some(kad::Mode::Client),
+        NodeMode::Auto => None,
+    };
+    swarm.behaviour_mut().kademlia.set_mode(kad_mode);
+    swarm.behaviour_mut().keyspaces.set_mode(kad_mode);
+    let keys = KeyDerivation::new(cli.key_hash, cli.key_namespace.clone());
+
+    for addr in &cli.external_addresses {
+        swarm.add_external_address(addr.clone());
+    }
+
+    // 导入快照：先写入本地存储，需要 republish 时等到第一个连接建立后再发布
+    let mut pending_republish = None;
+    if let Some(path) = &cli.import {
+        let snapshot = Snapshot::read_from(path)?;
+        let import = snapshot.restore(swarm.behaviour_mut().kademlia.store_mut());
+        report_import(&import, &path.display().to_string());
+        if cli.republish {
+            pending_republish = Some(snapshot);
+        }
+    }
+
+    let export_period = Duration::from_secs(cli.export_interval.max(1));
+    let mut export_tick =
+        tokio::time::interval_at(tokio::time::Instant::now() + export_period, export_period);
 
     // read full lines from stdin
     let mut stdin = io::BufReader::new(io::stdin()).lines();
 
     // listen on all interfaces and whatever port the OS assigns.
-    swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    if cli.listen_addresses.is_empty() {
+        swarm.listen_on("/ip4/0.0.0.0/tcp/0".parse()?)?;
+    }
+    for addr in &cli.listen_addresses {
+        swarm.listen_on(addr.clone())?;
+    }
+
+    for addr in &cli.peers {
+        let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
+            return Err(format!("peer address {} must end with /p2p/<peer id>", addr).into());
+        };
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, addr.clone());
+        swarm.dial(addr.clone())?;
+    }
+    if !cli.peers.is_empty() {
+        swarm.behaviour_mut().kademlia.bootstrap()?;
+    }
 
+    // 在中继上预留槽位：其他节点可通过 <relay>/p2p-circuit/p2p/<本节点> 连接进来，
+    // 随后由 DCUtR 尝试打洞升级为直连。先建立到中继的直连，再在其上监听，
+    // 避免端口复用下对同一节点并发拨号导致预留失败
+    let mut pending_relays = HashMap::new();
+    for addr in &cli.relays {
+        let Some(Protocol::P2p(relay_id)) = addr.iter().last() else {
+            return Err(format!("relay address {} must end with /p2p/<peer id>", addr).into());
+        };
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&relay_id, addr.clone());
+        swarm
+            .behaviour_mut()
+            .autonat
+            .add_server(relay_id, Some(addr.clone()));
+        if !cli.peers.contains(addr) {
+            swarm.dial(addr.clone())?;
+        }
+        pending_relays.insert(relay_id, addr.clone());
+    }
+
+    let mut state = NodeState {
+        pending_relays,
+        pending_republish,
+        gateway: PendingQueries::default(),
+        crdt: CrdtQueries::default(),
+        leases: LeaseManager::new(keys.clone()),
+        peers: PeerBook::default(),
+        reputation: Reputation::open(cli.ban_file.clone(), swarm.behaviour_mut())?,
+        ownership: Ownership::new(
+            Duration::from_secs(cli.republish_interval.max(1)),
+            Duration::from_secs(cli.reprovide_interval.max(1)),
+            gateway::parse_quorum(Some(&cli.republish_quorum))
+                .map_err(|_| format!("invalid quorum {:?}", cli.republish_quorum))?,
+        ),
+        tracer: QueryTracer::default(),
+        keys: keys.clone(),
+    };
+    let mut republish_tick = tokio::time::interval(state.ownership.check_interval());
+    let mut reputation_tick = tokio::time::interval(reputation::MAINTENANCE_INTERVAL);
+
+    // HTTP 网关运行在独立任务中，请求通过 channel 交给事件循环发起 Kademlia 查询
+    let (gateway_tx, mut gateway_rx) = mpsc::channel(64);
+    if let Some(addr) = cli.http {
+        let timeout = Duration::from_secs(cli.http_timeout);
+        tokio::spawn(async move {
+            if let Err(e) = gateway::serve(addr, gateway_tx, timeout, keys).await {
+                errln!("HTTP gateway error: {:?}", e);
+            }
+        });
+    }
+
+    // Ctrl-C / SIGTERM 与 QUIT 命令走同一套退出流程
+    let shutdown_signal = shutdown_signal();
+    tokio::pin!(shutdown_signal);
+    let mut stdin_open = !cli.tui;
+
+    // TUI 模式下终端由仪表盘接管，输出进入事件面板，命令从输入框读取
+    let mut dashboard = if cli.tui {
+        Some(Dashboard::open()?)
+    } else {
+        None
+    };
+    let mut redraw_tick = tokio::time::interval(Duration::from_millis(250));
 
     // kick it off
-    loop {
+    let handoff = loop {
         select! {
-            Ok(Some(line)) = stdin.next_line()=>{
-                handle_input_line(&mut swarm.behaviour_mut().kademlia, line);
-            }
-            event = swarm.select_next_some() => match event{
-                SwarmEvent::NewListenAddr{address,..}=>{
-                    println!("Listening on {:?}", address);
-                },
-                SwarmEvent::Behaviour(BehaviorEvent::Mdns(mdns::Event::Discovered(list)))=>{
-                   for (peer_id,multiaddr) in list {
-                        swarm.behaviour_mut().kademlia.add_address(&peer_id,multiaddr);
-                   }
-                },
-                // todo: handle other events
-                SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::OutboundQueryProgressed { result,.. })) =>{
-                    match result {
-                        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders { key, providers })) => {
-                            for peer in providers {
-                                println!("Found provider {} for key {}", peer, std::str::from_utf8(key.as_ref()).unwrap());
-                            }
-                        },
-                        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { closest_peers })) => {
-                            for peer in closest_peers {
-                                println!("Found closest_peers: ({}) ", peer);
-                            }
-                        },
-                        kad::QueryResult::GetProviders(Err(e)) => {
-                            eprintln!("GetProviders error: {:?}", e);
-                        },
-                        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord{record:kad::Record{key,value,..},..}))) => {
-                            println!("Found record {} for key {}", 
-                            std::str::from_utf8(value.as_ref()).unwrap(), 
-                            std::str::from_utf8(key.as_ref()).unwrap());
-                        },
-                        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord { cache_candidates })) => {
-                            for (kb_distance,peer_id) in cache_candidates {
-                                println!("Found cache_candidate: ({}) with kb_distance: ({:?})", peer_id, kb_distance.ilog2());
-                            }
-                        },
-                        kad::QueryResult::GetRecord(Err(e)) => {
-                            eprint!("GetRecord error: {:?}", e);
-                        },
-                        kad::QueryResult::PutRecord(Ok(
-                             kad::PutRecordOk{key}
-                        )) => {
-                            println!("Put record {}", std::str::from_utf8(key.as_ref()).unwrap());
-                        },
-                        kad::QueryResult::PutRecord(Err(e)) => {
-                            eprintln!("Put record error: {:?}", e);
-                        },
-                        kad::QueryResult::StartProviding(Ok(kad::AddProviderOk{key})) => {
-                            println!("Started providing {}", std::str::from_utf8(key.as_ref()).unwrap());
-                        },
-                        kad::QueryResult::StartProviding(Err(e)) => {
-                            eprintln!("StartProviding error: {:?}", e);
-                        },
-                        _=>{    
-                            println!("None handler for event");
+            line = stdin.next_line(), if stdin_open => match line {
+                Ok(Some(line)) => {
+                    if let Flow::Quit { handoff } = run_command(&mut swarm, &mut state, "stdin", line) {
+                        break handoff;
+                    }
+                }
+                Ok(None) => {
+                    outln!("stdin closed, no more commands accepted; press Ctrl-C to stop the node");
+                    stdin_open = false;
+                }
+                Err(e) => {
+                    errln!("Failed to read stdin: {:?}", e);
+                    stdin_open = false;
+                }
+            },
+            action = next_action(&mut dashboard) => match action {
+                Ok(Action::Command(line)) => {
+                    if let Flow::Quit { handoff } = run_command(&mut swarm, &mut state, "tui", line) {
+                        break handoff;
+                    }
+                    redraw(&mut dashboard, &mut swarm);
+                }
+                Ok(Action::Redraw) => redraw(&mut dashboard, &mut swarm),
+                Ok(Action::Quit) => break cli.handoff_on_exit,
+                Err(e) => {
+                    errln!("Failed to read the terminal: {:?}", e);
+                    break cli.handoff_on_exit;
+                }
+            },
+            _ = redraw_tick.tick(), if dashboard.is_some() => redraw(&mut dashboard, &mut swarm),
+            _ = &mut shutdown_signal => {
+                break cli.handoff_on_exit;
+            }
+            _ = export_tick.tick(), if cli.export.is_some() => {
+                if let Some(path) = &cli.export {
+                    export_snapshot(swarm.behaviour_mut().kademlia.store_mut(), path);
+                }
+            }
+            _ = reputation_tick.tick() => state.reputation.maintain(swarm.behaviour_mut()),
+            _ = republish_tick.tick() => state.ownership.republish_due(&mut swarm.behaviour_mut().kademlia),
+            Some(request) = gateway_rx.recv(), if cli.http.is_some() => {
+                let span = request.span();
+                traced(&mut swarm, &mut state, span, |swarm, state| state.gateway.start(swarm, &mut state.ownership, request));
+            }
+            event = swarm.select_next_some() => handle_swarm_event(&mut swarm, event, &mut state),
+        }
+    };
+
+    // 先恢复终端，退出过程的输出直接打印
+    drop(dashboard);
+
+    shutdown(
+        &mut swarm,
+        &mut state,
+        handoff,
+        cli.export.as_deref(),
+        Duration::from_secs(cli.shutdown_timeout),
+    )
+    .await;
+
+    Ok(())
+}
+
+async fn next_action(dashboard: &mut Option<Dashboard>) -> io::Result<Action> {
+    match dashboard {
+        Some(dashboard) => dashboard.next_action().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn redraw(dashboard: &mut Option<Dashboard>, swarm: &mut Swarm<Behavior>) {
+    if let Some(dashboard) = dashboard {
+        if let Err(e) = dashboard.draw(swarm) {
+            errln!("Failed to draw the dashboard: {:?}", e);
+        }
+    }
+}
+
+/// Bookkeeping shared by the main loop and the shutdown sequence.
+struct NodeState {
+    /// Relays we dialed and will listen through once connected
+    pending_relays: HashMap<PeerId, Multiaddr>,
+    /// Imported snapshot waiting for the first connection to be republished
+    pending_republish: Option<Snapshot>,
+    /// Queries started by the HTTP gateway
+    gateway: PendingQueries,
+    /// Reads of CRDT values waiting for all replicas to be merged
+    crdt: CrdtQueries,
+    /// Lease commands in flight and the leases held by this node
+    leases: LeaseManager,
+    /// Agent, protocols and RTT of the peers seen so far
+    peers: PeerBook,
+    /// Peer scores and bans
+    reputation: Reputation,
+    /// Keys written from this node, republished periodically
+    ownership: Ownership,
+    /// Spans of the queries started by commands
+    tracer: QueryTracer,
+    /// Turns keys typed on stdin into record keys
+    keys: KeyDerivation,
+}
+
+/// Runs `f` within `span`; the Kademlia queries it starts keep logging
+/// within the span until they finish.
+fn traced<R>(
+    swarm: &mut Swarm<Behavior>,
+    state: &mut NodeState,
+    span: Span,
+    f: impl FnOnce(&mut Swarm<Behavior>, &mut NodeState) -> R,
+) -> R {
+    let mark = QueryMark::new(&swarm.behaviour().kademlia);
+    let result = span.in_scope(|| f(swarm, state));
+    state.tracer.adopt(mark, &swarm.behaviour().kademlia, &span);
+    result
+}
+
+/// Runs a command line from `source` within its own span.
+fn run_command(
+    swarm: &mut Swarm<Behavior>,
+    state: &mut NodeState,
+    source: &'static str,
+    line: String,
+) -> Flow {
+    let mut args = line.split_ascii_whitespace();
+    let command = args.next().unwrap_or_default();
+    let span = tracing::info_span!(
+        "command",
+        source,
+        command,
+        key = tracing::field::Empty,
+        peer_id = tracing::field::Empty,
+    );
+    match (command, args.next()) {
+        ("PEER" | "BAN" | "UNBAN", Some(peer)) => {
+            span.record("peer_id", peer);
+        }
+        (_, Some(key)) => {
+            span.record("key", key);
+        }
+        _ => {}
+    }
+    traced(swarm, state, span, |swarm, state| {
+        handle_input_line(swarm.behaviour_mut(), state, line)
+    })
+}
+
+fn handle_swarm_event(
+    swarm: &mut Swarm<Behavior>,
+    event: SwarmEvent<BehaviorEvent>,
+    state: &mut NodeState,
+) {
+    match event {
+        SwarmEvent::NewListenAddr { address, .. } => {
+            outln!("Listening on {:?}", address);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Mdns(mdns::Event::Discovered(list))) => {
+            for (peer_id, multiaddr) in list {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peer_id, multiaddr);
+            }
+        }
+        SwarmEvent::ConnectionEstablished {
+            peer_id, endpoint, ..
+        } => {
+            tracing::info!(
+                kind = "connection_established",
+                peer_id = %peer_id,
+                address = %endpoint.get_remote_address(),
+            );
+            state.peers.on_connection_established(peer_id);
+            state
+                .reputation
+                .on_connection_established(swarm.behaviour_mut(), peer_id);
+            if let Some(addr) = state.pending_relays.remove(&peer_id) {
+                if let Err(e) = swarm.listen_on(addr.with(Protocol::P2pCircuit)) {
+                    errln!("Failed to listen via relay {}: {:?}", peer_id, e);
+                }
+            }
+            if let Some(snapshot) = state.pending_republish.take() {
+                let import =
+                    snapshot.republish(&mut swarm.behaviour_mut().kademlia, &mut state.ownership);
+                report_import(&import, "the imported snapshot (republished)");
+            }
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::ModeChanged { new_mode })) => {
+            outln!("Kademlia mode changed to {}", new_mode);
+        }
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established,
+            ..
+        } => {
+            tracing::info!(
+                kind = "connection_closed",
+                peer_id = %peer_id,
+                remaining = num_established,
+            );
+            state.peers.on_connection_closed(peer_id, num_established);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+            ..
+        })) => {
+            // identify 随 NAT 穿透加入（AutoNAT 和 DCUtR 依赖它），它学到的信息供 PEER 命令查看，
+            // 监听地址用于补全各个 DHT 的路由表
+            state.peers.on_identify(peer_id, &info);
+            swarm
+                .behaviour_mut()
+                .keyspaces
+                .add_peer(&peer_id, &info.protocols, &info.listen_addrs);
+            // 只有对方以服务端身份参与 DHT 时才加入路由表，地址可能包含 /p2p-circuit 中继地址
+            if info.protocols.contains(&kad::PROTOCOL_NAME) {
+                for addr in info.listen_addrs {
+                    swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                }
+            }
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Ping(ping::Event { peer, result, .. })) => {
+            match result {
+                Ok(rtt) => state.peers.on_ping(peer, rtt),
+                // Other 多为连接层的 I/O 错误，不一定是对方作恶，和超时同等处理
+                Err(ping::Failure::Timeout | ping::Failure::Other { .. }) => {
+                    state
+                        .reputation
+                        .penalize(swarm.behaviour_mut(), peer, Offence::FailedRequest);
+                }
+                Err(ping::Failure::Unsupported) => {}
+            }
+        }
+        // 拨号时对方的身份与地址中的不符：被拨号的节点可能只是换了地址，
+        // 惩罚的是实际应答的那个身份
+        SwarmEvent::OutgoingConnectionError {
+            error: DialError::WrongPeerId { obtained, .. },
+            ..
+        } => {
+            state
+                .reputation
+                .penalize(swarm.behaviour_mut(), obtained, Offence::ProtocolError);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::InboundRequest {
+            request: kad::InboundRequest::PutRecord { source, .. },
+        })) => {
+            state
+                .reputation
+                .on_inbound_write(swarm.behaviour_mut(), source);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Keyspaces(event)) => {
+            handle_keyspace_event(swarm, event, state);
+        }
+        SwarmEvent::ListenerClosed {
+            addresses,
+            reason: Err(e),
+            ..
+        } => {
+            errln!("Listener on {:?} closed: {}", addresses, e);
+        }
+        SwarmEvent::ExternalAddrConfirmed { address } => {
+            outln!("External address confirmed: {}", address);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Autonat(autonat::Event::StatusChanged {
+            old,
+            new,
+        })) => {
+            outln!("NAT status changed from {:?} to {:?}", old, new);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::RelayClient(
+            relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+        )) => {
+            outln!("Reservation accepted by relay {}", relay_peer_id);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Relay(relay::Event::ReservationReqAccepted {
+            src_peer_id,
+            ..
+        })) => {
+            outln!("Accepted relay reservation from {}", src_peer_id);
+        }
+        SwarmEvent::Behaviour(BehaviorEvent::Dcutr(dcutr::Event {
+            remote_peer_id,
+            result,
+        })) => match result {
+            Ok(_) => outln!("Hole punched a direct connection to {}", remote_peer_id),
+            Err(e) => errln!("Hole punching to {} failed: {}", remote_peer_id, e),
+        },
+        // todo: handle other events
+        SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+            id,
+            result,
+            stats,
+            step,
+        })) => {
+            let span = state.tracer.on_query_progressed(id, &result, &stats, &step);
+            traced(swarm, state, span, |swarm, state| {
+                handle_query_progressed(swarm, state, id, result, step)
+            });
+        }
+        _ => {
+            // outln!("None handler for event: {:30?}",event);
+        }
+    }
+}
+
+fn handle_query_progressed(
+    swarm: &mut Swarm<Behavior>,
+    state: &mut NodeState,
+    id: kad::QueryId,
+    result: kad::QueryResult,
+    step: kad::ProgressStep,
+) {
+    if let kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+        peer: Some(peer),
+        record,
+    }))) = &result
+    {
+        if !reputation::is_valid_record(record) {
+            state
+                .reputation
+                .penalize(swarm.behaviour_mut(), *peer, Offence::InvalidRecord);
+        }
+    }
+    if state.ownership.on_query_progressed(id, &result) {
+        return;
+    }
+    let kademlia = &mut swarm.behaviour_mut().kademlia;
+    if state
+        .gateway
+        .on_query_progressed(kademlia, id, &result, &step)
+        || state
+            .crdt
+            .on_query_progressed(kademlia, &mut state.ownership, id, &result, &step)
+    {
+        return;
+    }
+    if state
+        .leases
+        .on_query_progressed(kademlia, id, &result, &step)
+    {
+        print_lease_events(&mut state.leases);
+        return;
+    }
+    match result {
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+            key,
+            providers,
+        })) => {
+            for peer in providers {
+                outln!("Found provider {} for key {}", peer, printable(&key));
+            }
+        }
+        kad::QueryResult::GetProviders(Ok(
+            kad::GetProvidersOk::FinishedWithNoAdditionalRecord { closest_peers },
+        )) => {
+            for peer in closest_peers {
+                outln!("Found closest_peers: ({}) ", peer);
+            }
+        }
+        kad::QueryResult::GetProviders(Err(e)) => {
+            errln!("GetProviders error: {:?}", e);
+        }
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+            record: kad::Record { key, value, .. },
+            ..
+        }))) => {
+            outln!(
+                "Found record {} for key {}",
+                printable(&value),
+                printable(&key)
+            );
+        }
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord {
+            cache_candidates,
+        })) => {
+            for (kb_distance, peer_id) in cache_candidates {
+                outln!(
+                    "Found cache_candidate: ({}) with kb_distance: ({:?})",
+                    peer_id,
+                    kb_distance.ilog2()
+                );
+            }
+        }
+        kad::QueryResult::GetRecord(Err(e)) => {
+            errln!("GetRecord error: {:?}", e);
+        }
+        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+            outln!("Put record {}", printable(&key));
+        }
+        kad::QueryResult::PutRecord(Err(e)) => {
+            errln!("Put record error: {:?}", e);
+        }
+        kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
+            outln!("Started providing {}", printable(&key));
+        }
+        kad::QueryResult::StartProviding(Err(e)) => {
+            errln!("StartProviding error: {:?}", e);
+        }
+        _ => {
+            outln!("None handler for event");
+        }
+    }
+}
+
+/// Events of the keyspaces' Kademlia instances; results are printed with
+/// the keyspace in front.
+fn handle_keyspace_event(
+    swarm: &mut Swarm<Behavior>,
+    KeyspaceEvent { keyspace, event }: KeyspaceEvent,
+    state: &mut NodeState,
+) {
+    match event {
+        kad::Event::InboundRequest {
+            request: kad::InboundRequest::PutRecord { source, .. },
+        } => {
+            state
+                .reputation
+                .on_inbound_write(swarm.behaviour_mut(), source);
+        }
+        kad::Event::OutboundQueryProgressed { result, .. } => match result {
+            kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+                record: kad::Record { key, value, .. },
+                ..
+            }))) => {
+                outln!(
+                    "[{}] Found record {} for key {}",
+                    keyspace,
+                    printable(&value),
+                    printable(&key)
+                );
+            }
+            kad::QueryResult::GetRecord(Err(e)) => {
+                errln!("[{}] GetRecord error: {:?}", keyspace, e)
+            }
+            kad::QueryResult::PutRecord(Ok(kad::PutRecordOk { key })) => {
+                outln!("[{}] Put record {}", keyspace, printable(&key));
+            }
+            kad::QueryResult::PutRecord(Err(e)) => {
+                errln!("[{}] Put record error: {:?}", keyspace, e)
+            }
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                key,
+                providers,
+            })) => {
+                for peer in providers {
+                    outln!(
+                        "[{}] Found provider {} for key {}",
+                        keyspace,
+                        peer,
+                        printable(&key)
+                    );
+                }
+            }
+            kad::QueryResult::GetProviders(Err(e)) => {
+                errln!("[{}] GetProviders error: {:?}", keyspace, e);
+            }
+            kad::QueryResult::StartProviding(Ok(kad::AddProviderOk { key })) => {
+                outln!("[{}] Started providing {}", keyspace, printable(&key));
+            }
+            kad::QueryResult::StartProviding(Err(e)) => {
+                errln!("[{}] StartProviding error: {:?}", keyspace, e);
+            }
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// Stops the node: optionally hands local records off to the closest known
+/// peers, writes the final snapshot and closes all connections.
+async fn shutdown(
+    swarm: &mut Swarm<Behavior>,
+    state: &mut NodeState,
+    handoff: bool,
+    export: Option<&Path>,
+    timeout: Duration,
+) {
+    outln!("Shutting down...");
+    let deadline = tokio::time::sleep(timeout);
+    tokio::pin!(deadline);
+
+    if handoff {
+        let mut queries = handoff_records(&mut swarm.behaviour_mut().kademlia);
+        outln!("Handing off {} records to closest peers", queries.len());
+        while !queries.is_empty() {
+            select! {
+                _ = &mut deadline => {
+                    errln!("Hand-off timed out, {} records pending", queries.len());
+                    break;
+                }
+                event = swarm.select_next_some() => match event {
+                    SwarmEvent::Behaviour(BehaviorEvent::Kademlia(kad::Event::OutboundQueryProgressed {
+                        id,
+                        result: kad::QueryResult::PutRecord(result),
+                        step,
+                        ..
+                    })) if queries.contains(&id) => {
+                        if let Err(e) = result {
+                            errln!("Hand-off error: {:?}", e);
+                        }
+                        if step.last {
+                            queries.remove(&id);
                         }
-                        // kad::QueryResult::Bootstrap(bootstrap_ok) => todo!(),
-                        // kad::QueryResult::GetClosestPeers(get_closest_peers_ok) => todo!(),
-                        // kad::QueryResult::RepublishProvider(add_provider_ok) => todo!(),
-                        // kad::QueryResult::RepublishRecord(put_record_ok) => todo!()
                     }
-                },
-                _=>{
-                    // println!("None handler for event: {:30?}",event);
+                    event => handle_swarm_event(swarm, event, state),
                 }
             }
         }
-        
     }
 
-    Ok(())
+    // 持久化：退出前写出最后一次快照
+    if let Some(path) = export {
+        export_snapshot(swarm.behaviour_mut().kademlia.store_mut(), path);
+    }
+
+    let peers: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    while swarm.network_info().num_peers() > 0 {
+        select! {
+            _ = &mut deadline => break,
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::ConnectionClosed { peer_id, .. } = event {
+                    outln!("Disconnected from {}", peer_id);
+                }
+            }
+        }
+    }
+    outln!("Bye");
 }
 
-const METHODS: [&'static str; 4] = ["GET", "PUT", "GET_PROVIDERS", "PUT_PROVIDER"];
-fn handle_input_line(kademlia: &mut kad::Behaviour<MemoryStore>, line: String) {
+/// Pushes every locally stored record to the closest peers in the routing
+/// table, keeping the original publisher, and returns the started queries.
+fn handoff_records(kademlia: &mut kad::Behaviour<DkvStore>) -> HashSet<kad::QueryId> {
+    let records: Vec<kad::Record> = kademlia
+        .store_mut()
+        .records()
+        .map(|r| r.into_owned())
+        .collect();
+    let mut queries = HashSet::new();
+    for record in records {
+        let target = kad::KBucketKey::new(record.key.clone());
+        let peers: Vec<PeerId> = kademlia
+            .get_closest_local_peers(&target)
+            .take(kad::K_VALUE.get())
+            .map(|key| key.into_preimage())
+            .collect();
+        if peers.is_empty() {
+            continue;
+        }
+        queries.insert(kademlia.put_record_to(record, peers.into_iter(), kad::Quorum::One));
+    }
+    queries
+}
+
+/// What the main loop should do after a command.
+enum Flow {
+    Continue,
+    Quit { handoff: bool },
+}
+
+const METHODS: [&str; 24] = [
+    "GET",
+    "PUT",
+    "GET_PROVIDERS",
+    "PUT_PROVIDER",
+    "INCR",
+    "DECR",
+    "GINCR",
+    "SADD",
+    "SREM",
+    "SMEMBERS",
+    "LWWSET",
+    "LOCK",
+    "RENEW",
+    "UNLOCK",
+    "PEER",
+    "BAN",
+    "UNBAN",
+    "BANS",
+    "STATUS",
+    "IN",
+    "KEYSPACES",
+    "EXPORT",
+    "IMPORT",
+    "QUIT",
+];
+fn handle_input_line(behaviour: &mut Behavior, state: &mut NodeState, line: String) -> Flow {
+    let kademlia = &mut behaviour.kademlia;
     let mut args = line.split_ascii_whitespace();
 
     match args.next() {
         Some("GET") => {
             let key = {
                 match args.next() {
-                    Some(key) => kad::RecordKey::new(&key),
+                    Some(key) => state.keys.record_key(key),
                     None => {
-                        eprint!("Expected a key");
-                        return;
+                        errln!("Expected a key");
+                        return Flow::Continue;
                     }
                 }
             };
-            kademlia.get_record(key);
+            // CRDT 值需要合并所有副本，普通值照常输出
+            state.crdt.read(kademlia, key, None);
         }
         Some("GET_PROVIDERS") => {
             let key = {
                 match args.next() {
-                    Some(key) => kad::RecordKey::new(&key), // <details: What? When? Why?>
+                    Some(key) => state.keys.record_key(key),
                     None => {
-                        eprint!("Expected a key");
-                        return;
+                        errln!("Expected a key");
+                        return Flow::Continue;
                     }
                 }
             };
@@ -185,48 +952,316 @@ fn handle_input_line(kademlia: &mut kad::Behaviour<MemoryStore>, line: String) {
         Some("PUT") => {
             let key = {
                 match args.next() {
-                    Some(key) => kad::RecordKey::new(&key),
+                    Some(key) => state.keys.record_key(key),
                     None => {
-                        eprint!("missing key");
-                        return;
+                        errln!("missing key");
+                        return Flow::Continue;
                     }
                 }
             };
-            let value:Vec<u8> ={
+            let value: Vec<u8> = {
                 match args.next() {
-                    Some(v)=> v.as_bytes().to_vec(), // <details: What? When? Why?>
-                    None=>{
-                        eprint!("Expected value");
-                        return;
+                    Some(v) => v.as_bytes().to_vec(),
+                    None => {
+                        errln!("Expected value");
+                        return Flow::Continue;
                     }
                 }
             };
-            let record = kad::Record{
+            let record = kad::Record {
                 key,
                 value,
-                publisher:None,
-                expires:None
+                publisher: None,
+                expires: None,
             };
-            kademlia.put_record(record, kad::Quorum::One) // <details: What? When? Why?>
-                    .expect("Failed to put record");
+            // 本地副本持有有效租约时直接拒绝，而不是让存储报一个含糊的错误
+            if !kademlia.store_mut().admits(&record) {
+                errln!("Key is held by a live lease, UNLOCK it or wait for it to expire");
+                return Flow::Continue;
+            }
+            if let Err(e) = state
+                .ownership
+                .put_record(kademlia, record, kad::Quorum::One)
+            {
+                errln!("Put record error: {:?}", e);
+            }
         }
         Some("PUT_PROVIDER") => {
             let key = {
                 match args.next() {
-                    Some(key) => kad::RecordKey::new(&key),
+                    Some(key) => state.keys.record_key(key),
                     None => {
-                        eprint!("missing key");
-                        return;
+                        errln!("missing key");
+                        return Flow::Continue;
                     }
                 }
             };
 
-            kademlia.start_providing(key) // <details: What? When? Why?>
-                    .expect("Failed to start providing");
+            if let Err(e) = kademlia.start_providing(key) {
+                errln!("StartProviding error: {:?}", e);
+            }
+        }
+        Some(command @ ("INCR" | "DECR" | "GINCR" | "SADD" | "SREM" | "LWWSET")) => {
+            let Some(key) = args.next() else {
+                errln!("Expected a key");
+                return Flow::Continue;
+            };
+            match crdt::parse_op(command, args) {
+                Ok(op) => state
+                    .crdt
+                    .read(kademlia, state.keys.record_key(key), Some(op)),
+                Err(e) => errln!("{}", e),
+            }
+        }
+        Some("SMEMBERS") => {
+            let Some(key) = args.next() else {
+                errln!("Expected a key");
+                return Flow::Continue;
+            };
+            state.crdt.read(kademlia, state.keys.record_key(key), None);
+        }
+        Some(command @ ("LOCK" | "RENEW")) => {
+            // LOCK <name> <ttl seconds> / RENEW <name> <ttl seconds>
+            let (Some(name), Some(ttl)) = (args.next(), args.next()) else {
+                errln!("Expected a name and a ttl in seconds");
+                return Flow::Continue;
+            };
+            let Ok(ttl) = ttl.parse::<u64>().map(Duration::from_secs) else {
+                errln!("Invalid ttl {}", ttl);
+                return Flow::Continue;
+            };
+            if command == "LOCK" {
+                state.leases.lock(kademlia, name, ttl);
+            } else {
+                state.leases.renew(kademlia, name, ttl);
+            }
+            print_lease_events(&mut state.leases);
+        }
+        Some("UNLOCK") => {
+            let Some(name) = args.next() else {
+                errln!("Expected a name");
+                return Flow::Continue;
+            };
+            state.leases.unlock(kademlia, name);
+            print_lease_events(&mut state.leases);
+        }
+        Some("PEER") => {
+            let Some(peer) = args.next() else {
+                errln!("Expected a peer id");
+                return Flow::Continue;
+            };
+            let Ok(peer) = peer.parse::<PeerId>() else {
+                errln!("Invalid peer id {}", peer);
+                return Flow::Continue;
+            };
+            match state.peers.get(&peer) {
+                Some(info) => outln!("Peer {}\n{}", peer, info),
+                None => errln!("Never connected to {}", peer),
+            }
+        }
+        Some("BAN") => {
+            // BAN <peer id> [seconds] [reason...]
+            let Some(peer) = args.next().and_then(|p| p.parse::<PeerId>().ok()) else {
+                errln!("Expected a peer id");
+                return Flow::Continue;
+            };
+            let mut rest = args.peekable();
+            let duration = match rest.peek().map(|s| s.parse::<u64>()) {
+                Some(Ok(secs)) => {
+                    rest.next();
+                    Some(Duration::from_secs(secs))
+                }
+                _ => None,
+            };
+            let reason = rest.collect::<Vec<_>>().join(" ");
+            let reason = if reason.is_empty() {
+                "banned by hand".to_string()
+            } else {
+                reason
+            };
+            state.reputation.ban(behaviour, peer, duration, reason);
+        }
+        Some("UNBAN") => {
+            let Some(peer) = args.next().and_then(|p| p.parse::<PeerId>().ok()) else {
+                errln!("Expected a peer id");
+                return Flow::Continue;
+            };
+            if state.reputation.unban(behaviour, peer) {
+                outln!("Unbanned {}", peer);
+            } else {
+                errln!("{} is not banned", peer);
+            }
+        }
+        Some("BANS") => {
+            let mut bans: Vec<_> = state.reputation.bans().collect();
+            bans.sort_by_key(|ban| ban.peer);
+            outln!("{} banned peers", bans.len());
+            for ban in bans {
+                outln!("  {}", ban);
+            }
+            for (peer, score) in state.reputation.suspects() {
+                outln!("  {} scored {}", peer, score);
+            }
+        }
+        Some("STATUS") => {
+            let status = state.ownership.status();
+            outln!("{} owned keys", status.len());
+            for owned in status {
+                let kind = if owned.provider { "provider" } else { "record" };
+                let confirmed = match owned.confirmed {
+                    Some(at) => format!("confirmed {}s ago", at.elapsed().as_secs()),
+                    None => "never confirmed".to_string(),
+                };
+                let error = owned
+                    .last_error
+                    .map(|e| format!(", last attempt: {}", e))
+                    .unwrap_or_default();
+                outln!(
+                    "  {} {}: {}{}, written {}s ago, next in {}s",
+                    kind,
+                    printable(&owned.key),
+                    confirmed,
+                    error,
+                    owned.published.elapsed().as_secs(),
+                    owned
+                        .next
+                        .saturating_duration_since(std::time::Instant::now())
+                        .as_secs()
+                );
+            }
+        }
+        Some("IN") => {
+            // IN <keyspace> GET|PUT|GET_PROVIDERS|PUT_PROVIDER <key> [value]
+            let Some(name) = args.next() else {
+                errln!("Expected a keyspace");
+                return Flow::Continue;
+            };
+            let Some(kademlia) = behaviour.keyspaces.get_mut(name) else {
+                errln!("Not in keyspace {}", name);
+                return Flow::Continue;
+            };
+            let keys = state.keys.with_namespace(name);
+            match (args.next(), args.next()) {
+                (Some("GET"), Some(key)) => {
+                    kademlia.get_record(keys.record_key(key));
+                }
+                (Some("PUT"), Some(key)) => {
+                    let Some(value) = args.next() else {
+                        errln!("Expected value");
+                        return Flow::Continue;
+                    };
+                    let record = kad::Record::new(keys.record_key(key), value.as_bytes().to_vec());
+                    if !kademlia.store_mut().admits(&record) {
+                        errln!("[{}] Key is held by a live lease", name);
+                        return Flow::Continue;
+                    }
+                    if let Err(e) = kademlia.put_record(record, kad::Quorum::One) {
+                        errln!("[{}] Put record error: {:?}", name, e);
+                    }
+                }
+                (Some("GET_PROVIDERS"), Some(key)) => {
+                    kademlia.get_providers(keys.record_key(key));
+                }
+                (Some("PUT_PROVIDER"), Some(key)) => {
+                    if let Err(e) = kademlia.start_providing(keys.record_key(key)) {
+                        errln!("[{}] StartProviding error: {:?}", name, e);
+                    }
+                }
+                _ => errln!("Expected GET, PUT, GET_PROVIDERS or PUT_PROVIDER and a key"),
+            }
+        }
+        Some("KEYSPACES") => {
+            outln!("{} keyspaces", behaviour.keyspaces.iter().count());
+            for (name, kademlia) in behaviour.keyspaces.iter_mut() {
+                let peers: usize = kademlia.kbuckets().map(|b| b.num_entries()).sum();
+                let records = kademlia.store_mut().records().count();
+                outln!("  {}: {} peers, {} records", name, peers, records);
+            }
+        }
+        Some("EXPORT") => {
+            let Some(path) = args.next() else {
+                errln!("Expected a path");
+                return Flow::Continue;
+            };
+            export_snapshot(kademlia.store_mut(), path.as_ref());
+        }
+        Some("IMPORT") => {
+            let Some(path) = args.next() else {
+                errln!("Expected a path");
+                return Flow::Continue;
+            };
+            // IMPORT <path> [REPUBLISH]
+            let republish = matches!(args.next(), Some("REPUBLISH"));
+            let snapshot = match Snapshot::read_from(path.as_ref()) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    errln!("Import error: {:?}", e);
+                    return Flow::Continue;
+                }
+            };
+            let import = if republish {
+                snapshot.republish(kademlia, &mut state.ownership)
+            } else {
+                snapshot.restore(kademlia.store_mut())
+            };
+            report_import(&import, path);
+        }
+        Some("QUIT") => {
+            // QUIT [HANDOFF]
+            let handoff = matches!(args.next(), Some("HANDOFF"));
+            return Flow::Quit { handoff };
         }
         _ => {
-            eprintln!("unknown command");
-            eprintln!("Expecting one of: {:?}",METHODS);
+            errln!("unknown command");
+            errln!("Expecting one of: {:?}", METHODS);
         }
     }
+    Flow::Continue
+}
+
+fn print_lease_events(leases: &mut LeaseManager) {
+    for event in leases.drain_events() {
+        outln!("{}", event);
+    }
+}
+
+/// Skipped entries listed one by one at most, the rest are only counted
+const MAX_LISTED_SKIPPED: usize = 10;
+
+fn report_import(import: &Import, source: &str) {
+    outln!(
+        "Imported {} records and {} provider records from {}",
+        import.records,
+        import.providers,
+        source
+    );
+    if import.skipped.is_empty() {
+        return;
+    }
+    errln!(
+        "Skipped {} entries the store refused:",
+        import.skipped.len()
+    );
+    for (key, e) in import.skipped.iter().take(MAX_LISTED_SKIPPED) {
+        errln!("  {}: {:?}", printable(key), e);
+    }
+    if import.skipped.len() > MAX_LISTED_SKIPPED {
+        errln!(
+            "  ... and {} more",
+            import.skipped.len() - MAX_LISTED_SKIPPED
+        );
+    }
+}
+
+fn export_snapshot(store: &DkvStore, path: &std::path::Path) {
+    let snapshot = Snapshot::capture(store);
+    match snapshot.write_to(path) {
+        Ok(()) => outln!(
+            "Exported {} records and {} provider records to {}",
+            snapshot.records.len(),
+            snapshot.providers.len(),
+            path.display()
+        ),
+        Err(e) => errln!("Export error: {:?}", e),
+    }
 }
@@ -0,0 +1,356 @@
+//! Records and provider records this node published, and their republication.
+//!
+//! Records authored on this node (`PUT`, CRDT updates, the HTTP gateway,
+//! imported snapshots being republished) are owned by it: they are written
+//! through [`Ownership::put_record`] and the local copy carries this node as
+//! publisher. Other writes, such as the hand-off on `QUIT HANDOFF` or CRDT
+//! replicas repaired by a read, do not make a key owned. [`Ownership`]
+//! republishes owned records every interval with the node's quorum and a
+//! fresh TTL, so they survive replica holders leaving. Kademlia's own publication job is
+//! switched off for that (see `BehaviourOptions::republish_interval`); it
+//! would insist on `Quorum::All` and keep the original expiry.
+//!
+//! Leases are not republished; their holders renew them explicitly.
+//!
+//! Provider records are re-announced by Kademlia itself at the configured
+//! interval; their results are only recorded here.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use libp2p::kad::{self, store::RecordStore};
+
+use crate::{errln, lease, store::DkvStore};
+
+/// Republish queries started per call to [`Ownership::republish_due`]
+const MAX_REPUBLISH_PER_ROUND: usize = 32;
+
+#[derive(Debug)]
+struct Owned {
+    /// Last time the key was written
+    published: Instant,
+    /// Last time a write of the key reached its quorum
+    confirmed: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Owned {
+    fn new() -> Self {
+        Self {
+            published: Instant::now(),
+            confirmed: None,
+            last_error: None,
+        }
+    }
+}
+
+/// One line of the `STATUS` output.
+#[derive(Debug)]
+pub struct OwnedStatus {
+    pub key: kad::RecordKey,
+    pub provider: bool,
+    pub published: Instant,
+    pub confirmed: Option<Instant>,
+    pub last_error: Option<String>,
+    pub next: Instant,
+}
+
+/// Ownership table of this node.
+pub struct Ownership {
+    records: HashMap<kad::RecordKey, Owned>,
+    providers: HashMap<kad::RecordKey, Owned>,
+    republish_interval: Duration,
+    reprovide_interval: Duration,
+    quorum: kad::Quorum,
+    /// Republish queries started here, answered silently
+    queries: HashMap<kad::QueryId, kad::RecordKey>,
+    /// Writes started through [`Ownership::put_record`], answered by their
+    /// caller
+    writes: HashSet<kad::QueryId>,
+}
+
+impl Ownership {
+    pub fn new(
+        republish_interval: Duration,
+        reprovide_interval: Duration,
+        quorum: kad::Quorum,
+    ) -> Self {
+        Self {
+            records: HashMap::new(),
+            providers: HashMap::new(),
+            republish_interval,
+            reprovide_interval,
+            quorum,
+            queries: HashMap::new(),
+            writes: HashSet::new(),
+        }
+    }
+
+    /// How often [`Ownership::republish_due`] should run.
+    pub fn check_interval(&self) -> Duration {
+        (self.republish_interval / 4).clamp(Duration::from_secs(1), Duration::from_secs(60))
+    }
+
+    /// Puts a record authored on this node, which makes its key owned once
+    /// the write completes.
+    pub fn put_record(
+        &mut self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        record: kad::Record,
+        quorum: kad::Quorum,
+    ) -> kad::store::Result<kad::QueryId> {
+        let id = kademlia.put_record(record, quorum)?;
+        self.writes.insert(id);
+        Ok(id)
+    }
+
+    /// Records the outcome of writes of owned records and of provider
+    /// records; returns `true` if the query was a republication started here.
+    pub fn on_query_progressed(&mut self, id: kad::QueryId, result: &kad::QueryResult) -> bool {
+        match result {
+            kad::QueryResult::PutRecord(result) => {
+                let key = match result {
+                    Ok(kad::PutRecordOk { key }) => key,
+                    Err(e) => e.key(),
+                };
+                let owned = self.writes.remove(&id) || self.queries.contains_key(&id);
+                if owned && !lease::is_key(key) {
+                    let owned = self.records.entry(key.clone()).or_insert_with(Owned::new);
+                    owned.published = Instant::now();
+                    match result {
+                        Ok(_) => {
+                            owned.confirmed = Some(Instant::now());
+                            owned.last_error = None;
+                        }
+                        Err(e) => owned.last_error = Some(put_error(e)),
+                    }
+                }
+            }
+            kad::QueryResult::StartProviding(result)
+            | kad::QueryResult::RepublishProvider(result) => {
+                let key = match result {
+                    Ok(kad::AddProviderOk { key }) => key,
+                    Err(e) => e.key(),
+                };
+                let owned = self.providers.entry(key.clone()).or_insert_with(Owned::new);
+                owned.published = Instant::now();
+                match result {
+                    Ok(_) => {
+                        owned.confirmed = Some(Instant::now());
+                        owned.last_error = None;
+                    }
+                    Err(e) => owned.last_error = Some(format!("{:?}", e)),
+                }
+            }
+            _ => {}
+        }
+        self.queries.remove(&id).is_some()
+    }
+
+    /// Starts republishing the owned records whose interval has passed.
+    pub fn republish_due(&mut self, kademlia: &mut kad::Behaviour<DkvStore>) {
+        let local = kademlia.store_mut().local_peer_id();
+        let due: Vec<kad::RecordKey> = self
+            .records
+            .iter()
+            .filter(|(key, owned)| {
+                owned.published.elapsed() >= self.republish_interval
+                    && !self.queries.values().any(|k| k == *key)
+            })
+            .map(|(key, _)| key.clone())
+            .take(MAX_REPUBLISH_PER_ROUND)
+            .collect();
+        for key in due {
+            let record = kademlia
+                .store_mut()
+                .get(&key)
+                .map(|r| r.into_owned())
+                .filter(|r| r.publisher == Some(local));
+            // 记录已删除、过期，或已被其他节点覆盖时不再归本节点所有
+            let Some(mut record) = record else {
+                self.records.remove(&key);
+                continue;
+            };
+            // 清除过期时间，让 Kademlia 为副本重新计算 TTL
+            record.expires = None;
+            let owned = self.records.get_mut(&key).expect("key is due");
+            owned.published = Instant::now();
+            match kademlia.put_record(record, self.quorum) {
+                Ok(id) => {
+                    self.queries.insert(id, key);
+                }
+                Err(e) => {
+                    errln!("Failed to republish {:?}: {:?}", key, e);
+                    owned.last_error = Some(format!("{:?}", e));
+                }
+            }
+        }
+    }
+
+    /// Owned records and provider records, sorted by key.
+    pub fn status(&self) -> Vec<OwnedStatus> {
+        let records = self
+            .records
+            .iter()
+            .map(|(key, owned)| (key, owned, false, self.republish_interval));
+        let providers = self
+            .providers
+            .iter()
+            .map(|(key, owned)| (key, owned, true, self.reprovide_interval));
+        let mut status: Vec<OwnedStatus> = records
+            .chain(providers)
+            .map(|(key, owned, provider, interval)| OwnedStatus {
+                key: key.clone(),
+                provider,
+                published: owned.published,
+                confirmed: owned.confirmed,
+                last_error: owned.last_error.clone(),
+                next: owned.published + interval,
+            })
+            .collect();
+        status.sort_by(|a, b| (a.key.as_ref(), a.provider).cmp(&(b.key.as_ref(), b.provider)));
+        status
+    }
+}
+
+fn put_error(e: &kad::PutRecordError) -> String {
+    match e {
+        kad::PutRecordError::QuorumFailed {
+            success, quorum, ..
+        } => format!("quorum failed ({}/{})", success.len(), quorum),
+        kad::PutRecordError::Timeout {
+            success, quorum, ..
+        } => format!("timed out ({}/{})", success.len(), quorum),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use libp2p::PeerId;
+
+    use super::*;
+    use crate::store::StoreLimits;
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn kademlia() -> kad::Behaviour<DkvStore> {
+        let local = PeerId::random();
+        kad::Behaviour::new(local, DkvStore::new(local, StoreLimits::default()))
+    }
+
+    fn record(key: &str) -> kad::Record {
+        kad::Record::new(kad::RecordKey::new(&key), b"value".to_vec())
+    }
+
+    fn put_ok(key: &str) -> kad::QueryResult {
+        kad::QueryResult::PutRecord(Ok(kad::PutRecordOk {
+            key: kad::RecordKey::new(&key),
+        }))
+    }
+
+    fn owned_keys(ownership: &Ownership) -> Vec<Vec<u8>> {
+        ownership
+            .status()
+            .into_iter()
+            .map(|s| s.key.to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn only_writes_authored_here_are_owned() {
+        let mut kademlia = kademlia();
+        let mut ownership = Ownership::new(HOUR, HOUR, kad::Quorum::One);
+        let mine = ownership
+            .put_record(&mut kademlia, record("mine"), kad::Quorum::One)
+            .unwrap();
+        // 交接和修复副本的写入不经过 Ownership
+        let handoff = kademlia
+            .put_record(record("handoff"), kad::Quorum::One)
+            .unwrap();
+        let lease = ownership
+            .put_record(&mut kademlia, record("lock/job"), kad::Quorum::One)
+            .unwrap();
+
+        assert!(!ownership.on_query_progressed(mine, &put_ok("mine")));
+        assert!(!ownership.on_query_progressed(handoff, &put_ok("handoff")));
+        assert!(!ownership.on_query_progressed(lease, &put_ok("lock/job")));
+        assert_eq!(owned_keys(&ownership), vec![b"mine".to_vec()]);
+        let status = &ownership.status()[0];
+        assert!(status.confirmed.is_some());
+        assert_eq!(status.next, status.published + HOUR);
+    }
+
+    #[test]
+    fn failed_writes_are_owned_with_their_error() {
+        let mut kademlia = kademlia();
+        let mut ownership = Ownership::new(HOUR, HOUR, kad::Quorum::One);
+        let id = ownership
+            .put_record(&mut kademlia, record("mine"), kad::Quorum::One)
+            .unwrap();
+        let failed = kad::QueryResult::PutRecord(Err(kad::PutRecordError::QuorumFailed {
+            key: kad::RecordKey::new(&"mine"),
+            success: Vec::new(),
+            quorum: NonZeroUsize::new(3).unwrap(),
+        }));
+        ownership.on_query_progressed(id, &failed);
+
+        let status = &ownership.status()[0];
+        assert_eq!(status.confirmed, None);
+        assert_eq!(status.last_error.as_deref(), Some("quorum failed (0/3)"));
+    }
+
+    #[test]
+    fn owned_records_are_republished_once_due() {
+        let mut kademlia = kademlia();
+        let mut ownership = Ownership::new(HOUR, HOUR, kad::Quorum::One);
+        let id = ownership
+            .put_record(&mut kademlia, record("mine"), kad::Quorum::One)
+            .unwrap();
+        ownership.on_query_progressed(id, &put_ok("mine"));
+        ownership.republish_due(&mut kademlia);
+        assert!(ownership.queries.is_empty());
+
+        let mut ownership = Ownership::new(Duration::ZERO, HOUR, kad::Quorum::One);
+        assert_eq!(ownership.check_interval(), Duration::from_secs(1));
+        let id = ownership
+            .put_record(&mut kademlia, record("mine"), kad::Quorum::One)
+            .unwrap();
+        ownership.on_query_progressed(id, &put_ok("mine"));
+        ownership.republish_due(&mut kademlia);
+        assert_eq!(ownership.queries.len(), 1);
+        // 上一次重新发布还没有结果时不重复发起
+        ownership.republish_due(&mut kademlia);
+        assert_eq!(ownership.queries.len(), 1);
+
+        let republished = *ownership.queries.keys().next().unwrap();
+        assert!(ownership.on_query_progressed(republished, &put_ok("mine")));
+        assert!(ownership.queries.is_empty());
+        assert_eq!(owned_keys(&ownership), vec![b"mine".to_vec()]);
+    }
+
+    #[test]
+    fn records_overwritten_or_gone_are_no_longer_owned() {
+        let mut kademlia = kademlia();
+        let mut ownership = Ownership::new(Duration::ZERO, HOUR, kad::Quorum::One);
+        for key in ["overwritten", "removed"] {
+            let id = ownership
+                .put_record(&mut kademlia, record(key), kad::Quorum::One)
+                .unwrap();
+            ownership.on_query_progressed(id, &put_ok(key));
+        }
+        let mut foreign = record("overwritten");
+        foreign.publisher = Some(PeerId::random());
+        kademlia.store_mut().put(foreign).unwrap();
+        kademlia
+            .store_mut()
+            .remove(&kad::RecordKey::new(&"removed"));
+
+        ownership.republish_due(&mut kademlia);
+        assert!(ownership.queries.is_empty());
+        assert!(owned_keys(&ownership).is_empty());
+    }
+}
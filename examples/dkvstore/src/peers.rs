@@ -0,0 +1,101 @@
+//! What a node has learned about other peers through identify and ping.
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use libp2p::{identify, Multiaddr, PeerId, StreamProtocol};
+
+/// Information about one peer, kept after it disconnects.
+#[derive(Debug, Default)]
+pub struct PeerInfo {
+    pub agent_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub protocols: Vec<StreamProtocol>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Our own address as the peer sees it
+    pub observed_addr: Option<Multiaddr>,
+    /// Round trip time of the last successful ping
+    pub rtt: Option<Duration>,
+    /// Open connections to the peer
+    pub connections: u32,
+}
+
+impl fmt::Display for PeerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = || "unknown".to_string();
+        writeln!(f, "  connections: {}", self.connections)?;
+        writeln!(
+            f,
+            "  agent: {}",
+            self.agent_version.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "  protocol version: {}",
+            self.protocol_version.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "  rtt: {}",
+            self.rtt
+                .map(|rtt| format!("{:?}", rtt))
+                .unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "  observed address: {}",
+            self.observed_addr
+                .as_ref()
+                .map(|a| a.to_string())
+                .unwrap_or_else(unknown)
+        )?;
+        writeln!(f, "  listen addresses:")?;
+        for addr in &self.listen_addrs {
+            writeln!(f, "    {}", addr)?;
+        }
+        write!(f, "  protocols:")?;
+        for protocol in &self.protocols {
+            write!(f, "\n    {}", protocol)?;
+        }
+        Ok(())
+    }
+}
+
+/// Peers seen since the node started.
+#[derive(Debug, Default)]
+pub struct PeerBook {
+    peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerBook {
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerInfo> {
+        self.peers.get(peer)
+    }
+
+    pub fn on_connection_established(&mut self, peer: PeerId) {
+        self.peers.entry(peer).or_default().connections += 1;
+    }
+
+    /// `remaining` is the number of connections still open to the peer.
+    pub fn on_connection_closed(&mut self, peer: PeerId, remaining: u32) {
+        if let Some(info) = self.peers.get_mut(&peer) {
+            info.connections = remaining;
+            if remaining == 0 {
+                // 断开后旧的 RTT 不再有参考价值
+                info.rtt = None;
+            }
+        }
+    }
+
+    pub fn on_identify(&mut self, peer: PeerId, info: &identify::Info) {
+        let entry = self.peers.entry(peer).or_default();
+        entry.agent_version = Some(info.agent_version.clone());
+        entry.protocol_version = Some(info.protocol_version.clone());
+        entry.protocols = info.protocols.clone();
+        entry.listen_addrs = info.listen_addrs.clone();
+        entry.observed_addr = Some(info.observed_addr.clone());
+    }
+
+    pub fn on_ping(&mut self, peer: PeerId, rtt: Duration) {
+        self.peers.entry(peer).or_default().rtt = Some(rtt);
+    }
+}
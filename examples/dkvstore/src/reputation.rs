@@ -0,0 +1,431 @@
+//! Peer scoring and the ban list.
+//!
+//! Every peer starts at a score of zero and loses points when it misbehaves:
+//!
+//! - 2 for a failed request, e.g. a ping that timed out
+//! - 20 for a protocol error, e.g. answering a dial under another identity
+//! - 25 for an invalid record: a CRDT value or lease that does not decode, or
+//!   a record that had already expired
+//! - 30 for flooding: more than [`FLOOD_WRITES`] inbound writes or
+//!   [`FLOOD_CONNECTIONS`] new connections within one maintenance period
+//!
+//! Scores recover by [`RECOVERY`] points every [`MAINTENANCE_INTERVAL`], and
+//! peers back at zero that were never banned are forgotten. A peer that drops
+//! to [`BAN_SCORE`] is banned for [`TEMP_BAN`], doubled for every earlier
+//! automatic ban. Manual bans (`BAN`/`UNBAN`) last until lifted unless
+//! a duration is given.
+//!
+//! A ban blocks the peer in the allow/block list behaviour, which closes its
+//! connections and refuses new ones, and removes it from the routing tables.
+//! With a ban file the list survives restarts.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use libp2p::{kad, PeerId};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    behaviour::Behavior,
+    crdt::CrdtValue,
+    errln,
+    lease::{self, now_millis, Lease},
+    outln,
+};
+
+/// How often scores recover, flood counters reset and bans expire
+pub const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(10);
+pub const BAN_SCORE: i32 = -100;
+pub const RECOVERY: i32 = 5;
+pub const TEMP_BAN: Duration = Duration::from_secs(10 * 60);
+const MAX_TEMP_BAN: Duration = Duration::from_secs(24 * 60 * 60);
+pub const FLOOD_WRITES: u32 = 100;
+pub const FLOOD_CONNECTIONS: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    FailedRequest,
+    ProtocolError,
+    InvalidRecord,
+    Flooding,
+}
+
+impl Offence {
+    fn penalty(self) -> i32 {
+        match self {
+            Offence::FailedRequest => 2,
+            Offence::ProtocolError => 20,
+            Offence::InvalidRecord => 25,
+            Offence::Flooding => 30,
+        }
+    }
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Offence::FailedRequest => "failed request",
+            Offence::ProtocolError => "protocol error",
+            Offence::InvalidRecord => "invalid record",
+            Offence::Flooding => "flooding",
+        })
+    }
+}
+
+/// Whether a record returned by a peer is something an honest node would serve.
+pub fn is_valid_record(record: &kad::Record) -> bool {
+    !CrdtValue::is_malformed(&record.value)
+        && !Lease::is_malformed(&record.value)
+        && !record.is_expired(Instant::now())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    #[serde(with = "lease::peer_id_string")]
+    pub peer: PeerId,
+    /// Unix timestamp in milliseconds, `None` until lifted by hand
+    pub until: Option<u64>,
+    pub reason: String,
+}
+
+impl fmt::Display for Ban {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.until {
+            Some(until) => write!(
+                f,
+                "{} for another {}s: {}",
+                self.peer,
+                until.saturating_sub(now_millis()) / 1000,
+                self.reason
+            ),
+            None => write!(f, "{} until unbanned: {}", self.peer, self.reason),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerScore {
+    score: i32,
+    /// Automatic bans so far, to lengthen the next one
+    strikes: u32,
+    /// Inbound writes and new connections in the current period
+    writes: u32,
+    connections: u32,
+}
+
+/// Scores of the peers seen so far and the peers currently banned.
+#[derive(Debug, Default)]
+pub struct Reputation {
+    peers: HashMap<PeerId, PeerScore>,
+    bans: HashMap<PeerId, Ban>,
+    path: Option<PathBuf>,
+}
+
+impl Reputation {
+    /// Loads the ban list from `path` if it exists and blocks the banned peers.
+    pub fn open(path: Option<PathBuf>, behaviour: &mut Behavior) -> anyhow::Result<Self> {
+        let mut reputation = Self {
+            path,
+            ..Default::default()
+        };
+        if let Some(path) = reputation.path.as_deref().filter(|p| p.exists()) {
+            let bans: Vec<Ban> = serde_json::from_slice(
+                &std::fs::read(path).with_context(|| format!("reading {}", path.display()))?,
+            )
+            .with_context(|| format!("{}: invalid ban list", path.display()))?;
+            let now = now_millis();
+            for ban in bans {
+                if ban.until.is_some_and(|until| until <= now) {
+                    continue;
+                }
+                block(behaviour, ban.peer);
+                reputation.bans.insert(ban.peer, ban);
+            }
+        }
+        Ok(reputation)
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = &Ban> {
+        self.bans.values()
+    }
+
+    /// Peers that lost points and are not banned, lowest score first.
+    pub fn suspects(&self) -> Vec<(PeerId, i32)> {
+        let mut suspects: Vec<(PeerId, i32)> = self
+            .peers
+            .iter()
+            .filter(|(peer, s)| s.score < 0 && !self.bans.contains_key(peer))
+            .map(|(peer, s)| (*peer, s.score))
+            .collect();
+        suspects.sort_by_key(|(_, score)| *score);
+        suspects
+    }
+
+    pub fn penalize(&mut self, behaviour: &mut Behavior, peer: PeerId, offence: Offence) {
+        if self.bans.contains_key(&peer) {
+            return;
+        }
+        let entry = self.peers.entry(peer).or_default();
+        entry.score -= offence.penalty();
+        tracing::debug!("{} scored {} for {}", peer, entry.score, offence);
+        if entry.score > BAN_SCORE {
+            return;
+        }
+        // 每次自动封禁时长翻倍，上限一天
+        let duration = TEMP_BAN
+            .saturating_mul(1 << entry.strikes.min(16))
+            .min(MAX_TEMP_BAN);
+        entry.strikes += 1;
+        let reason = format!("score {} after {}", entry.score, offence);
+        self.ban(behaviour, peer, Some(duration), reason);
+    }
+
+    pub fn on_inbound_write(&mut self, behaviour: &mut Behavior, peer: PeerId) {
+        let entry = self.peers.entry(peer).or_default();
+        entry.writes += 1;
+        if entry.writes == FLOOD_WRITES + 1 {
+            self.penalize(behaviour, peer, Offence::Flooding);
+        }
+    }
+
+    pub fn on_connection_established(&mut self, behaviour: &mut Behavior, peer: PeerId) {
+        let entry = self.peers.entry(peer).or_default();
+        entry.connections += 1;
+        if entry.connections == FLOOD_CONNECTIONS + 1 {
+            self.penalize(behaviour, peer, Offence::Flooding);
+        }
+    }
+
+    /// Bans `peer` for `duration`, or until unbanned if `None`.
+    pub fn ban(
+        &mut self,
+        behaviour: &mut Behavior,
+        peer: PeerId,
+        duration: Option<Duration>,
+        reason: String,
+    ) {
+        let ban = Ban {
+            peer,
+            until: duration.map(|d| {
+                now_millis().saturating_add(u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+            }),
+            reason,
+        };
+        outln!("Banned {}", ban);
+        block(behaviour, peer);
+        self.bans.insert(peer, ban);
+        self.save();
+    }
+
+    /// Lifts the ban on `peer` and forgives its score; `false` if it was not banned.
+    pub fn unban(&mut self, behaviour: &mut Behavior, peer: PeerId) -> bool {
+        if self.bans.remove(&peer).is_none() {
+            return false;
+        }
+        behaviour.blocked.unblock_peer(peer);
+        if let Some(entry) = self.peers.get_mut(&peer) {
+            entry.score = 0;
+        }
+        self.save();
+        true
+    }
+
+    /// Recovers scores, resets the flood counters, forgets peers in good
+    /// standing and lifts expired bans; call every [`MAINTENANCE_INTERVAL`].
+    pub fn maintain(&mut self, behaviour: &mut Behavior) {
+        for entry in self.peers.values_mut() {
+            entry.score = (entry.score + RECOVERY).min(0);
+            entry.writes = 0;
+            entry.connections = 0;
+        }
+        // 只保留仍在扣分或被封禁过的节点，其余的不必记住
+        self.peers
+            .retain(|_, entry| entry.score < 0 || entry.strikes > 0);
+        let now = now_millis();
+        let expired: Vec<PeerId> = self
+            .bans
+            .values()
+            .filter(|ban| ban.until.is_some_and(|until| until <= now))
+            .map(|ban| ban.peer)
+            .collect();
+        for peer in expired {
+            if self.unban(behaviour, peer) {
+                outln!("Ban on {} expired", peer);
+            }
+        }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let bans: Vec<&Ban> = self.bans.values().collect();
+        if let Err(e) = write_atomically(path, &serde_json::to_vec_pretty(&bans).unwrap()) {
+            errln!("Failed to save the ban list: {:?}", e);
+        }
+    }
+}
+
+fn block(behaviour: &mut Behavior, peer: PeerId) {
+    behaviour.blocked.block_peer(peer);
+    behaviour.kademlia.remove_peer(&peer);
+    behaviour.keyspaces.remove_peer(&peer);
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes).with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("renaming to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use libp2p::{identity::Keypair, relay};
+
+    use super::*;
+    use crate::{
+        behaviour::{BehaviourOptions, NodeMode},
+        store::StoreLimits,
+    };
+
+    fn behaviour() -> Behavior {
+        let key = Keypair::generate_ed25519();
+        let (_, relay_client) = relay::client::new(key.public().to_peer_id());
+        let options = BehaviourOptions {
+            mode: NodeMode::Server,
+            mdns: false,
+            relay_server: false,
+            republish_interval: None,
+            reprovide_interval: None,
+            store: StoreLimits::default(),
+        };
+        Behavior::new(&key, relay_client, options).unwrap()
+    }
+
+    fn temp_path() -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "dkv-bans-{}-{}.json",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    fn banned(reputation: &Reputation, peer: PeerId) -> Option<&Ban> {
+        reputation.bans().find(|ban| ban.peer == peer)
+    }
+
+    #[test]
+    fn penalties_add_up_to_a_ban() {
+        let mut behaviour = behaviour();
+        let mut reputation = Reputation::default();
+        let peer = PeerId::random();
+        for _ in 0..4 {
+            reputation.penalize(&mut behaviour, peer, Offence::ProtocolError);
+        }
+        assert_eq!(reputation.suspects(), vec![(peer, -80)]);
+        assert!(banned(&reputation, peer).is_none());
+
+        reputation.penalize(&mut behaviour, peer, Offence::ProtocolError);
+        let until = banned(&reputation, peer).unwrap().until.unwrap();
+        let expected = now_millis() + TEMP_BAN.as_millis() as u64;
+        assert!(until <= expected && until + 1000 > expected);
+        // 被封禁的节点不再出现在嫌疑名单里，也不再扣分
+        assert!(reputation.suspects().is_empty());
+        reputation.penalize(&mut behaviour, peer, Offence::Flooding);
+        assert_eq!(reputation.peers[&peer].score, -100);
+    }
+
+    #[test]
+    fn scores_recover_and_good_peers_are_forgotten() {
+        let mut behaviour = behaviour();
+        let mut reputation = Reputation::default();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        reputation.penalize(&mut behaviour, a, Offence::FailedRequest);
+        reputation.penalize(&mut behaviour, b, Offence::InvalidRecord);
+        reputation.on_connection_established(&mut behaviour, PeerId::random());
+
+        reputation.maintain(&mut behaviour);
+        assert_eq!(reputation.suspects(), vec![(b, -20)]);
+        assert_eq!(reputation.peers.len(), 1);
+        for _ in 0..4 {
+            reputation.maintain(&mut behaviour);
+        }
+        assert!(reputation.suspects().is_empty());
+        assert!(reputation.peers.is_empty());
+    }
+
+    #[test]
+    fn flooding_is_penalized_once_per_period() {
+        let mut behaviour = behaviour();
+        let mut reputation = Reputation::default();
+        let peer = PeerId::random();
+        for _ in 0..FLOOD_WRITES * 3 {
+            reputation.on_inbound_write(&mut behaviour, peer);
+        }
+        assert_eq!(reputation.suspects(), vec![(peer, -30)]);
+    }
+
+    #[test]
+    fn bans_expire_in_maintenance() {
+        let mut behaviour = behaviour();
+        let mut reputation = Reputation::default();
+        let (short, manual) = (PeerId::random(), PeerId::random());
+        reputation.ban(&mut behaviour, short, Some(Duration::ZERO), "test".into());
+        reputation.ban(&mut behaviour, manual, None, "test".into());
+        // BAN <peer> 18446744073709551615
+        let forever = PeerId::random();
+        reputation.ban(
+            &mut behaviour,
+            forever,
+            Some(Duration::from_secs(u64::MAX)),
+            "test".into(),
+        );
+        assert_eq!(banned(&reputation, forever).unwrap().until, Some(u64::MAX));
+
+        reputation.maintain(&mut behaviour);
+        assert!(banned(&reputation, short).is_none());
+        assert!(banned(&reputation, manual).is_some());
+        assert!(banned(&reputation, forever).is_some());
+        assert!(reputation.unban(&mut behaviour, manual));
+        assert!(!reputation.unban(&mut behaviour, manual));
+    }
+
+    #[test]
+    fn ban_file_round_trips() {
+        let path = temp_path();
+        let (manual, timed, expired) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut behaviour = behaviour();
+        let mut reputation = Reputation::open(Some(path.clone()), &mut behaviour).unwrap();
+        reputation.ban(&mut behaviour, manual, None, "by hand".into());
+        reputation.ban(
+            &mut behaviour,
+            timed,
+            Some(Duration::from_secs(60)),
+            "score".into(),
+        );
+        reputation.ban(&mut behaviour, expired, Some(Duration::ZERO), "gone".into());
+
+        let reopened = Reputation::open(Some(path.clone()), &mut behaviour).unwrap();
+        assert_eq!(banned(&reopened, manual).unwrap().reason, "by hand");
+        assert_eq!(banned(&reopened, manual).unwrap().until, None);
+        assert_eq!(
+            banned(&reopened, timed).unwrap().until,
+            banned(&reputation, timed).unwrap().until
+        );
+        // 文件里已过期的封禁在启动时丢弃
+        assert!(banned(&reopened, expired).is_none());
+
+        std::fs::write(&path, b"not json").unwrap();
+        assert!(Reputation::open(Some(path.clone()), &mut behaviour).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,384 @@
+//! Fault-injecting in-memory network for scenario tests.
+//!
+//! [`SimNetwork`] hands out one [`SimTransport`] per node. Node `i` listens on
+//! [`SimNetwork::addr`]`(i)` and every connection it makes or accepts goes
+//! through the faults currently configured:
+//!
+//! - latency: every write waits `latency` plus up to `jitter`, so a round trip
+//!   costs at least twice the latency
+//! - loss: libp2p streams are reliable, so loss is modelled at the connection
+//!   level; every dial and every write fails with probability `drop_rate`,
+//!   which resets the whole connection
+//! - partitions: nodes in different groups can neither dial each other nor
+//!   keep using connections opened before the split
+//!
+//! Crashes are simulated by dropping a node's swarm and restarts by building a
+//! new one with the same keypair on the same address.
+//!
+//! Random draws come from generators derived from the seed, the node and a
+//! per-node connection counter, so a run is repeatable up to the order in
+//! which the tokio scheduler lets nodes open their connections.
+
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use futures::{future::BoxFuture, AsyncRead, AsyncWrite, FutureExt};
+use libp2p::{
+    core::{
+        multiaddr::Protocol,
+        transport::{
+            memory::Channel, DialOpts, ListenerId, MemoryTransport, TransportError, TransportEvent,
+        },
+        upgrade::Version,
+        Transport,
+    },
+    identity::Keypair,
+    noise, yamux, Multiaddr, Swarm, SwarmBuilder,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::time::Sleep;
+
+use crate::{
+    behaviour::{Behavior, BehaviourOptions, NodeMode},
+    store::StoreLimits,
+};
+
+/// Memory ports are process-wide, keep clear of the ones tests pick by hand.
+const BASE_PORT: u64 = 40_000;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SimConfig {
+    pub latency: Duration,
+    pub jitter: Duration,
+    /// Probability in `0.0..=1.0` that a dial or a write fails
+    pub drop_rate: f64,
+}
+
+struct NetState {
+    seed: u64,
+    config: SimConfig,
+    /// Partition group of every node; nodes without an entry are in group 0
+    groups: Vec<usize>,
+    /// Connections opened per node so far, to derive their generators
+    connections: Vec<u64>,
+}
+
+/// Shared fault configuration of a simulated network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetState>>,
+}
+
+impl SimNetwork {
+    pub fn new(seed: u64, config: SimConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(NetState {
+                seed,
+                config,
+                groups: Vec::new(),
+                connections: Vec::new(),
+            })),
+        }
+    }
+
+    /// The address node `node` listens on.
+    pub fn addr(node: usize) -> Multiaddr {
+        Protocol::Memory(BASE_PORT + node as u64).into()
+    }
+
+    fn node_of(addr: &Multiaddr) -> Option<usize> {
+        addr.iter().find_map(|p| match p {
+            Protocol::Memory(port) if port >= BASE_PORT => Some((port - BASE_PORT) as usize),
+            _ => None,
+        })
+    }
+
+    pub fn set_config(&self, config: SimConfig) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    /// Splits the network; nodes not listed in any group end up in the first.
+    pub fn partition(&self, groups: &[&[usize]]) {
+        let mut state = self.state.lock().unwrap();
+        state.groups.clear();
+        for (group, nodes) in groups.iter().enumerate() {
+            for &node in *nodes {
+                if state.groups.len() <= node {
+                    state.groups.resize(node + 1, 0);
+                }
+                state.groups[node] = group;
+            }
+        }
+    }
+
+    pub fn heal(&self) {
+        self.state.lock().unwrap().groups.clear();
+    }
+
+    fn is_cut(&self, a: usize, b: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        let group = |n: usize| state.groups.get(n).copied().unwrap_or(0);
+        group(a) != group(b)
+    }
+
+    fn config(&self) -> SimConfig {
+        self.state.lock().unwrap().config
+    }
+
+    fn rng_for(&self, node: usize) -> StdRng {
+        let mut state = self.state.lock().unwrap();
+        if state.connections.len() <= node {
+            state.connections.resize(node + 1, 0);
+        }
+        state.connections[node] += 1;
+        let seq = state.connections[node];
+        StdRng::seed_from_u64(state.seed ^ ((node as u64) << 32) ^ seq.wrapping_mul(0x9e37_79b9))
+    }
+
+    pub fn transport(&self, node: usize) -> SimTransport {
+        SimTransport {
+            inner: MemoryTransport::default(),
+            net: self.clone(),
+            local: node,
+            listeners: Vec::new(),
+        }
+    }
+
+    /// Builds a server node on this network; use the same keypair to restart it.
+    pub fn swarm(
+        &self,
+        node: usize,
+        key: Keypair,
+    ) -> Result<Swarm<Behavior>, Box<dyn Error + Send + Sync>> {
+        let transport = self.transport(node);
+        let swarm = SwarmBuilder::with_existing_identity(key)
+            .with_tokio()
+            .with_other_transport(|key| {
+                Ok(transport
+                    .upgrade(Version::V1)
+                    .authenticate(noise::Config::new(key)?)
+                    .multiplex(yamux::Config::default()))
+            })?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_behaviour(|key, relay_client| {
+                Behavior::new(
+                    key,
+                    relay_client,
+                    BehaviourOptions {
+                        mode: NodeMode::Server,
+                        mdns: false,
+                        relay_server: false,
+                        republish_interval: None,
+                        reprovide_interval: None,
+                        store: StoreLimits::default(),
+                    },
+                )
+            })?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build();
+        Ok(swarm)
+    }
+}
+
+/// A [`MemoryTransport`] of one node, subject to the faults of its network.
+pub struct SimTransport {
+    inner: MemoryTransport,
+    net: SimNetwork,
+    local: usize,
+    listeners: Vec<ListenerId>,
+}
+
+impl Drop for SimTransport {
+    fn drop(&mut self) {
+        // MemoryTransport 只在 remove_listener 时释放端口，崩溃的节点要能在原地址重启
+        for id in self.listeners.drain(..) {
+            self.inner.remove_listener(id);
+        }
+    }
+}
+
+impl Transport for SimTransport {
+    type Output = SimConnection;
+    type Error = io::Error;
+    type ListenerUpgrade = BoxFuture<'static, Result<SimConnection, io::Error>>;
+    type Dial = BoxFuture<'static, Result<SimConnection, io::Error>>;
+
+    fn listen_on(
+        &mut self,
+        id: ListenerId,
+        addr: Multiaddr,
+    ) -> Result<(), TransportError<Self::Error>> {
+        self.inner
+            .listen_on(id, addr)
+            .map_err(|e| e.map(io::Error::other))?;
+        self.listeners.push(id);
+        Ok(())
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.listeners.retain(|l| *l != id);
+        self.inner.remove_listener(id)
+    }
+
+    fn dial(
+        &mut self,
+        addr: Multiaddr,
+        opts: DialOpts,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let remote = SimNetwork::node_of(&addr);
+        let dial = self
+            .inner
+            .dial(addr, opts)
+            .map_err(|e| e.map(io::Error::other))?;
+        let net = self.net.clone();
+        let local = self.local;
+        let mut rng = net.rng_for(local);
+        Ok(async move {
+            let config = net.config();
+            tokio::time::sleep(latency(&config, &mut rng)).await;
+            let cut = remote.is_some_and(|remote| net.is_cut(local, remote));
+            if cut || rng.gen_bool(config.drop_rate) {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            let channel = dial.await.map_err(io::Error::other)?;
+            Ok(SimConnection::new(
+                channel,
+                net,
+                rng,
+                remote.map(|r| (local, r)),
+            ))
+        }
+        .boxed())
+    }
+
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        let event = ready!(Pin::new(&mut self.inner).poll(cx));
+        let net = self.net.clone();
+        let local = self.local;
+        Poll::Ready(
+            event
+                .map_upgrade(|upgrade| {
+                    // 入站连接不知道对端是哪个节点，分区由拨号方负责检查
+                    async move {
+                        let channel = upgrade.await.map_err(io::Error::other)?;
+                        let rng = net.rng_for(local);
+                        Ok(SimConnection::new(channel, net, rng, None))
+                    }
+                    .boxed()
+                })
+                .map_err(io::Error::other),
+        )
+    }
+}
+
+fn latency(config: &SimConfig, rng: &mut StdRng) -> Duration {
+    if config.jitter.is_zero() {
+        config.latency
+    } else {
+        config.latency + config.jitter.mul_f64(rng.gen::<f64>())
+    }
+}
+
+enum WriteState {
+    Idle,
+    Delaying(Pin<Box<Sleep>>),
+    Ready,
+}
+
+/// A connection that delays writes and fails when dropped or partitioned.
+pub struct SimConnection {
+    inner: Channel<Vec<u8>>,
+    net: SimNetwork,
+    rng: StdRng,
+    /// `(local, remote)` when known, i.e. on the dialing side
+    link: Option<(usize, usize)>,
+    write: WriteState,
+}
+
+impl SimConnection {
+    fn new(
+        inner: Channel<Vec<u8>>,
+        net: SimNetwork,
+        rng: StdRng,
+        link: Option<(usize, usize)>,
+    ) -> Self {
+        Self {
+            inner,
+            net,
+            rng,
+            link,
+            write: WriteState::Idle,
+        }
+    }
+
+    fn check_link(&self) -> io::Result<()> {
+        match self.link {
+            Some((a, b)) if self.net.is_cut(a, b) => Err(io::ErrorKind::ConnectionReset.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl AsyncRead for SimConnection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_link()?;
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SimConnection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check_link()?;
+        loop {
+            match &mut self.write {
+                WriteState::Idle => {
+                    let config = self.net.config();
+                    if self.rng.gen_bool(config.drop_rate) {
+                        return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+                    }
+                    let delay = latency(&config, &mut self.rng);
+                    self.write = if delay.is_zero() {
+                        WriteState::Ready
+                    } else {
+                        WriteState::Delaying(Box::pin(tokio::time::sleep(delay)))
+                    };
+                }
+                WriteState::Delaying(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.write = WriteState::Ready;
+                }
+                WriteState::Ready => {
+                    let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf));
+                    self.write = WriteState::Idle;
+                    return Poll::Ready(written);
+                }
+            }
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,379 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use libp2p::{
+    kad::{self, store::RecordStore, ProviderRecord, Record},
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{ownership::Ownership, store::DkvStore};
+
+/// One line of a snapshot file (JSON Lines).
+///
+/// Keys and values are base64 encoded, expiry is an absolute unix timestamp in
+/// milliseconds so that a snapshot stays meaningful after a restart.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Entry {
+    Record {
+        key: String,
+        value: String,
+        publisher: Option<String>,
+        expires_at: Option<u64>,
+    },
+    Provider {
+        key: String,
+        provider: String,
+        addresses: Vec<String>,
+        expires_at: Option<u64>,
+    },
+}
+
+/// Outcome of loading a snapshot. Entries the store refuses (it is full, see
+/// [`crate::store::StoreLimits`], or the key is held by a live lease) are
+/// skipped and the rest is loaded anyway.
+#[derive(Debug, Default)]
+pub struct Import {
+    pub records: usize,
+    pub providers: usize,
+    /// Keys of the refused entries and why they were refused
+    pub skipped: Vec<(kad::RecordKey, kad::store::Error)>,
+}
+
+/// A portable copy of the records and provider records held by a node.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    pub records: Vec<Record>,
+    pub providers: Vec<ProviderRecord>,
+}
+
+impl Snapshot {
+    pub fn capture(store: &DkvStore) -> Self {
+        let now = Instant::now();
+        Self {
+            records: store
+                .records()
+                .filter(|r| !r.is_expired(now))
+                .map(|r| r.into_owned())
+                .collect(),
+            providers: store
+                .all_providers()
+                .filter(|p| !p.is_expired(now))
+                .collect(),
+        }
+    }
+
+    pub fn write_to(&self, path: &Path) -> anyhow::Result<()> {
+        // write to a temporary file first so a crash never leaves a truncated snapshot
+        let tmp = path.with_extension("tmp");
+        let mut out = BufWriter::new(
+            File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?,
+        );
+        let now = Instant::now();
+        for r in &self.records {
+            let entry = Entry::Record {
+                key: BASE64.encode(r.key.as_ref()),
+                value: BASE64.encode(&r.value),
+                publisher: r.publisher.map(|p| p.to_string()),
+                expires_at: r.expires.map(|e| to_unix_millis(e, now)),
+            };
+            serde_json::to_writer(&mut out, &entry)?;
+            out.write_all(b"\n")?;
+        }
+        for p in &self.providers {
+            let entry = Entry::Provider {
+                key: BASE64.encode(p.key.as_ref()),
+                provider: p.provider.to_string(),
+                addresses: p.addresses.iter().map(|a| a.to_string()).collect(),
+                expires_at: p.expires.map(|e| to_unix_millis(e, now)),
+            };
+            serde_json::to_writer(&mut out, &entry)?;
+            out.write_all(b"\n")?;
+        }
+        out.flush()?;
+        drop(out);
+        std::fs::rename(&tmp, path).with_context(|| format!("renaming to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads a snapshot, silently dropping entries that expired in the meantime.
+    pub fn read_from(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let now = Instant::now();
+        let mut snapshot = Snapshot::default();
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: Entry = serde_json::from_str(&line)
+                .with_context(|| format!("{}:{}: invalid entry", path.display(), n + 1))?;
+            match entry {
+                Entry::Record {
+                    key,
+                    value,
+                    publisher,
+                    expires_at,
+                } => {
+                    let Some(expires) = from_unix_millis(expires_at, now) else {
+                        continue;
+                    };
+                    snapshot.records.push(Record {
+                        key: kad::RecordKey::new(&BASE64.decode(key)?),
+                        value: BASE64.decode(value)?,
+                        publisher: publisher.map(|p| p.parse::<PeerId>()).transpose()?,
+                        expires,
+                    });
+                }
+                Entry::Provider {
+                    key,
+                    provider,
+                    addresses,
+                    expires_at,
+                } => {
+                    let Some(expires) = from_unix_millis(expires_at, now) else {
+                        continue;
+                    };
+                    snapshot.providers.push(ProviderRecord {
+                        key: kad::RecordKey::new(&BASE64.decode(key)?),
+                        provider: provider.parse()?,
+                        addresses: addresses
+                            .iter()
+                            .map(|a| a.parse::<Multiaddr>())
+                            .collect::<Result<_, _>>()?,
+                        expires,
+                    });
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Loads the snapshot into the local store only.
+    pub fn restore(&self, store: &mut DkvStore) -> Import {
+        let mut import = Import::default();
+        for r in &self.records {
+            match store.put(r.clone()) {
+                Ok(()) => import.records += 1,
+                Err(e) => import.skipped.push((r.key.clone(), e)),
+            }
+        }
+        for p in &self.providers {
+            match store.add_provider(p.clone()) {
+                Ok(()) => import.providers += 1,
+                Err(e) => import.skipped.push((p.key.clone(), e)),
+            }
+        }
+        import
+    }
+
+    /// Loads the snapshot and publishes it to the network: records published
+    /// by this node, or by nobody, are put again and keys provided by this
+    /// node are announced again. Records and provider records of other peers
+    /// are only kept locally, since putting them would make this node their
+    /// publisher.
+    pub fn republish(
+        &self,
+        kademlia: &mut kad::Behaviour<DkvStore>,
+        ownership: &mut Ownership,
+    ) -> Import {
+        let local_peer_id = kademlia.store_mut().local_peer_id();
+        let mut import = Import::default();
+        for r in &self.records {
+            let result = if r.publisher.is_none_or(|p| p == local_peer_id) {
+                ownership
+                    .put_record(kademlia, r.clone(), kad::Quorum::One)
+                    .map(|_| ())
+            } else {
+                kademlia.store_mut().put(r.clone())
+            };
+            match result {
+                Ok(()) => import.records += 1,
+                Err(e) => import.skipped.push((r.key.clone(), e)),
+            }
+        }
+        for p in &self.providers {
+            let result = if p.provider == local_peer_id {
+                kademlia.start_providing(p.key.clone()).map(|_| ())
+            } else {
+                kademlia.store_mut().add_provider(p.clone())
+            };
+            match result {
+                Ok(()) => import.providers += 1,
+                Err(e) => import.skipped.push((p.key.clone(), e)),
+            }
+        }
+        import
+    }
+}
+
+fn to_unix_millis(expires: Instant, now: Instant) -> u64 {
+    let remaining = expires.saturating_duration_since(now);
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// `None` means the entry has already expired, `Some(None)` that it never does.
+fn from_unix_millis(expires_at: Option<u64>, now: Instant) -> Option<Option<Instant>> {
+    let Some(millis) = expires_at else {
+        return Some(None);
+    };
+    let remaining = (UNIX_EPOCH + Duration::from_millis(millis))
+        .duration_since(SystemTime::now())
+        .ok()?;
+    Some(Some(now + remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreLimits;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "dkv-snapshot-{}-{}.jsonl",
+            std::process::id(),
+            name
+        ))
+    }
+
+    fn record(key: &str, expires: Option<Instant>) -> Record {
+        Record {
+            key: kad::RecordKey::new(&key),
+            value: key.as_bytes().to_vec(),
+            publisher: Some(PeerId::random()),
+            expires,
+        }
+    }
+
+    #[test]
+    fn round_trip_through_a_file() {
+        let local = PeerId::random();
+        let mut store = DkvStore::new(local, StoreLimits::default());
+        let in_an_hour = Instant::now() + Duration::from_secs(3600);
+        store.put(record("forever", None)).unwrap();
+        store.put(record("hour", Some(in_an_hour))).unwrap();
+        store
+            .add_provider(ProviderRecord {
+                key: kad::RecordKey::new(&"provided"),
+                provider: local,
+                addresses: vec!["/ip4/127.0.0.1/tcp/4001".parse().unwrap()],
+                expires: Some(in_an_hour),
+            })
+            .unwrap();
+
+        let path = temp_path("round-trip");
+        Snapshot::capture(&store).write_to(&path).unwrap();
+        let snapshot = Snapshot::read_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut restored = DkvStore::new(local, StoreLimits::default());
+        let import = snapshot.restore(&mut restored);
+        assert_eq!((import.records, import.providers), (2, 1));
+        assert!(import.skipped.is_empty());
+
+        for key in ["forever", "hour"] {
+            let key = kad::RecordKey::new(&key);
+            let (before, after) = (store.get(&key).unwrap(), restored.get(&key).unwrap());
+            assert_eq!(before.value, after.value);
+            assert_eq!(before.publisher, after.publisher);
+            // 到期时间以毫秒精度保存
+            match (before.expires, after.expires) {
+                (None, None) => {}
+                (Some(a), Some(b)) => {
+                    let drift = if a > b { a - b } else { b - a };
+                    assert!(drift < Duration::from_secs(1), "drifted by {:?}", drift);
+                }
+                other => panic!("expiry changed: {:?}", other),
+            }
+        }
+        let providers = restored.providers(&kad::RecordKey::new(&"provided"));
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, local);
+        assert_eq!(providers[0].addresses.len(), 1);
+    }
+
+    #[test]
+    fn expired_entries_are_dropped_on_read() {
+        let path = temp_path("expired");
+        let line = |key: &str, expires_at: u64| {
+            format!(
+                "{{\"kind\":\"record\",\"key\":\"{}\",\"value\":\"\",\"publisher\":null,\"expires_at\":{}}}\n",
+                BASE64.encode(key),
+                expires_at
+            )
+        };
+        let tomorrow = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            + 24 * 60 * 60 * 1000;
+        std::fs::write(&path, line("old", 1) + "\n" + &line("new", tomorrow)).unwrap();
+        let snapshot = Snapshot::read_from(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(snapshot.records.len(), 1);
+        assert_eq!(snapshot.records[0].key, kad::RecordKey::new(&"new"));
+    }
+
+    #[test]
+    fn restore_keeps_going_past_refused_records() {
+        let snapshot = Snapshot {
+            records: ["a", "b", "c"].map(|k| record(k, None)).to_vec(),
+            providers: Vec::new(),
+        };
+        let limits = StoreLimits {
+            max_records: 1,
+            ..StoreLimits::default()
+        };
+        let mut store = DkvStore::new(PeerId::random(), limits);
+        let import = snapshot.restore(&mut store);
+
+        assert_eq!(import.records, 1);
+        assert_eq!(import.skipped.len(), 2);
+        assert!(import
+            .skipped
+            .iter()
+            .all(|(_, e)| matches!(e, kad::store::Error::MaxRecords)));
+        assert_eq!(store.records().count(), 1);
+    }
+
+    #[test]
+    fn republish_keeps_foreign_publishers() {
+        let local = PeerId::random();
+        let mut kademlia = kad::Behaviour::new(local, DkvStore::new(local, StoreLimits::default()));
+        let foreign = record("foreign", None);
+        let mut anonymous = record("anonymous", None);
+        anonymous.publisher = None;
+        let mut own = record("own", None);
+        own.publisher = Some(local);
+        let snapshot = Snapshot {
+            records: vec![foreign.clone(), anonymous, own],
+            providers: Vec::new(),
+        };
+
+        let mut ownership = Ownership::new(
+            Duration::from_secs(3600),
+            Duration::from_secs(3600),
+            kad::Quorum::One,
+        );
+        let import = snapshot.republish(&mut kademlia, &mut ownership);
+        assert_eq!(import.records, 3);
+        let store = kademlia.store_mut();
+        let publisher = |store: &mut DkvStore, key: &str| {
+            store.get(&kad::RecordKey::new(&key)).unwrap().publisher
+        };
+        assert_eq!(publisher(store, "foreign"), foreign.publisher);
+        assert_eq!(publisher(store, "anonymous"), Some(local));
+        assert_eq!(publisher(store, "own"), Some(local));
+    }
+}
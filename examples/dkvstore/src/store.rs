@@ -0,0 +1,150 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use libp2p::{
+    kad::{
+        self,
+        store::{MemoryStore, MemoryStoreConfig, RecordStore},
+        ProviderRecord, Record,
+    },
+    PeerId,
+};
+use serde::Serialize;
+
+use crate::lease;
+
+/// `MemoryStore`'s default number of records
+pub const DEFAULT_MAX_RECORDS: usize = 1024;
+/// `MemoryStore`'s default number of keys this node provides
+pub const DEFAULT_MAX_PROVIDED_KEYS: usize = 1024;
+
+/// How much a node's store holds at most. Every node keeps a replica of the
+/// keys it is among the closest peers of, so with the replication factor of
+/// 20 a node holds about `20 * keys / nodes` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreLimits {
+    pub max_records: usize,
+    pub max_provided_keys: usize,
+}
+
+impl Default for StoreLimits {
+    fn default() -> Self {
+        Self {
+            max_records: DEFAULT_MAX_RECORDS,
+            max_provided_keys: DEFAULT_MAX_PROVIDED_KEYS,
+        }
+    }
+}
+
+/// Writes the store refused, by cause.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Rejections {
+    /// The store was at its [`StoreLimits`]
+    pub full: u64,
+    /// The key is held by a live lease
+    pub leased: u64,
+}
+
+/// A `MemoryStore` that also remembers which keys have provider records, so
+/// the whole keyspace held by this node can be enumerated (e.g. for snapshots).
+/// Writes to lease records are checked by [`lease::admits`].
+pub struct DkvStore {
+    local_peer_id: PeerId,
+    inner: MemoryStore,
+    provider_keys: HashSet<kad::RecordKey>,
+    rejections: Rejections,
+}
+
+impl DkvStore {
+    pub fn new(local_peer_id: PeerId, limits: StoreLimits) -> Self {
+        let config = MemoryStoreConfig {
+            max_records: limits.max_records,
+            max_provided_keys: limits.max_provided_keys,
+            ..Default::default()
+        };
+        Self {
+            local_peer_id,
+            inner: MemoryStore::with_config(local_peer_id, config),
+            provider_keys: HashSet::new(),
+            rejections: Rejections::default(),
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Whether `record` may be written here, see [`lease::admits`]; local
+    /// writers check this first to tell a held lease from other store errors.
+    pub fn admits(&self, record: &Record) -> bool {
+        lease::admits(self.inner.get(&record.key).as_deref(), record)
+    }
+
+    /// Writes refused since the node started.
+    pub fn rejections(&self) -> Rejections {
+        self.rejections
+    }
+
+    /// All provider records held locally, including those of other peers.
+    pub fn all_providers(&self) -> impl Iterator<Item = ProviderRecord> + '_ {
+        self.provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+    }
+}
+
+impl RecordStore for DkvStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &kad::RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> kad::store::Result<()> {
+        if !self.admits(&r) {
+            // store::Error has no variant for a refused write; any error makes
+            // Kademlia reset the PUT stream so the writer does not count us.
+            // MaxRecords would read as a full store, so use the other one
+            self.rejections.leased += 1;
+            return Err(kad::store::Error::ValueTooLarge);
+        }
+        let result = self.inner.put(r);
+        if let Err(kad::store::Error::MaxRecords) = result {
+            self.rejections.full += 1;
+        }
+        result
+    }
+
+    fn remove(&mut self, k: &kad::RecordKey) {
+        self.inner.remove(k)
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> kad::store::Result<()> {
+        let key = record.key.clone();
+        if let Err(e) = self.inner.add_provider(record) {
+            self.rejections.full += 1;
+            return Err(e);
+        }
+        self.provider_keys.insert(key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &kad::RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &kad::RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        if self.inner.providers(k).is_empty() {
+            self.provider_keys.remove(k);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Terminal dashboard for a running node, enabled with `--tui`.
+//!
+//! The screen shows the node's addresses, connected peers, k-bucket
+//! occupancy, Kademlia queries in flight, recent events and the records held
+//! locally, with a command line at the bottom that accepts the same commands
+//! as stdin.
+//!
+//! Keys: `Enter` runs the command, `Up`/`Down` walk the command history,
+//! `PageUp`/`PageDown` scroll the events, `Esc` clears the input and `Ctrl-C`
+//! stops the node.
+//!
+//! Everything the node prints goes through [`outln!`] and [`errln!`]. While a
+//! [`Dashboard`] is open those lines end up in its event pane instead of on
+//! the terminal it draws on.
+
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use libp2p::{
+    kad::{self, store::RecordStore},
+    PeerId, Swarm,
+};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    behaviour::Behavior,
+    logging::{printable, LogLine, CAPTURED},
+    outln,
+};
+
+/// What the main loop should do after a key press.
+pub enum Action {
+    /// A command line was entered
+    Command(String),
+    /// The user asked to stop the node
+    Quit,
+    /// Only the screen changed
+    Redraw,
+}
+
+/// The terminal while the dashboard is shown; dropping it restores the terminal.
+pub struct Dashboard {
+    terminal: DefaultTerminal,
+    events: EventStream,
+    input: String,
+    history: Vec<String>,
+    /// Position in `history` while walking it with the arrow keys
+    recalled: Option<usize>,
+    /// Lines scrolled up from the newest event
+    scroll: usize,
+}
+
+impl Dashboard {
+    /// Switches the terminal to the dashboard and starts capturing output.
+    pub fn open() -> io::Result<Self> {
+        *CAPTURED.lock().unwrap() = Some(VecDeque::new());
+        Ok(Self {
+            terminal: ratatui::try_init()?,
+            events: EventStream::new(),
+            input: String::new(),
+            history: Vec::new(),
+            recalled: None,
+            scroll: 0,
+        })
+    }
+
+    /// Waits for the next key press and applies it to the input line.
+    pub async fn next_action(&mut self) -> io::Result<Action> {
+        loop {
+            match self.events.next().await {
+                Some(Ok(Event::Key(key))) if key.kind != KeyEventKind::Release => {
+                    return Ok(self.on_key(key));
+                }
+                Some(Ok(Event::Resize(..))) => return Ok(Action::Redraw),
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(Action::Quit),
+            }
+        }
+    }
+
+    fn on_key(&mut self, key: KeyEvent) -> Action {
+        match key.code {
+            // 原始模式下 Ctrl-C 不再产生信号，在这里处理
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Action::Quit
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.input.clear(),
+            KeyCode::Enter => {
+                let line = std::mem::take(&mut self.input);
+                self.recalled = None;
+                self.scroll = 0;
+                if line.trim().is_empty() {
+                    return Action::Redraw;
+                }
+                if self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                outln!("> {}", line);
+                return Action::Command(line);
+            }
+            KeyCode::Up if !self.history.is_empty() => {
+                let index = match self.recalled {
+                    Some(index) => index.saturating_sub(1),
+                    None => self.history.len() - 1,
+                };
+                self.recalled = Some(index);
+                self.input = self.history[index].clone();
+            }
+            KeyCode::Down => match self.recalled {
+                Some(index) if index + 1 < self.history.len() => {
+                    self.recalled = Some(index + 1);
+                    self.input = self.history[index + 1].clone();
+                }
+                _ => {
+                    self.recalled = None;
+                    self.input.clear();
+                }
+            },
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            _ => {}
+        }
+        Action::Redraw
+    }
+
+    /// Redraws the whole screen from the current state of `swarm`.
+    pub fn draw(&mut self, swarm: &mut Swarm<Behavior>) -> io::Result<()> {
+        let view = View::capture(swarm);
+        let log = CAPTURED.lock().unwrap();
+        let log = log.as_ref().map(|log| log.iter().collect::<Vec<_>>());
+        let log = log.unwrap_or_default();
+        self.terminal.draw(|frame| {
+            let [header, middle, lower, input] = Layout::vertical([
+                Constraint::Length(4),
+                Constraint::Percentage(40),
+                Constraint::Fill(1),
+                Constraint::Length(3),
+            ])
+            .areas(frame.area());
+            let [peers, buckets, queries] = Layout::horizontal([
+                Constraint::Percentage(35),
+                Constraint::Percentage(25),
+                Constraint::Percentage(40),
+            ])
+            .areas(middle);
+            let [events, keys] =
+                Layout::horizontal([Constraint::Percentage(65), Constraint::Percentage(35)])
+                    .areas(lower);
+
+            view.draw_header(frame, header);
+            view.draw_peers(frame, peers);
+            view.draw_buckets(frame, buckets);
+            view.draw_queries(frame, queries);
+            view.draw_keys(frame, keys);
+            draw_events(frame, events, &log, self.scroll);
+
+            let prompt = Paragraph::new(format!("> {}", self.input))
+                .block(Block::bordered().title(" command (Enter to run, Ctrl-C to quit) "));
+            frame.render_widget(prompt, input);
+            frame.set_cursor_position((
+                input.x + 3 + self.input.chars().count() as u16,
+                input.y + 1,
+            ));
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        ratatui::restore();
+        *CAPTURED.lock().unwrap() = None;
+    }
+}
+
+/// Everything shown on screen, taken from the swarm before drawing.
+struct View {
+    local_peer_id: PeerId,
+    listen_addrs: Vec<String>,
+    external_addrs: Vec<String>,
+    peers: Vec<(PeerId, bool)>,
+    /// `(bucket index, entries, has pending entry)` of the non-empty buckets
+    buckets: Vec<(u32, usize, bool)>,
+    queries: Vec<String>,
+    records: Vec<(String, usize)>,
+    num_records: usize,
+}
+
+impl View {
+    fn capture(swarm: &mut Swarm<Behavior>) -> Self {
+        let local_peer_id = *swarm.local_peer_id();
+        let listen_addrs = swarm.listeners().map(|a| a.to_string()).collect();
+        let external_addrs = swarm.external_addresses().map(|a| a.to_string()).collect();
+        let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+
+        let kademlia = &mut swarm.behaviour_mut().kademlia;
+        let mut routable = HashSet::new();
+        let mut buckets = Vec::new();
+        for bucket in kademlia.kbuckets() {
+            // 空桶不会出现在迭代结果中；桶序号即距离的 log2
+            let index = bucket.range().0.ilog2().unwrap_or(0);
+            buckets.push((index, bucket.num_entries(), bucket.has_pending()));
+            routable.extend(bucket.iter().map(|entry| *entry.node.key.preimage()));
+        }
+        let mut peers: Vec<(PeerId, bool)> = connected
+            .into_iter()
+            .map(|peer| (peer, routable.contains(&peer)))
+            .collect();
+        peers.sort();
+
+        let queries = kademlia.iter_queries().map(describe_query).collect();
+
+        let store = kademlia.store_mut();
+        let mut records: Vec<(String, usize)> = store
+            .records()
+            .map(|r| (printable(&r.key), r.value.len()))
+            .collect();
+        records.sort();
+        let num_records = records.len();
+
+        Self {
+            local_peer_id,
+            listen_addrs,
+            external_addrs,
+            peers,
+            buckets,
+            queries,
+            records,
+            num_records,
+        }
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        let lines = vec![
+            Line::from(vec![
+                Span::styled(self.local_peer_id.to_string(), bold),
+                Span::raw(format!(
+                    "  {} connected, {} records",
+                    self.peers.len(),
+                    self.num_records
+                )),
+            ]),
+            Line::from(format!("listening: {}", self.listen_addrs.join(", "))),
+        ];
+        let mut block = Block::bordered().title(" dkvstore ");
+        if !self.external_addrs.is_empty() {
+            block = block.title_bottom(format!(" external: {} ", self.external_addrs.join(", ")));
+        }
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    fn draw_peers(&self, frame: &mut Frame, area: Rect) {
+        let items = self.peers.iter().map(|(peer, routable)| {
+            let marker = if *routable { "*" } else { " " };
+            ListItem::new(format!("{} {}", marker, peer))
+        });
+        let title = format!(
+            " connected peers: {} (* in routing table) ",
+            self.peers.len()
+        );
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_buckets(&self, frame: &mut Frame, area: Rect) {
+        let k = kad::K_VALUE.get();
+        let items = self.buckets.iter().map(|(index, entries, pending)| {
+            let bar = "█".repeat(*entries) + &"·".repeat(k.saturating_sub(*entries));
+            let pending = if *pending { " +1" } else { "" };
+            ListItem::new(format!("{:>3} {} {:>2}{}", index, bar, entries, pending))
+        });
+        let total: usize = self.buckets.iter().map(|(_, entries, _)| entries).sum();
+        let title = format!(" k-buckets: {} peers ", total);
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_queries(&self, frame: &mut Frame, area: Rect) {
+        let items = self.queries.iter().map(|q| ListItem::new(q.as_str()));
+        let title = format!(" queries in flight: {} ", self.queries.len());
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+
+    fn draw_keys(&self, frame: &mut Frame, area: Rect) {
+        let items = self
+            .records
+            .iter()
+            .map(|(key, len)| ListItem::new(format!("{} ({} B)", key, len)));
+        let title = format!(" local records: {} ", self.num_records);
+        frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+    }
+}
+
+fn draw_events(frame: &mut Frame, area: Rect, log: &[&LogLine], scroll: usize) {
+    let height = area.height.saturating_sub(2) as usize;
+    let end = log
+        .len()
+        .saturating_sub(scroll.min(log.len().saturating_sub(height)));
+    let start = end.saturating_sub(height);
+    let items = log[start..end].iter().map(|line| {
+        let style = if line.error {
+            Style::default().fg(Color::Red)
+        } else {
+            Style::default()
+        };
+        ListItem::new(line.text.as_str()).style(style)
+    });
+    let title = if end < log.len() {
+        format!(" events ({} newer below) ", log.len() - end)
+    } else {
+        " events ".to_string()
+    };
+    frame.render_widget(List::new(items).block(Block::bordered().title(title)), area);
+}
+
+/// One line per query: kind, target, progress of its requests and age.
+fn describe_query(query: kad::QueryRef<'_>) -> String {
+    let target = match query.info() {
+        kad::QueryInfo::Bootstrap { .. } => "BOOTSTRAP".to_string(),
+        kad::QueryInfo::GetClosestPeers { key, .. } => format!("CLOSEST {}", printable(key)),
+        kad::QueryInfo::GetProviders { key, .. } => {
+            format!("GET_PROVIDERS {}", printable(key.as_ref()))
+        }
+        kad::QueryInfo::AddProvider { key, .. } => {
+            format!("PUT_PROVIDER {}", printable(key.as_ref()))
+        }
+        kad::QueryInfo::PutRecord { record, .. } => {
+            format!("PUT {}", printable(record.key.as_ref()))
+        }
+        kad::QueryInfo::GetRecord { key, .. } => format!("GET {}", printable(key.as_ref())),
+    };
+    let stats = query.stats();
+    let elapsed = stats.duration().unwrap_or_default();
+    format!(
+        "{} {}/{} ok, {} failed, {} pending, {:.1}s",
+        target,
+        stats.num_successes(),
+        stats.num_requests(),
+        stats.num_failures(),
+        stats.num_pending(),
+        elapsed.as_secs_f32()
+    )
+}
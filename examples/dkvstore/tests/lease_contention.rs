@@ -0,0 +1,289 @@
+//! Runs several dkvstore nodes in-process and lets them fight over one lease.
+//!
+//! Fails if two nodes ever hold the lease at the same time, if a fencing
+//! token goes backwards, or if a step does not behave as documented in
+//! `dkvstore::lease`.
+//!
+//! ```text
+//! cargo test -p dkvstore --test lease_contention -- --nocapture
+//! ```
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use futures::StreamExt;
+use libp2p::{identity::Keypair, kad, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
+use tokio::{select, sync::mpsc, time::timeout};
+use tracing_subscriber::EnvFilter;
+
+use dkvstore::{
+    behaviour::{new_swarm, Behavior, BehaviorEvent, BehaviourOptions, NodeMode},
+    lease::{now_millis, Lease, LeaseEvent, LeaseManager},
+    store::StoreLimits,
+};
+
+const NODES: usize = 5;
+const LOCK: &str = "job";
+/// Long enough to outlast the losers of a contended round, which only give up
+/// once the replicas that refused them time out
+const TTL: Duration = Duration::from_secs(30);
+
+enum Command {
+    Lock(Duration),
+    Renew(Duration),
+    Unlock,
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn one_holder_at_a_time() -> Result<()> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .try_init();
+
+    let mut swarms = Vec::new();
+    for _ in 0..NODES {
+        let mut swarm = new_swarm(
+            Keypair::generate_ed25519(),
+            BehaviourOptions {
+                mode: NodeMode::Server,
+                mdns: false,
+                relay_server: false,
+                republish_interval: None,
+                reprovide_interval: None,
+                store: StoreLimits::default(),
+            },
+        )
+        .map_err(|e| anyhow!(e))?;
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .set_mode(Some(kad::Mode::Server));
+        swarm.listen_on("/ip4/127.0.0.1/tcp/0".parse()?)?;
+        let addr = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = swarm.select_next_some().await {
+                break address;
+            }
+        };
+        swarms.push((swarm, addr));
+    }
+
+    // 每个节点都认识其他所有节点，保证大家看到相同的最近节点集合
+    let known: Vec<(PeerId, Multiaddr)> = swarms
+        .iter()
+        .map(|(swarm, addr)| (*swarm.local_peer_id(), addr.clone()))
+        .collect();
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let mut nodes = Vec::new();
+    for (index, (mut swarm, _)) in swarms.into_iter().enumerate() {
+        for (peer_id, addr) in &known {
+            if peer_id != swarm.local_peer_id() {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(peer_id, addr.clone());
+            }
+        }
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        tokio::spawn(run_node(index, swarm, commands, events_tx.clone()));
+        nodes.push(commands_tx);
+        println!("node {}: {}", index, known[index].0);
+    }
+
+    let mut checker = Checker::default();
+    let send = |node: usize, command: Command| {
+        nodes[node]
+            .send(command)
+            .map_err(|_| anyhow!("node {} stopped", node))
+    };
+
+    println!("\n# all {} nodes LOCK {} at once", NODES, LOCK);
+    let mut holder = None;
+    for round in 1..=5 {
+        for node in 0..NODES {
+            send(node, Command::Lock(TTL))?;
+        }
+        let results = checker.collect(&mut events, NODES).await?;
+        let winners: Vec<usize> = results
+            .iter()
+            .filter(|(_, e)| matches!(e, LeaseEvent::Acquired { .. }))
+            .map(|(node, _)| *node)
+            .collect();
+        if let [winner] = winners[..] {
+            holder = Some(winner);
+            break;
+        }
+        println!("round {}: nobody reached a majority, retrying", round);
+        tokio::time::sleep(Duration::from_millis(200 * round)).await;
+    }
+    let Some(holder) = holder else {
+        bail!("no node acquired the lease after 5 rounds");
+    };
+
+    let other = (holder + 1) % NODES;
+    println!("\n# node {} tries while node {} holds it", other, holder);
+    send(other, Command::Lock(TTL))?;
+    checker.expect(&mut events, other, "Busy").await?;
+
+    println!("\n# node {} renews", holder);
+    send(holder, Command::Renew(TTL))?;
+    checker.expect(&mut events, holder, "Renewed").await?;
+
+    println!(
+        "\n# node {} unlocks, node {} takes over with a short ttl",
+        holder, other
+    );
+    send(holder, Command::Unlock)?;
+    checker.expect(&mut events, holder, "Released").await?;
+    // 释放只等多数副本确认，其余副本稍后才写入，之前 LOCK 可能仍读到旧租约
+    for attempt in 1..=5 {
+        send(other, Command::Lock(Duration::from_secs(2)))?;
+        let (from, event) = checker.collect(&mut events, 1).await?.remove(0);
+        match (from == other, kind(&event)) {
+            (true, "Acquired") => break,
+            (true, "Busy") if attempt < 5 => {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+            (_, actual) => bail!(
+                "expected Acquired from node {}, got {} from node {}",
+                other,
+                actual,
+                from
+            ),
+        }
+    }
+
+    let third = (holder + 2) % NODES;
+    println!(
+        "\n# node {} lets it expire, node {} takes over",
+        other, third
+    );
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    send(other, Command::Renew(TTL))?;
+    checker.expect(&mut events, other, "Failed").await?;
+    send(third, Command::Lock(TTL))?;
+    checker.expect(&mut events, third, "Acquired").await?;
+
+    println!("\nOK: at most one holder at any time, tokens only grew");
+    Ok(())
+}
+
+async fn run_node(
+    index: usize,
+    mut swarm: Swarm<Behavior>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+    events: mpsc::UnboundedSender<(usize, LeaseEvent)>,
+) {
+    let mut leases = LeaseManager::default();
+    loop {
+        select! {
+            command = commands.recv() => {
+                let kademlia = &mut swarm.behaviour_mut().kademlia;
+                match command {
+                    Some(Command::Lock(ttl)) => leases.lock(kademlia, LOCK, ttl),
+                    Some(Command::Renew(ttl)) => leases.renew(kademlia, LOCK, ttl),
+                    Some(Command::Unlock) => leases.unlock(kademlia, LOCK),
+                    None => return,
+                }
+            }
+            event = swarm.select_next_some() => {
+                if let SwarmEvent::Behaviour(BehaviorEvent::Kademlia(
+                    kad::Event::OutboundQueryProgressed { id, result, step, .. },
+                )) = event
+                {
+                    let kademlia = &mut swarm.behaviour_mut().kademlia;
+                    leases.on_query_progressed(kademlia, id, &result, &step);
+                }
+            }
+        }
+        for event in leases.drain_events() {
+            let _ = events.send((index, event));
+        }
+    }
+}
+
+/// Watches all lease events for violations of the safety guarantees.
+#[derive(Default)]
+struct Checker {
+    holder: Option<(usize, Lease)>,
+    last_token: u64,
+}
+
+impl Checker {
+    fn observe(&mut self, node: usize, event: &LeaseEvent) -> Result<()> {
+        println!("node {}: {}", node, event);
+        match event {
+            LeaseEvent::Acquired { lease, .. } => {
+                if let Some((other, held)) = &self.holder {
+                    if *other != node && held.is_live(now_millis()) {
+                        bail!(
+                            "node {} acquired while node {} holds the lease",
+                            node,
+                            other
+                        );
+                    }
+                }
+                if lease.token <= self.last_token {
+                    bail!("token went from {} to {}", self.last_token, lease.token);
+                }
+                self.last_token = lease.token;
+                self.holder = Some((node, lease.clone()));
+            }
+            LeaseEvent::Renewed { lease, .. } => {
+                if lease.token != self.last_token {
+                    bail!("renewal changed the token to {}", lease.token);
+                }
+                self.holder = Some((node, lease.clone()));
+            }
+            LeaseEvent::Released { .. } => self.holder = None,
+            LeaseEvent::Busy { .. } | LeaseEvent::Failed { .. } => {}
+        }
+        Ok(())
+    }
+
+    async fn collect(
+        &mut self,
+        events: &mut mpsc::UnboundedReceiver<(usize, LeaseEvent)>,
+        count: usize,
+    ) -> Result<Vec<(usize, LeaseEvent)>> {
+        let mut results = Vec::new();
+        while results.len() < count {
+            let Ok(Some((node, event))) = timeout(Duration::from_secs(30), events.recv()).await
+            else {
+                bail!("timed out waiting for lease events");
+            };
+            self.observe(node, &event)?;
+            results.push((node, event));
+        }
+        Ok(results)
+    }
+
+    async fn expect(
+        &mut self,
+        events: &mut mpsc::UnboundedReceiver<(usize, LeaseEvent)>,
+        node: usize,
+        expected: &str,
+    ) -> Result<()> {
+        let (from, event) = self.collect(events, 1).await?.remove(0);
+        let actual = kind(&event);
+        if from != node || actual != expected {
+            bail!(
+                "expected {} from node {}, got {} from node {}",
+                expected,
+                node,
+                actual,
+                from
+            );
+        }
+        Ok(())
+    }
+}
+
+fn kind(event: &LeaseEvent) -> &'static str {
+    match event {
+        LeaseEvent::Acquired { .. } => "Acquired",
+        LeaseEvent::Busy { .. } => "Busy",
+        LeaseEvent::Renewed { .. } => "Renewed",
+        LeaseEvent::Released { .. } => "Released",
+        LeaseEvent::Failed { .. } => "Failed",
+    }
+}
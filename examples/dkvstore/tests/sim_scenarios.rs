@@ -0,0 +1,283 @@
+//! Fault-injection scenarios on a simulated network, see `dkvstore::sim`.
+//!
+//! ```text
+//! SIM_SEED=7 cargo test -p dkvstore --test sim_scenarios -- --nocapture
+//! ```
+//!
+//! Every scenario asserts that records stay available. `SIM_SEED` replaces
+//! the default seed of the fault schedule and the simulated network.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use libp2p::{identity::Keypair, kad};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use tokio::sync::Mutex;
+
+use dkvstore::{
+    cluster::ClusterNode,
+    gateway::GatewayError,
+    sim::{SimConfig, SimNetwork},
+};
+
+const OP_TIMEOUT: Duration = Duration::from_secs(10);
+const ATTEMPTS: usize = 3;
+
+/// The simulated nodes listen on fixed memory ports, which are shared by the
+/// whole process, so the scenarios run one at a time
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+struct Args {
+    seed: u64,
+    nodes: usize,
+    /// Number of records written before faults are injected
+    keys: usize,
+}
+
+impl Args {
+    fn new() -> Self {
+        Self {
+            seed: std::env::var("SIM_SEED")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(7),
+            nodes: 10,
+            keys: 40,
+        }
+    }
+}
+
+/// Runs `scenario` on a fresh cluster with the faults of `config`.
+async fn run<F>(config: SimConfig, scenario: F) -> Result<()>
+where
+    F: AsyncFnOnce(&mut Sim, &Args) -> Result<()>,
+{
+    let _serial = SERIAL.lock().await;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .try_init();
+
+    let args = Args::new();
+    println!("seed {}, {:?}", args.seed, config);
+    let mut sim = Sim::start(&args, config).await?;
+    let result = scenario(&mut sim, &args).await;
+    sim.shutdown().await;
+    result
+}
+
+/// Crash and restart a third of the nodes, three times over
+#[tokio::test(flavor = "multi_thread")]
+async fn churn_keeps_records() -> Result<()> {
+    run(SimConfig::default(), churn).await
+}
+
+/// Split the network in two, write on one side, then heal
+#[tokio::test(flavor = "multi_thread")]
+async fn partition_heals() -> Result<()> {
+    run(SimConfig::default(), partition).await
+}
+
+/// Latency, jitter and connection loss on every link
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_links_keep_records() -> Result<()> {
+    let config = SimConfig {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(20),
+        drop_rate: 0.005,
+    };
+    run(config, lossy).await
+}
+
+async fn churn(sim: &mut Sim, args: &Args) -> Result<()> {
+    let keys = sim.write_keys("churn", args.keys).await?;
+
+    for round in 1..=3 {
+        let mut victims: Vec<usize> = (0..args.nodes).collect();
+        victims.shuffle(&mut sim.rng);
+        victims.truncate(args.nodes / 3);
+        println!("round {}: crashing nodes {:?}", round, victims);
+        for &node in &victims {
+            sim.crash(node).await;
+        }
+        sim.expect_available(&keys, &sim.live()).await?;
+
+        println!("round {}: restarting nodes {:?}", round, victims);
+        for &node in &victims {
+            sim.restart(node).await?;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        // 重启后的节点本地为空，只能从存活的副本读到数据
+        sim.expect_available(&keys, &victims).await?;
+    }
+    Ok(())
+}
+
+async fn partition(sim: &mut Sim, args: &Args) -> Result<()> {
+    let before = sim.write_keys("before", args.keys).await?;
+
+    let half = args.nodes / 2;
+    let left: Vec<usize> = (0..half).collect();
+    let right: Vec<usize> = (half..args.nodes).collect();
+    println!("partitioning into {:?} and {:?}", left, right);
+    sim.net.partition(&[&left, &right]);
+
+    // 分区前写入的记录两边都有副本
+    sim.expect_available(&before, &left).await?;
+    sim.expect_available(&before, &right).await?;
+
+    let mut during = Vec::new();
+    for i in 0..args.keys {
+        let key = format!("during-{}", i);
+        let node = left[i % left.len()];
+        sim.put_with_retries(node, &key).await?;
+        during.push(key);
+    }
+    let leaked = sim
+        .count_available(&during[..5.min(during.len())], &right, 1)
+        .await;
+    if leaked > 0 {
+        bail!(
+            "{} records written on the left side are visible on the right",
+            leaked
+        );
+    }
+    println!("records written during the split are invisible on the other side");
+
+    println!("healing the partition");
+    sim.net.heal();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    sim.expect_available(&during, &right).await?;
+    Ok(())
+}
+
+async fn lossy(sim: &mut Sim, args: &Args) -> Result<()> {
+    let keys = sim.write_keys("lossy", args.keys).await?;
+    sim.expect_available(&keys, &sim.live()).await?;
+    Ok(())
+}
+
+/// A cluster on a simulated network, with a seeded generator for choices.
+struct Sim {
+    net: SimNetwork,
+    keypairs: Vec<Keypair>,
+    nodes: Vec<Option<ClusterNode>>,
+    rng: StdRng,
+}
+
+impl Sim {
+    async fn start(args: &Args, config: SimConfig) -> Result<Self> {
+        let mut sim = Sim {
+            net: SimNetwork::new(args.seed, config),
+            keypairs: (0..args.nodes)
+                .map(|_| Keypair::generate_ed25519())
+                .collect(),
+            nodes: (0..args.nodes).map(|_| None).collect(),
+            rng: StdRng::seed_from_u64(args.seed),
+        };
+        for node in 0..args.nodes {
+            sim.restart(node).await?;
+        }
+        // 给路由表一点时间收敛
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(sim)
+    }
+
+    fn live(&self) -> Vec<usize> {
+        (0..self.nodes.len())
+            .filter(|&n| self.nodes[n].is_some())
+            .collect()
+    }
+
+    async fn shutdown(self) {
+        for running in self.nodes.into_iter().flatten() {
+            running.stop().await;
+        }
+    }
+
+    async fn crash(&mut self, node: usize) {
+        if let Some(running) = self.nodes[node].take() {
+            running.stop().await;
+        }
+    }
+
+    /// Starts `node` with its old identity, bootstrapping from the live nodes.
+    async fn restart(&mut self, node: usize) -> Result<()> {
+        let bootstrap: Vec<_> = self
+            .nodes
+            .iter()
+            .flatten()
+            .map(|n| (n.peer_id, n.addr.clone()))
+            .collect();
+        let swarm = self
+            .net
+            .swarm(node, self.keypairs[node].clone())
+            .map_err(|e| anyhow!(e))?;
+        let running = ClusterNode::spawn(swarm, SimNetwork::addr(node), &bootstrap).await?;
+        self.nodes[node] = Some(running);
+        Ok(())
+    }
+
+    async fn put_with_retries(&mut self, node: usize, key: &str) -> Result<()> {
+        let mut last = None;
+        for _ in 0..ATTEMPTS {
+            let Some(running) = &self.nodes[node] else {
+                bail!("node {} is down", node);
+            };
+            let record = kad::Record::new(kad::RecordKey::new(&key), key.as_bytes().to_vec());
+            match running.put(record, kad::Quorum::One, OP_TIMEOUT).await {
+                Ok(()) => return Ok(()),
+                Err(e) => last = Some(e),
+            }
+        }
+        Err(anyhow!("PUT {} from node {} failed: {:?}", key, node, last))
+    }
+
+    async fn write_keys(&mut self, prefix: &str, count: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::with_capacity(count);
+        for i in 0..count {
+            let key = format!("{}-{}", prefix, i);
+            let node = self.rng.gen_range(0..self.nodes.len());
+            self.put_with_retries(node, &key).await?;
+            keys.push(key);
+        }
+        println!("wrote {} records", keys.len());
+        Ok(keys)
+    }
+
+    /// How many of `keys` can be read from one of `readers`, trying up to
+    /// `attempts` readers per key.
+    async fn count_available(&self, keys: &[String], readers: &[usize], attempts: usize) -> usize {
+        let mut available = 0;
+        for (i, key) in keys.iter().enumerate() {
+            for attempt in 0..attempts {
+                let reader = readers[(i + attempt) % readers.len()];
+                let Some(running) = &self.nodes[reader] else {
+                    continue;
+                };
+                match running.get(kad::RecordKey::new(key), OP_TIMEOUT).await {
+                    Ok(value) if value == key.as_bytes() => {
+                        available += 1;
+                        break;
+                    }
+                    Ok(_) | Err(GatewayError::NotFound) => {}
+                    Err(e) => tracing::debug!("GET {} from node {}: {:?}", key, reader, e),
+                }
+            }
+        }
+        available
+    }
+
+    async fn expect_available(&self, keys: &[String], readers: &[usize]) -> Result<()> {
+        let available = self.count_available(keys, readers, ATTEMPTS).await;
+        println!(
+            "{}/{} records available from nodes {:?}",
+            available,
+            keys.len(),
+            readers
+        );
+        if available < keys.len() {
+            bail!("{} records lost", keys.len() - available);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+//! Optional HTTP admin API of the server (`--admin`), for operators.
+//!
+//! - `GET /namespaces`: `200` with the namespaces and their number of
+//!   registrations as JSON
+//! - `GET /namespaces/{namespace}`: `200` with the registrations in the
+//!   namespace, their addresses and remaining TTL as JSON
+//! - `DELETE /namespaces/{namespace}/{peer}`: evicts a registration, `204` on
+//!   success, `404` if there is none
+//! - `GET /peers`: `200` with the connected peers and their addresses as JSON
+//! - `GET /refused`: `200` with the number of registrations refused by the
+//!   namespace policy per namespace as JSON, see [`crate::policy`]
+//! - `GET /bans`: `200` with the banned peers as JSON
+//! - `PUT /bans/{peer}`: bans a peer, `204`; its connections are closed, its
+//!   registrations dropped and it is refused until unbanned
+//! - `DELETE /bans/{peer}`: lifts a ban, `204`, `404` if the peer was not banned
+//!
+//! Bans are kept in the registration store (`--store`) and outlive restarts;
+//! without a store they last until the server restarts. The API has no
+//! authentication, so bind it to a loopback address.
+
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{delete, get, put},
+    Json, Router,
+};
+use libp2p::{rendezvous::Namespace, PeerId};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tracing::info;
+
+use crate::namespace::{self, NamespaceInfo};
+
+pub type Reply<T> = oneshot::Sender<Result<T, AdminError>>;
+
+/// A request forwarded from the HTTP server to the server's event loop.
+pub enum AdminRequest {
+    Namespaces {
+        reply: Reply<Vec<NamespaceInfo>>,
+    },
+    Registrations {
+        namespace: Namespace,
+        reply: Reply<Vec<RegistrationInfo>>,
+    },
+    Evict {
+        namespace: Namespace,
+        peer: PeerId,
+        reply: Reply<()>,
+    },
+    Peers {
+        reply: Reply<Vec<PeerInfo>>,
+    },
+    Refused {
+        reply: Reply<BTreeMap<String, u64>>,
+    },
+    Bans {
+        reply: Reply<Vec<String>>,
+    },
+    Ban {
+        peer: PeerId,
+        reply: Reply<()>,
+    },
+    Unban {
+        peer: PeerId,
+        reply: Reply<()>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct RegistrationInfo {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+    /// TTL granted at registration, in seconds
+    pub ttl: u64,
+    /// Seconds until the registration expires
+    pub remaining: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PeerInfo {
+    pub peer_id: String,
+    /// Remote addresses of the peer's connections
+    pub addresses: Vec<String>,
+    pub registrations: usize,
+}
+
+#[derive(Debug)]
+pub enum AdminError {
+    NotFound,
+    BadRequest(String),
+    Internal(String),
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            AdminError::NotFound => (StatusCode::NOT_FOUND, "not found".to_string()),
+            AdminError::BadRequest(m) => (StatusCode::BAD_REQUEST, m),
+            AdminError::Internal(m) => (StatusCode::INTERNAL_SERVER_ERROR, m),
+        };
+        (status, message).into_response()
+    }
+}
+
+#[derive(Clone)]
+struct AdminState {
+    requests: mpsc::Sender<AdminRequest>,
+}
+
+impl AdminState {
+    async fn call<T>(
+        &self,
+        request: impl FnOnce(Reply<T>) -> AdminRequest,
+    ) -> Result<T, AdminError> {
+        let (tx, rx) = oneshot::channel();
+        self.requests
+            .send(request(tx))
+            .await
+            .map_err(|_| AdminError::Internal("server is shutting down".to_string()))?;
+        rx.await
+            .map_err(|_| AdminError::Internal("request dropped".to_string()))?
+    }
+}
+
+/// Serves the admin API until the server's request channel is closed.
+pub async fn serve(addr: SocketAddr, requests: mpsc::Sender<AdminRequest>) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/namespaces", get(namespaces))
+        .route("/namespaces/{namespace}", get(registrations))
+        .route("/namespaces/{namespace}/{peer}", delete(evict))
+        .route("/peers", get(peers))
+        .route("/refused", get(refused))
+        .route("/bans", get(bans))
+        .route("/bans/{peer}", put(ban).delete(unban))
+        .with_state(AdminState { requests });
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Admin API listening on {}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn namespaces(
+    State(state): State<AdminState>,
+) -> Result<Json<Vec<NamespaceInfo>>, AdminError> {
+    let namespaces = state
+        .call(|reply| AdminRequest::Namespaces { reply })
+        .await?;
+    Ok(Json(namespaces))
+}
+
+async fn registrations(
+    State(state): State<AdminState>,
+    Path(namespace): Path<String>,
+) -> Result<Json<Vec<RegistrationInfo>>, AdminError> {
+    let namespace = parse_namespace(&namespace)?;
+    let registrations = state
+        .call(|reply| AdminRequest::Registrations { namespace, reply })
+        .await?;
+    Ok(Json(registrations))
+}
+
+async fn evict(
+    State(state): State<AdminState>,
+    Path((namespace, peer)): Path<(String, String)>,
+) -> Result<StatusCode, AdminError> {
+    let namespace = parse_namespace(&namespace)?;
+    let peer = parse_peer(&peer)?;
+    state
+        .call(|reply| AdminRequest::Evict {
+            namespace,
+            peer,
+            reply,
+        })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn peers(State(state): State<AdminState>) -> Result<Json<Vec<PeerInfo>>, AdminError> {
+    let peers = state.call(|reply| AdminRequest::Peers { reply }).await?;
+    Ok(Json(peers))
+}
+
+async fn refused(
+    State(state): State<AdminState>,
+) -> Result<Json<BTreeMap<String, u64>>, AdminError> {
+    let refused = state.call(|reply| AdminRequest::Refused { reply }).await?;
+    Ok(Json(refused))
+}
+
+async fn bans(State(state): State<AdminState>) -> Result<Json<Vec<String>>, AdminError> {
+    let bans = state.call(|reply| AdminRequest::Bans { reply }).await?;
+    Ok(Json(bans))
+}
+
+async fn ban(
+    State(state): State<AdminState>,
+    Path(peer): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let peer = parse_peer(&peer)?;
+    state
+        .call(|reply| AdminRequest::Ban { peer, reply })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unban(
+    State(state): State<AdminState>,
+    Path(peer): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    let peer = parse_peer(&peer)?;
+    state
+        .call(|reply| AdminRequest::Unban { peer, reply })
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn parse_namespace(namespace: &str) -> Result<Namespace, AdminError> {
+    namespace::parse(namespace).map_err(AdminError::BadRequest)
+}
+
+fn parse_peer(peer: &str) -> Result<PeerId, AdminError> {
+    peer.parse()
+        .map_err(|_| AdminError::BadRequest(format!("invalid peer id {:?}", peer)))
+}
@@ -0,0 +1,120 @@
+//! Network behaviours of the server and the clients, and the swarm they run in.
+
+use std::time::Duration;
+
+use libp2p::{
+    allow_block_list::{self, BlockedPeers},
+    identify,
+    identity::Keypair,
+    noise, ping, rendezvous,
+    request_response::{self, ProtocolSupport},
+    swarm::NetworkBehaviour,
+    tcp, yamux, Swarm, SwarmBuilder,
+};
+
+use crate::{
+    capability::{self, PresentTokens, TokenResults},
+    federation::{self, FederationRequest, FederationResponse},
+    namespace::{self, ListNamespaces, NamespaceInfo},
+    protocol,
+};
+
+/// Identify protocol version of the server and the clients
+pub const PROTOCOL_VERSION: &str = "rendezvous-exp/1.0.0";
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(NetworkBehaviour)]
+pub struct ServerBehaviour {
+    pub identify: identify::Behaviour,
+    /// Answers the rendezvous protocol, see [`protocol`]
+    pub rendezvous: request_response::Behaviour<protocol::Codec>,
+    /// Answers `ListNamespaces`, see [`namespace`]
+    pub namespaces: request_response::json::Behaviour<ListNamespaces, Vec<NamespaceInfo>>,
+    /// Takes capability tokens, see [`capability`]
+    pub capabilities: request_response::json::Behaviour<PresentTokens, TokenResults>,
+    /// Shares registrations with other servers, see [`federation`]
+    pub federation: request_response::json::Behaviour<FederationRequest, FederationResponse>,
+    pub ping: ping::Behaviour,
+    /// Peers banned through the admin API, see [`crate::admin`]
+    pub blocked: allow_block_list::Behaviour<BlockedPeers>,
+}
+
+impl ServerBehaviour {
+    pub fn new(key: &Keypair) -> Self {
+        Self {
+            identify: identify(key),
+            rendezvous: request_response::Behaviour::with_codec(
+                protocol::Codec,
+                [(protocol::PROTOCOL, ProtocolSupport::Inbound)],
+                request_response::Config::default(),
+            ),
+            namespaces: request_response::json::Behaviour::new(
+                [(namespace::PROTOCOL, ProtocolSupport::Inbound)],
+                request_response::Config::default(),
+            ),
+            capabilities: request_response::json::Behaviour::new(
+                [(capability::PROTOCOL, ProtocolSupport::Inbound)],
+                request_response::Config::default(),
+            ),
+            federation: request_response::json::Behaviour::new(
+                [(federation::PROTOCOL, ProtocolSupport::Full)],
+                request_response::Config::default(),
+            ),
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(PING_INTERVAL)),
+            blocked: allow_block_list::Behaviour::default(),
+        }
+    }
+}
+
+#[derive(NetworkBehaviour)]
+pub struct ClientBehaviour {
+    pub identify: identify::Behaviour,
+    pub rendezvous: rendezvous::client::Behaviour,
+    pub namespaces: request_response::json::Behaviour<ListNamespaces, Vec<NamespaceInfo>>,
+    pub capabilities: request_response::json::Behaviour<PresentTokens, TokenResults>,
+    pub ping: ping::Behaviour,
+}
+
+impl ClientBehaviour {
+    pub fn new(key: &Keypair) -> Self {
+        Self {
+            identify: identify(key),
+            rendezvous: rendezvous::client::Behaviour::new(key.clone()),
+            namespaces: request_response::json::Behaviour::new(
+                [(namespace::PROTOCOL, ProtocolSupport::Outbound)],
+                request_response::Config::default(),
+            ),
+            capabilities: request_response::json::Behaviour::new(
+                [(capability::PROTOCOL, ProtocolSupport::Outbound)],
+                request_response::Config::default(),
+            ),
+            ping: ping::Behaviour::new(ping::Config::new().with_interval(PING_INTERVAL)),
+        }
+    }
+}
+
+fn identify(key: &Keypair) -> identify::Behaviour {
+    identify::Behaviour::new(identify::Config::new(
+        PROTOCOL_VERSION.to_string(),
+        key.public(),
+    ))
+}
+
+/// A TCP/noise/yamux swarm on tokio running `behaviour`.
+pub fn build_swarm<B: NetworkBehaviour>(
+    keypair: Keypair,
+    idle_timeout: Duration,
+    behaviour: impl FnOnce(&Keypair) -> B,
+) -> anyhow::Result<Swarm<B>> {
+    Ok(SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_behaviour(behaviour)?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(idle_timeout))
+        .build())
+}
@@ -0,0 +1,214 @@
+use std::{collections::HashMap, time::Duration};
+
+use libp2p::{
+    multiaddr::Protocol, ping, rendezvous, request_response, swarm::SwarmEvent, Multiaddr, PeerId,
+    Swarm,
+};
+use rzv::{
+    behaviour::{build_swarm, ClientBehaviour, ClientBehaviourEvent},
+    namespace::{self, ListNamespaces},
+    point::Point,
+};
+use tracing::{debug, info, warn};
+
+use crate::ClientArgs;
+
+/// A rendezvous point discovered from, with a cookie per namespace.
+struct Source {
+    point: Point,
+    connected: bool,
+    dialing: bool,
+    cookies: Vec<(rendezvous::Namespace, Option<rendezvous::Cookie>)>,
+}
+
+impl Source {
+    fn discover(&self, swarm: &mut Swarm<ClientBehaviour>) {
+        for (namespace, cookie) in &self.cookies {
+            swarm.behaviour_mut().rendezvous.discover(
+                Some(namespace.clone()),
+                cookie.clone(),
+                None,
+                self.point.peer_id,
+            );
+        }
+    }
+}
+
+pub async fn run(
+    args: ClientArgs,
+    idle_timeout: Duration,
+    interval: Duration,
+) -> anyhow::Result<()> {
+    let mut sources: Vec<Source> = args
+        .point
+        .resolve()?
+        .into_iter()
+        .map(|point| Source {
+            point,
+            connected: false,
+            dialing: false,
+            cookies: args
+                .namespaces
+                .iter()
+                .map(|ns| (ns.clone(), None))
+                .collect(),
+        })
+        .collect();
+    let mut swarm = build_swarm(args.keypair()?, idle_timeout, ClientBehaviour::new)?;
+
+    if let Some(listen) = args.listen {
+        swarm.listen_on(listen)?;
+    }
+
+    // 每轮向所有已连接的服务器查询，连不上的在下一轮重新拨号
+    let mut discover_tick = tokio::time::interval(interval);
+    // 已拨号的节点记录序号，多个服务器返回同一条记录时只拨号一次
+    let mut dialed: HashMap<PeerId, u64> = HashMap::new();
+    let mut listed = false;
+
+    loop {
+        tokio::select! {
+            event = futures::StreamExt::select_next_some(&mut swarm) => match event {
+                SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                    let Some(source) = sources.iter_mut().find(|s| s.point.peer_id == peer_id) else {
+                        continue;
+                    };
+                    source.connected = true;
+                    source.dialing = false;
+                    if !listed {
+                        swarm
+                            .behaviour_mut()
+                            .namespaces
+                            .send_request(&peer_id, ListNamespaces);
+                    }
+                    info!(
+                        "Connected to rendezvous point {}, discovering nodes in {} namespaces ...",
+                        peer_id,
+                        source.cookies.len()
+                    );
+                    source.discover(&mut swarm);
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    num_established: 0,
+                    cause,
+                    ..
+                } => {
+                    let Some(source) = sources.iter_mut().find(|s| s.point.peer_id == peer_id) else {
+                        continue;
+                    };
+                    source.connected = false;
+                    if let Some(error) = cause {
+                        warn!(
+                            "Lost rendezvous point {} ({}), discovering from the others",
+                            peer_id, error
+                        );
+                    }
+                }
+                SwarmEvent::OutgoingConnectionError {
+                    peer_id: Some(peer_id),
+                    error,
+                    ..
+                } => {
+                    let Some(source) = sources.iter_mut().find(|s| s.point.peer_id == peer_id) else {
+                        continue;
+                    };
+                    source.dialing = false;
+                    warn!("Failed to reach rendezvous point {}: {}", peer_id, error);
+                }
+                SwarmEvent::Behaviour(ClientBehaviourEvent::Namespaces(
+                    request_response::Event::Message {
+                        message: request_response::Message::Response { response, .. },
+                        ..
+                    },
+                )) => {
+                    listed = true;
+                    namespace::check(&args.namespaces, &response);
+                }
+                SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                    rendezvous::client::Event::Discovered {
+                        rendezvous_node,
+                        registrations,
+                        cookie: new_cookie,
+                    },
+                )) => {
+                    if let Some((_, cookie)) = sources
+                        .iter_mut()
+                        .find(|s| s.point.peer_id == rendezvous_node)
+                        .and_then(|s| {
+                            s.cookies
+                                .iter_mut()
+                                .find(|(ns, _)| Some(ns) == new_cookie.namespace())
+                        })
+                    {
+                        cookie.replace(new_cookie);
+                    }
+                    for reg in registrations {
+                        let peer = reg.record.peer_id();
+                        let seq = reg.record.seq();
+                        if dialed.get(&peer).is_some_and(|&dialed| dialed >= seq) {
+                            debug!("Peer {} in '{}' is known already", peer, reg.namespace);
+                            continue;
+                        }
+                        dialed.insert(peer, seq);
+                        for addr in reg.record.addresses() {
+                            info!(
+                                "Discovered peer {:?} in '{}' at {:?} via {}",
+                                peer, reg.namespace, addr, rendezvous_node
+                            );
+
+                            let p2p_suffix = Protocol::P2p(peer);
+                            let address_with_p2p =
+                                if !addr.ends_with(&Multiaddr::empty().with(p2p_suffix.clone())) {
+                                    addr.clone().with(p2p_suffix)
+                                } else {
+                                    addr.clone()
+                                };
+
+                            if let Err(e) = swarm.dial(address_with_p2p) {
+                                warn!("Failed to dial {}: {}", peer, e);
+                            }
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                    rendezvous::client::Event::DiscoverFailed {
+                        rendezvous_node,
+                        namespace,
+                        error,
+                    },
+                )) => {
+                    warn!(
+                        "Failed to discover {:?} at {}: {:?}",
+                        namespace, rendezvous_node, error
+                    );
+                }
+                SwarmEvent::Behaviour(ClientBehaviourEvent::Ping(ping::Event {
+                    peer,
+                    result: Ok(rtt), // RTT: round-trip time - 往返时间
+                    ..
+                })) if !sources.iter().any(|s| s.point.peer_id == peer) => {
+                    info!("Ping to {} successful in {:?}ms", peer, rtt.as_millis());
+                }
+                other => {
+                    debug!("unhandled {:?}", other);
+                }
+            },
+            _ = discover_tick.tick() => {
+                for source in &mut sources {
+                    if source.connected {
+                        source.discover(&mut swarm);
+                    } else if !source.dialing {
+                        // 上一次拨号还没有结果时不重复拨号
+                        match swarm.dial(source.point.dial_opts()) {
+                            Ok(()) => source.dialing = true,
+                            Err(e) => {
+                                debug!("Not dialing rendezvous point {}: {}", source.point.peer_id, e)
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{identify, ping, swarm::SwarmEvent};
+use rzv::{
+    behaviour::{build_swarm, ClientBehaviour, ClientBehaviourEvent},
+    registrar::Registrars,
+};
+use tracing::{debug, info};
+
+use crate::{register::far_future, RegisterArgs};
+
+pub async fn run(args: RegisterArgs, idle_timeout: Duration) -> anyhow::Result<()> {
+    let points = args.client.point.resolve()?;
+    let mut swarm = build_swarm(args.client.keypair()?, idle_timeout, ClientBehaviour::new)?;
+
+    swarm.listen_on(
+        args.client
+            .listen
+            .unwrap_or_else(|| "/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")),
+    )?;
+
+    // 等 identify 报告观察到的地址后才注册
+    let mut registrars = Registrars::new(
+        points,
+        args.points,
+        args.client.namespaces,
+        args.ttl,
+        args.tokens,
+    )?;
+
+    loop {
+        let deadline = registrars.deadline(&swarm);
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                registrars.on_event(&mut swarm, &event)?;
+                match event {
+                    SwarmEvent::NewListenAddr {
+                        listener_id,
+                        address,
+                    } => {
+                        info!("Listening on {} {}", listener_id, address)
+                    }
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Identify(identify::Event::Received {
+                        peer_id,
+                        info,
+                        ..
+                    })) if registrars.is_point(&peer_id) => {
+                        // Register our external address. Needs to be done explicitly
+                        // for this case, as it's a local address.
+                        swarm.add_external_address(info.observed_addr);
+                        registrars.run_due(&mut swarm)?;
+                    }
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Ping(ping::Event {
+                        peer,
+                        result: Ok(rtt),
+                        ..
+                    })) => {
+                        info!("Ping to {} is {}ms", peer, rtt.as_millis())
+                    }
+                    other => {
+                        debug!("Unhandled {:?}", other);
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(far_future)), if deadline.is_some() => {
+                registrars.run_due(&mut swarm)?;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                registrars.shutdown(&mut swarm).await;
+                return Ok(());
+            }
+        }
+    }
+}
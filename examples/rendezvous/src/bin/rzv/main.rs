@@ -0,0 +1,220 @@
+//! `rzv`: the rendezvous server and its clients in one tool.
+//!
+//! ```text
+//! rzv server --export-addr rendezvous.addr --store registrations.json --admin 127.0.0.1:8081
+//! rzv server --listen /ip4/0.0.0.0/tcp/64338 --federate /ip4/10.0.0.1/tcp/64337/p2p/12D3KooW...
+//! rzv register --rendezvous-file rendezvous.addr
+//! rzv token --admin-key admin.key --namespace chat --peer 12D3KooW...
+//! rzv register --rendezvous-file rendezvous.addr --key-file peer.key --namespace chat --token ...
+//! rzv register --rendezvous /ip4/10.0.0.1/tcp/64337/p2p/12D3KooW... --rendezvous /ip4/10.0.0.2/tcp/64337/p2p/12D3KooW... --points 1
+//! rzv identify --rendezvous-file rendezvous.addr
+//! rzv discover --rendezvous-file rendezvous.addr --namespace chat --namespace files
+//! rzv namespaces --rendezvous-file rendezvous.addr
+//! ```
+
+mod discover;
+mod identify;
+mod namespaces;
+mod register;
+mod token;
+
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use clap::{Args, Parser, Subcommand};
+use libp2p::{identity::Keypair, rendezvous, Multiaddr, PeerId};
+use rzv::{
+    keyfile,
+    logging::{self, LogFormat},
+    namespace::{self, DEFAULT_NAMESPACE},
+    point::PointArgs,
+    registry::Limits,
+    server::{self, ServerOptions},
+};
+
+#[derive(Parser, Debug)]
+#[command(about = "Rendezvous server and clients for libp2p peers")]
+struct Cli {
+    /// Format of the log output
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+    /// Seconds an idle connection is kept open
+    #[arg(long, default_value_t = 5, global = true)]
+    idle_timeout: u64,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Runs a rendezvous point
+    Server {
+        /// Address to listen on
+        #[arg(long, default_value = "/ip4/0.0.0.0/tcp/64337")]
+        listen: Multiaddr,
+        /// Key file of the server's identity, generated on first start
+        #[arg(long, default_value = "rendezvous.key")]
+        key_file: PathBuf,
+        /// Writes the server's full addresses (with /p2p/) to this file for the clients
+        #[arg(long, value_name = "PATH")]
+        export_addr: Option<PathBuf>,
+        /// File the registrations are kept in across restarts
+        #[arg(long, default_value = "registrations.json")]
+        store: PathBuf,
+        /// Keeps the registrations in memory only
+        #[arg(long, conflicts_with = "store")]
+        no_store: bool,
+        #[command(flatten)]
+        limits: Limits,
+        /// Policy file restricting who may register in which namespace
+        #[arg(long, value_name = "PATH")]
+        policy: Option<PathBuf>,
+        /// Serves the admin API on this address, e.g. 127.0.0.1:8081
+        #[arg(long, value_name = "ADDR")]
+        admin: Option<SocketAddr>,
+        /// Another rendezvous point (with /p2p/) to share registrations with,
+        /// may be given more than once
+        #[arg(long = "federate", value_name = "MULTIADDR")]
+        federation: Vec<Multiaddr>,
+    },
+    /// Registers this peer at rendezvous points under its external addresses,
+    /// renews the registrations until interrupted and then unregisters
+    Register {
+        #[command(flatten)]
+        args: RegisterArgs,
+        /// Address to register, may be given more than once
+        #[arg(long = "external-addr", default_value = "/ip4/127.0.0.1/tcp/0")]
+        external_addrs: Vec<Multiaddr>,
+    },
+    /// Like `register`, under the addresses the rendezvous points observe
+    Identify {
+        #[command(flatten)]
+        args: RegisterArgs,
+    },
+    /// Discovers the peers registered at rendezvous points and pings them
+    Discover {
+        #[command(flatten)]
+        client: ClientArgs,
+        /// Seconds between discoveries
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+    },
+    /// Lists the namespaces a rendezvous point holds registrations in, asking
+    /// the next one if it cannot be reached
+    Namespaces {
+        #[command(flatten)]
+        point: PointArgs,
+    },
+    /// Issues a capability token allowing a peer to register in a namespace
+    Token {
+        /// Key file of the admin, generated on first use
+        #[arg(long, default_value = "admin.key")]
+        admin_key: PathBuf,
+        /// Namespace the token is for
+        #[arg(long, value_parser = namespace::parse)]
+        namespace: rendezvous::Namespace,
+        /// Peer the token is issued to
+        #[arg(long)]
+        peer: PeerId,
+        /// Seconds the token is valid, forever if not given
+        #[arg(long)]
+        valid_for: Option<u64>,
+    },
+}
+
+/// Flags shared by the clients.
+#[derive(Args, Debug)]
+struct ClientArgs {
+    #[command(flatten)]
+    point: PointArgs,
+    /// Namespace to register in or discover, may be given more than once
+    #[arg(
+        long = "namespace",
+        default_value = DEFAULT_NAMESPACE,
+        value_parser = namespace::parse,
+    )]
+    namespaces: Vec<rendezvous::Namespace>,
+    /// Address to listen on
+    #[arg(long)]
+    listen: Option<Multiaddr>,
+    /// Key file of this peer's identity, generated on first use; a new
+    /// identity every run if not given
+    #[arg(long, value_name = "PATH")]
+    key_file: Option<PathBuf>,
+}
+
+impl ClientArgs {
+    fn keypair(&self) -> anyhow::Result<Keypair> {
+        let keypair = match &self.key_file {
+            Some(path) => keyfile::load_or_generate(path)?,
+            None => Keypair::generate_ed25519(),
+        };
+        tracing::info!("Local peer id: {}", keypair.public().to_peer_id());
+        Ok(keypair)
+    }
+}
+
+/// Flags shared by the registering clients.
+#[derive(Args, Debug)]
+struct RegisterArgs {
+    #[command(flatten)]
+    client: ClientArgs,
+    /// Seconds the registration lasts, the server's default if not given
+    #[arg(long)]
+    ttl: Option<u64>,
+    /// Capability token for a restricted namespace, may be given more than once
+    #[arg(long = "token")]
+    tokens: Vec<String>,
+    /// Number of rendezvous points to stay registered at, all of them if not
+    /// given; the others stand by in case one fails
+    #[arg(long)]
+    points: Option<usize>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    logging::init(cli.log_format);
+    let idle_timeout = Duration::from_secs(cli.idle_timeout);
+
+    match cli.command {
+        Command::Server {
+            listen,
+            key_file,
+            export_addr,
+            store,
+            no_store,
+            limits,
+            policy,
+            admin,
+            federation,
+        } => {
+            server::run(ServerOptions {
+                listen,
+                key_file,
+                export_addr,
+                store: (!no_store).then_some(store),
+                limits,
+                policy,
+                admin,
+                federation,
+                idle_timeout,
+            })
+            .await
+        }
+        Command::Register {
+            args,
+            external_addrs,
+        } => register::run(args, idle_timeout, external_addrs).await,
+        Command::Identify { args } => identify::run(args, idle_timeout).await,
+        Command::Discover { client, interval } => {
+            discover::run(client, idle_timeout, Duration::from_secs(interval)).await
+        }
+        Command::Namespaces { point } => namespaces::run(point, idle_timeout).await,
+        Command::Token {
+            admin_key,
+            namespace,
+            peer,
+            valid_for,
+        } => token::run(&admin_key, &namespace, peer, valid_for),
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use anyhow::bail;
+use futures::StreamExt;
+use libp2p::{identity::Keypair, request_response, swarm::SwarmEvent, PeerId, Swarm};
+use rzv::{
+    behaviour::{build_swarm, ClientBehaviour, ClientBehaviourEvent},
+    namespace::ListNamespaces,
+    point::{Point, PointArgs},
+};
+use tracing::{debug, info, warn};
+
+pub async fn run(point: PointArgs, idle_timeout: Duration) -> anyhow::Result<()> {
+    let mut points = point.resolve()?.into_iter();
+    let mut swarm = build_swarm(
+        Keypair::generate_ed25519(),
+        idle_timeout,
+        ClientBehaviour::new,
+    )?;
+    // 依次尝试各个服务器，直到有一个应答
+    let mut current = next_point(&mut swarm, &mut points, None)?;
+
+    while let Some(event) = swarm.next().await {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == current => {
+                swarm
+                    .behaviour_mut()
+                    .namespaces
+                    .send_request(&current, ListNamespaces);
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Namespaces(
+                request_response::Event::Message {
+                    message: request_response::Message::Response { response, .. },
+                    ..
+                },
+            )) => {
+                if response.is_empty() {
+                    println!("No registrations");
+                }
+                for info in response {
+                    println!("{}\t{} registrations", info.name, info.registrations);
+                }
+                return Ok(());
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Namespaces(
+                request_response::Event::OutboundFailure { peer, error, .. },
+            )) if peer == current => {
+                warn!("Failed to list the namespaces at {}: {}", peer, error);
+                current = next_point(&mut swarm, &mut points, Some(current))?;
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } if peer_id == current => {
+                warn!("Failed to reach rendezvous point {}: {}", peer_id, error);
+                current = next_point(&mut swarm, &mut points, Some(current))?;
+            }
+            other => debug!("Unhandled {:?}", other),
+        }
+    }
+    Ok(())
+}
+
+/// Dials the next of `points`; fails once all were tried.
+fn next_point(
+    swarm: &mut Swarm<ClientBehaviour>,
+    points: &mut impl Iterator<Item = Point>,
+    failed: Option<PeerId>,
+) -> anyhow::Result<PeerId> {
+    let Some(point) = points.next() else {
+        bail!("None of the rendezvous points could list the namespaces");
+    };
+    if let Some(failed) = failed {
+        info!(
+            "Asking rendezvous point {} instead of {}",
+            point.peer_id, failed
+        );
+    }
+    swarm.dial(point.dial_opts())?;
+    Ok(point.peer_id)
+}
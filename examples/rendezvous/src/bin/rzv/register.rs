@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{ping, swarm::SwarmEvent, Multiaddr};
+use rzv::{
+    behaviour::{build_swarm, ClientBehaviour, ClientBehaviourEvent},
+    registrar::Registrars,
+};
+use tracing::{debug, info};
+
+use crate::RegisterArgs;
+
+pub async fn run(
+    args: RegisterArgs,
+    idle_timeout: Duration,
+    external_addrs: Vec<Multiaddr>,
+) -> anyhow::Result<()> {
+    let points = args.client.point.resolve()?;
+    let mut swarm = build_swarm(args.client.keypair()?, idle_timeout, ClientBehaviour::new)?;
+
+    if let Some(listen) = args.client.listen {
+        swarm.listen_on(listen)?;
+    }
+    // In production the external address should be the publicly facing IP address of the rendezvous
+    // point. This address is recorded in the registration entry by the rendezvous point.
+    for addr in external_addrs {
+        swarm.add_external_address(addr);
+    }
+
+    let mut registrars = Registrars::new(
+        points,
+        args.points,
+        args.client.namespaces,
+        args.ttl,
+        args.tokens,
+    )?;
+
+    loop {
+        let deadline = registrars.deadline(&swarm);
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                registrars.on_event(&mut swarm, &event)?;
+                match event {
+                    SwarmEvent::NewListenAddr {
+                        listener_id,
+                        address,
+                    } => {
+                        info!("Listening on {} {}", listener_id, address)
+                    }
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Ping(ping::Event {
+                        peer,
+                        result: Ok(rtt),
+                        ..
+                    })) if !registrars.is_point(&peer) => {
+                        info!("Ping to {} is {}ms", peer, rtt.as_millis())
+                    }
+                    other => {
+                        debug!("Unhandled {:?}", other);
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(far_future)), if deadline.is_some() => {
+                registrars.run_due(&mut swarm)?;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                registrars.shutdown(&mut swarm).await;
+                return Ok(());
+            }
+        }
+    }
+}
+
+pub fn far_future() -> tokio::time::Instant {
+    tokio::time::Instant::now() + Duration::from_secs(24 * 60 * 60)
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+use libp2p::{rendezvous::Namespace, PeerId};
+use rzv::{capability, keyfile, registry::now_millis};
+
+/// Prints a token allowing `peer` to register in `namespace`, and nothing
+/// else on stdout so that it can be captured.
+pub fn run(
+    admin_key: &Path,
+    namespace: &Namespace,
+    peer: PeerId,
+    valid_for: Option<u64>,
+) -> anyhow::Result<()> {
+    let admin = keyfile::load_or_generate(admin_key)?;
+    eprintln!(
+        "Issuing with admin key {}, list it under \"admins\" in the server's policy",
+        admin.public().to_peer_id()
+    );
+    let expires = valid_for.map(|secs| (now_millis() / 1000).saturating_add(secs));
+    println!("{}", capability::issue(&admin, namespace, peer, expires)?);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! Capability tokens: an admin's permission for one peer to register in one
+//! namespace.
+//!
+//! A token is a [`SignedEnvelope`] made with an admin key (`rzv token`) over
+//! the namespace, the peer and an optional expiry time, passed around base64
+//! encoded. Registering clients present their tokens (`--token`) over
+//! [`PROTOCOL`] whenever they connect and before they register; the server
+//! keeps those signed by one of its policy's admins, see [`crate::policy`].
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use libp2p::{
+    core::SignedEnvelope, identity::Keypair, rendezvous::Namespace, PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{namespace, registry::now_millis};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/rzv/capability/1.0.0");
+
+const DOMAIN: &str = "rzv-capability";
+const PAYLOAD_TYPE: &[u8] = b"/rzv/capability";
+
+/// Request presenting a client's tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresentTokens(pub Vec<String>);
+
+/// Outcome of each presented token, in order: the namespace it grants, or
+/// why it was refused.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenResults(pub Vec<Result<String, String>>);
+
+#[derive(Serialize, Deserialize)]
+struct Payload {
+    namespace: String,
+    peer_id: String,
+    /// Unix timestamp in seconds
+    expires: Option<u64>,
+}
+
+/// A verified token.
+#[derive(Debug, Clone)]
+pub struct Capability {
+    pub namespace: Namespace,
+    pub peer: PeerId,
+    /// Unix timestamp in seconds, `None` if the token does not expire
+    pub expires: Option<u64>,
+    /// Peer id of the admin key that signed the token
+    pub issuer: PeerId,
+}
+
+impl Capability {
+    pub fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|t| t.saturating_mul(1000) <= now_millis())
+    }
+}
+
+/// Signs a token allowing `peer` to register in `namespace` until `expires`.
+pub fn issue(
+    admin: &Keypair,
+    namespace: &Namespace,
+    peer: PeerId,
+    expires: Option<u64>,
+) -> anyhow::Result<String> {
+    let payload = Payload {
+        namespace: namespace.to_string(),
+        peer_id: peer.to_string(),
+        expires,
+    };
+    let envelope = SignedEnvelope::new(
+        admin,
+        DOMAIN.to_string(),
+        PAYLOAD_TYPE.to_vec(),
+        serde_json::to_vec(&payload)?,
+    )
+    .context("signing the token")?;
+    Ok(BASE64.encode(envelope.into_protobuf_encoding()))
+}
+
+/// Decodes `token` and checks its signature; whether the issuer is an admin
+/// and the token has not expired is up to the caller.
+pub fn verify(token: &str) -> Result<Capability, String> {
+    let bytes = BASE64
+        .decode(token.trim())
+        .map_err(|_| "token is not base64".to_string())?;
+    let envelope = SignedEnvelope::from_protobuf_encoding(&bytes).map_err(|e| e.to_string())?;
+    let (payload, key) = envelope
+        .payload_and_signing_key(DOMAIN.to_string(), PAYLOAD_TYPE)
+        .map_err(|e| e.to_string())?;
+    let payload: Payload =
+        serde_json::from_slice(payload).map_err(|e| format!("invalid token: {}", e))?;
+    Ok(Capability {
+        namespace: namespace::parse(&payload.namespace)?,
+        peer: payload
+            .peer_id
+            .parse()
+            .map_err(|_| format!("invalid peer id {:?}", payload.peer_id))?,
+        expires: payload.expires,
+        issuer: key.to_peer_id(),
+    })
+}
@@ -0,0 +1,241 @@
+//! Federation of rendezvous points.
+//!
+//! Servers started with `--federate` for each other member share the
+//! registrations made at them over [`PROTOCOL`], so that a peer registered at
+//! one member is discovered at all of them:
+//!
+//! - every registration, renewal and unregistration made at a server is
+//!   pushed to the other members as it happens
+//! - when a member connects, and every [`SYNC_INTERVAL`], a server pushes all
+//!   registrations made at it, which catches up members that were down
+//!
+//! Replicas are not passed on, so every member has to list all the others.
+//! Members trust each other: replicas skip the namespace policy, but requests
+//! of other peers are refused. Each member still holds replicas to its own
+//! limits: their TTL is cut to its `--max-ttl`, new ones have to fit its
+//! quotas and those of peers banned there are refused, see
+//! [`crate::registry`].
+//!
+//! A peer may be registered at several members, or renew at another member
+//! than the one it registered at. A replica replaces what a server holds for
+//! the same peer and namespace only if its peer record is newer, or the same
+//! record expires clearly later, so a refresh that arrives late or twice never
+//! rolls a registration back and the latest renewal wins everywhere. An
+//! unregistration only removes the replicas of the member it was made at; if
+//! it is lost, the replicas expire with their TTL.
+
+use std::time::Duration;
+
+use libp2p::{rendezvous::Ttl, Multiaddr, PeerId, StreamProtocol, Swarm};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    behaviour::ServerBehaviour,
+    namespace, point,
+    registry::{self, now_millis, Entry, Registry},
+};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/rzv/federation/1.0.0");
+
+/// How often all registrations made here are pushed to the other members
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Replicas per request at most, to stay below the request size limit
+const MAX_BATCH: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum FederationRequest {
+    /// Registrations made or renewed at the sender
+    Registered(Vec<Replica>),
+    /// Registrations removed at the sender
+    Unregistered(Vec<Removal>),
+}
+
+/// Number of registrations taken, or why the request was refused.
+pub type FederationResponse = Result<usize, String>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replica {
+    pub namespace: String,
+    /// Signed envelope of the peer record, base64 encoded
+    pub record: String,
+    pub ttl: Ttl,
+    /// Milliseconds until the registration expires, so that the members'
+    /// clocks need not agree
+    pub remaining_ms: u64,
+}
+
+impl Replica {
+    pub fn new(entry: &Entry) -> Self {
+        Self {
+            namespace: entry.namespace.to_string(),
+            record: registry::encode_record(&entry.record),
+            ttl: entry.ttl,
+            remaining_ms: entry.expires.saturating_sub(now_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Removal {
+    pub namespace: String,
+    pub peer_id: String,
+}
+
+/// The other members and the updates waiting to be pushed to them.
+#[derive(Debug, Default)]
+pub struct Federation {
+    members: Vec<(PeerId, Multiaddr)>,
+    outbox: Vec<FederationRequest>,
+}
+
+impl Federation {
+    /// Members given as addresses ending in `/p2p/<peer id>`.
+    pub fn new(members: Vec<Multiaddr>) -> anyhow::Result<Self> {
+        let members = members
+            .into_iter()
+            .map(|addr| Ok((point::peer_id(&addr)?, addr)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            members,
+            outbox: Vec::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn is_member(&self, peer: &PeerId) -> bool {
+        self.members.iter().any(|(p, _)| p == peer)
+    }
+
+    /// Queues a registration made or renewed here.
+    pub fn registered(&mut self, entry: &Entry) {
+        if !self.is_empty() && entry.origin.is_none() {
+            self.outbox
+                .push(FederationRequest::Registered(vec![Replica::new(entry)]));
+        }
+    }
+
+    /// Queues the removal of a registration made here.
+    pub fn unregistered(&mut self, entry: &Entry) {
+        if !self.is_empty() && entry.origin.is_none() {
+            self.outbox
+                .push(FederationRequest::Unregistered(vec![Removal {
+                    namespace: entry.namespace.to_string(),
+                    peer_id: entry.peer().to_string(),
+                }]));
+        }
+    }
+
+    /// Pushes the queued updates to every member, dialing those that are not
+    /// connected.
+    pub fn flush(&mut self, swarm: &mut Swarm<ServerBehaviour>) {
+        for request in std::mem::take(&mut self.outbox) {
+            for (peer, addr) in &self.members {
+                send(swarm, *peer, addr, request.clone());
+            }
+        }
+    }
+
+    /// Pushes all registrations made here to `member`, or to every member.
+    pub fn sync(
+        &self,
+        swarm: &mut Swarm<ServerBehaviour>,
+        registry: &Registry,
+        member: Option<PeerId>,
+    ) {
+        let replicas: Vec<Replica> = registry
+            .iter()
+            .filter(|e| e.origin.is_none())
+            .map(Replica::new)
+            .collect();
+        for (peer, addr) in &self.members {
+            if member.is_some_and(|m| m != *peer) {
+                continue;
+            }
+            if replicas.is_empty() {
+                // 没有本地注册时也联系一下，让对方在连接建立时推送它的注册
+                send(
+                    swarm,
+                    *peer,
+                    addr,
+                    FederationRequest::Registered(Vec::new()),
+                );
+            }
+            for batch in replicas.chunks(MAX_BATCH) {
+                send(
+                    swarm,
+                    *peer,
+                    addr,
+                    FederationRequest::Registered(batch.to_vec()),
+                );
+            }
+        }
+    }
+
+    /// Applies a request of the member `origin`.
+    pub fn apply(
+        &self,
+        registry: &mut Registry,
+        origin: PeerId,
+        request: FederationRequest,
+    ) -> FederationResponse {
+        if !self.is_member(&origin) {
+            return Err("not a federation member".to_string());
+        }
+        let mut taken = 0;
+        match request {
+            FederationRequest::Registered(replicas) => {
+                for replica in replicas {
+                    let namespace = namespace::parse(&replica.namespace)?;
+                    let record =
+                        registry::decode_record(&replica.record).map_err(|e| e.to_string())?;
+                    if registry.replicate(
+                        origin,
+                        namespace,
+                        record,
+                        replica.ttl,
+                        replica.remaining_ms,
+                    ) {
+                        taken += 1;
+                    }
+                }
+            }
+            FederationRequest::Unregistered(removals) => {
+                for removal in removals {
+                    let namespace = namespace::parse(&removal.namespace)?;
+                    let peer: PeerId = removal
+                        .peer_id
+                        .parse()
+                        .map_err(|_| format!("invalid peer id {:?}", removal.peer_id))?;
+                    if registry.remove_replica(origin, &namespace, peer) {
+                        taken += 1;
+                    }
+                }
+            }
+        }
+        Ok(taken)
+    }
+}
+
+fn send(
+    swarm: &mut Swarm<ServerBehaviour>,
+    peer: PeerId,
+    addr: &Multiaddr,
+    request: FederationRequest,
+) {
+    // 拨号失败时 request_response 会丢弃地址，每次发送前重新告知
+    swarm.add_peer_address(peer, addr.clone());
+    let id = swarm
+        .behaviour_mut()
+        .federation
+        .send_request(&peer, request);
+    debug!("Pushing {:?} to federation member {}", id, peer);
+}
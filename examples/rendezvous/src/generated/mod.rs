@@ -0,0 +1,9 @@
+//! Protobuf messages of the rendezvous protocol, see [`crate::protocol`].
+//!
+//! `rendezvous.proto` and the `pb-rs` output `rendezvous.rs` are copied from
+//! `protocols/rendezvous/src/generated` of libp2p-rendezvous 0.15.0, the
+//! version libp2p 0.54.1 depends on, since that crate does not export them.
+//! Keep them in step with that crate when upgrading libp2p.
+
+// Automatically generated mod.rs
+pub mod rendezvous;
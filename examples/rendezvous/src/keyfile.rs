@@ -0,0 +1,107 @@
+//! The rendezvous server's identity.
+//!
+//! The server used to derive its key from an all-zero seed, so anybody could
+//! pose as it. Now it generates an Ed25519 keypair on first start and keeps
+//! it protobuf-encoded in a key file readable by its owner only; later starts
+//! load it, so the peer id clients were given stays valid.
+
+use std::{fs, io::Write, path::Path};
+
+use anyhow::{bail, Context};
+use libp2p::identity::Keypair;
+
+/// Peer id of the all-zero seed, known to everyone.
+const ZERO_SEED_PEER_ID: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+
+/// Loads the keypair from `path`, or generates one and stores it there.
+pub fn load_or_generate(path: &Path) -> anyhow::Result<Keypair> {
+    if path.exists() {
+        let bytes = fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let keypair = Keypair::from_protobuf_encoding(&bytes)
+            .with_context(|| format!("{}: invalid key file", path.display()))?;
+        if keypair.public().to_peer_id().to_string() == ZERO_SEED_PEER_ID {
+            bail!(
+                "{}: holds the well-known all-zero key, delete it to generate a new one",
+                path.display()
+            );
+        }
+        return Ok(keypair);
+    }
+    let keypair = Keypair::generate_ed25519();
+    let bytes = keypair.to_protobuf_encoding()?;
+    write_private(path, &bytes).with_context(|| format!("writing {}", path.display()))?;
+    Ok(keypair)
+}
+
+/// Creates `path` with `bytes`, readable by the owner only.
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    fn key_file() -> PathBuf {
+        // 测试并行运行，每次用不同的文件
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "rzv-keyfile-{}-{}.key",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ))
+    }
+
+    #[test]
+    fn keys_are_generated_once_and_loaded_after() -> anyhow::Result<()> {
+        let path = key_file();
+        let generated = load_or_generate(&path)?;
+        let loaded = load_or_generate(&path)?;
+        assert_eq!(generated.public(), loaded.public());
+        assert_eq!(
+            generated.to_protobuf_encoding()?,
+            loaded.to_protobuf_encoding()?
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn the_zero_seed_key_is_refused() -> anyhow::Result<()> {
+        let zero = Keypair::ed25519_from_bytes([0; 32])?;
+        assert_eq!(zero.public().to_peer_id().to_string(), ZERO_SEED_PEER_ID);
+        let path = key_file();
+        fs::write(&path, zero.to_protobuf_encoding()?)?;
+        let result = load_or_generate(&path);
+        fs::remove_file(&path)?;
+        assert!(result.unwrap_err().to_string().contains("all-zero key"));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_key_files_are_refused() -> anyhow::Result<()> {
+        let path = key_file();
+        fs::write(&path, b"not a key")?;
+        let result = load_or_generate(&path);
+        // 无效的密钥文件保持原样，不被覆盖
+        assert_eq!(fs::read(&path)?, b"not a key");
+        fs::remove_file(&path)?;
+        assert!(result.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,16 @@
+//! Shared pieces of the `rzv` rendezvous server and its clients.
+
+pub mod admin;
+pub mod behaviour;
+pub mod capability;
+pub mod federation;
+mod generated;
+pub mod keyfile;
+pub mod logging;
+pub mod namespace;
+pub mod point;
+pub mod policy;
+pub mod protocol;
+pub mod registrar;
+pub mod registry;
+pub mod server;
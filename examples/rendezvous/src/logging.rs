@@ -0,0 +1,28 @@
+//! Log output of `rzv`.
+//!
+//! Everything is logged through `tracing`. Without `RUST_LOG` the events of
+//! `rzv` itself are shown at `info` and those of libp2p from `warn` on.
+
+use clap::ValueEnum;
+use tracing_subscriber::EnvFilter;
+
+const DEFAULT_FILTER: &str = "warn,rzv=info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Plain lines for people
+    Text,
+    /// One JSON object per line for log pipelines
+    Json,
+}
+
+/// Installs the global `tracing` subscriber.
+pub fn init(format: LogFormat) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+}
@@ -0,0 +1,175 @@
+//! Namespaces shared by the server and the clients.
+//!
+//! Every client defaults to [`DEFAULT_NAMESPACE`] and every namespace given on
+//! the command line goes through [`parse`], the same check the server applies
+//! to registrations. A namespace that passes can still be a typo of the one
+//! the other side uses, so the server answers a `ListNamespaces` query
+//! ([`PROTOCOL`]) with the namespaces it holds registrations in; clients
+//! query it when they connect and warn about look-alikes ([`check`]), and
+//! `rzv namespaces` prints it.
+
+use libp2p::{rendezvous::Namespace, StreamProtocol};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub const DEFAULT_NAMESPACE: &str = "rendezvous";
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/rzv/namespaces/1.0.0");
+
+/// Request of the namespace listing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListNamespaces;
+
+/// One namespace of the listing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NamespaceInfo {
+    pub name: String,
+    /// Registrations that have not expired
+    pub registrations: usize,
+}
+
+/// Checks a namespace: not empty, at most 255 bytes, no whitespace or
+/// control characters.
+pub fn parse(s: &str) -> Result<Namespace, String> {
+    if s.is_empty() {
+        return Err("empty namespace".to_string());
+    }
+    if s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(format!("namespace {:?} contains whitespace", s));
+    }
+    Namespace::new(s.to_string()).map_err(|e| format!("namespace {:?}: {}", s, e))
+}
+
+/// Warns about each of `ours` that is missing at the server while a similar
+/// namespace is there.
+pub fn check(ours: &[Namespace], listing: &[NamespaceInfo]) {
+    for (name, info) in look_alikes(ours, listing) {
+        warn!(
+            "Namespace '{}' is unknown to the rendezvous point, but '{}' has {} registrations; is one of them misspelt?",
+            name, info.name, info.registrations
+        );
+    }
+}
+
+/// Pairs each of `ours` missing from `listing` with the similar namespaces
+/// of the listing.
+fn look_alikes<'a>(
+    ours: &[Namespace],
+    listing: &'a [NamespaceInfo],
+) -> Vec<(String, &'a NamespaceInfo)> {
+    let mut found = Vec::new();
+    for namespace in ours {
+        let name = namespace.to_string();
+        if listing.iter().any(|info| info.name == name) {
+            continue;
+        }
+        for info in listing.iter().filter(|info| similar(&name, &info.name)) {
+            found.push((name.clone(), info));
+        }
+    }
+    found
+}
+
+/// Whether two different namespaces differ in case only or by at most two edits.
+fn similar(a: &str, b: &str) -> bool {
+    a != b && (a.eq_ignore_ascii_case(b) || edit_distance(a, b) <= 2)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(name: &str, registrations: usize) -> NamespaceInfo {
+        NamespaceInfo {
+            name: name.to_string(),
+            registrations,
+        }
+    }
+
+    #[test]
+    fn namespaces_are_checked() {
+        assert_eq!(parse("rendezvous").unwrap().to_string(), "rendezvous");
+        assert!(parse("chat/eu-west_1.v2").is_ok());
+        assert!(parse("").is_err());
+        assert!(parse("two words").is_err());
+        assert!(parse("tab\there").is_err());
+        assert!(parse("line\n").is_err());
+        assert!(parse("bell\u{7}").is_err());
+        assert!(parse("\u{a0}nbsp").is_err());
+
+        assert!(parse(&"x".repeat(255)).is_ok());
+        assert!(parse(&"x".repeat(256)).is_err());
+        // 上限按字节算，不按字符
+        assert!(parse(&"é".repeat(128)).is_err());
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("chat", ""), 4);
+        assert_eq!(edit_distance("", "chat"), 4);
+        assert_eq!(edit_distance("chat", "chat"), 0);
+        assert_eq!(edit_distance("chat", "cht"), 1);
+        assert_eq!(edit_distance("chat", "chats"), 1);
+        assert_eq!(edit_distance("chat", "chut"), 1);
+        assert_eq!(edit_distance("redenzvous", "rendezvous"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("naïve", "naive"), 1);
+    }
+
+    #[test]
+    fn look_alikes_are_similar() {
+        assert!(similar("Rendezvous", "rendezvous"));
+        assert!(similar("RENDEZVOUS", "rendezvous"));
+        assert!(similar("redenzvous", "rendezvous"));
+        assert!(similar("rendezvou", "rendezvous"));
+        assert!(!similar("rendezvous", "rendezvous"));
+        assert!(!similar("chat", "games"));
+        assert!(!similar("rndzvs", "rendezvous"));
+    }
+
+    #[test]
+    fn only_missing_namespaces_are_flagged() {
+        let listing = [info("rendezvous", 3), info("chat", 1), info("chats", 2)];
+        let ours = |names: &[&str]| -> Vec<Namespace> {
+            names.iter().map(|name| parse(name).unwrap()).collect()
+        };
+
+        // 服务器上有完全相同的命名空间，即使还有相似的也不提醒
+        assert!(look_alikes(&ours(&["rendezvous", "chat"]), &listing).is_empty());
+        assert!(look_alikes(&ours(&["unrelated"]), &listing).is_empty());
+
+        let found = look_alikes(&ours(&["redenzvous", "Chat"]), &listing);
+        let found: Vec<_> = found
+            .iter()
+            .map(|(name, info)| (name.as_str(), info.name.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                ("redenzvous", "rendezvous"),
+                ("Chat", "chat"),
+                ("Chat", "chats")
+            ]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! Where the clients find the rendezvous points.
+//!
+//! A rendezvous point is given as a multiaddr ending in `/p2p/<peer id>`,
+//! on the command line, in a file such as the one the server writes with
+//! `--export-addr`, or both. Several points may be given; addresses with the
+//! same peer id are one point reachable under each of them.
+
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context};
+use libp2p::{
+    multiaddr::Protocol,
+    swarm::dial_opts::{DialOpts, PeerCondition},
+    Multiaddr, PeerId,
+};
+
+#[derive(Debug, clap::Args)]
+pub struct PointArgs {
+    /// Rendezvous point, e.g. /ip4/127.0.0.1/tcp/64337/p2p/12D3KooW..., may be
+    /// given more than once
+    #[arg(long = "rendezvous", value_name = "MULTIADDR")]
+    pub rendezvous: Vec<Multiaddr>,
+    /// File holding rendezvous point addresses, one per line
+    #[arg(long, value_name = "PATH")]
+    pub rendezvous_file: Option<PathBuf>,
+}
+
+/// A rendezvous point and its addresses to dial.
+#[derive(Debug, Clone)]
+pub struct Point {
+    pub peer_id: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+impl Point {
+    /// Dials all addresses of the point unless it is connected already.
+    pub fn dial_opts(&self) -> DialOpts {
+        DialOpts::peer_id(self.peer_id)
+            .addresses(self.addrs.clone())
+            .condition(PeerCondition::DisconnectedAndNotDialing)
+            .build()
+    }
+}
+
+impl PointArgs {
+    /// The rendezvous points, in the order they were given.
+    pub fn resolve(&self) -> anyhow::Result<Vec<Point>> {
+        let mut addrs = self.rendezvous.clone();
+        if let Some(path) = &self.rendezvous_file {
+            let text =
+                fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+            let before = addrs.len();
+            for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+                addrs.push(
+                    line.parse().with_context(|| {
+                        format!("{}: invalid address {:?}", path.display(), line)
+                    })?,
+                );
+            }
+            if addrs.len() == before {
+                bail!("{}: no address", path.display());
+            }
+        }
+        if addrs.is_empty() {
+            bail!("no rendezvous point, pass --rendezvous or --rendezvous-file");
+        }
+
+        let mut points: Vec<Point> = Vec::new();
+        for addr in addrs {
+            let peer_id = peer_id(&addr)?;
+            match points.iter_mut().find(|p| p.peer_id == peer_id) {
+                Some(point) => point.addrs.push(addr),
+                None => points.push(Point {
+                    peer_id,
+                    addrs: vec![addr],
+                }),
+            }
+        }
+        Ok(points)
+    }
+}
+
+/// The peer id at the end of `addr`.
+pub fn peer_id(addr: &Multiaddr) -> anyhow::Result<PeerId> {
+    match addr.iter().last() {
+        Some(Protocol::P2p(peer)) => Ok(peer),
+        _ => bail!("{} does not end in /p2p/<peer id>", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn peer_ids_come_from_the_p2p_suffix() {
+        let peer = PeerId::random();
+        let with_peer = addr(&format!("/ip4/127.0.0.1/tcp/64337/p2p/{}", peer));
+        assert_eq!(peer_id(&with_peer).unwrap(), peer);
+
+        assert!(peer_id(&addr("/ip4/127.0.0.1/tcp/64337")).is_err());
+        assert!(peer_id(&Multiaddr::empty()).is_err());
+        let inner = addr(&format!("/ip4/127.0.0.1/tcp/1/p2p/{}/p2p-circuit", peer));
+        assert!(peer_id(&inner).is_err());
+    }
+
+    #[test]
+    fn points_group_their_addresses() -> anyhow::Result<()> {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        // 测试并行运行，每次用不同的文件
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rzv-points-{}-{}.txt",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(
+            &path,
+            format!(
+                "\n  /ip4/127.0.0.2/tcp/2/p2p/{}\n/ip4/127.0.0.3/tcp/3/p2p/{}\n",
+                b, a
+            ),
+        )?;
+        let args = PointArgs {
+            rendezvous: vec![addr(&format!("/ip4/127.0.0.1/tcp/1/p2p/{}", a))],
+            rendezvous_file: Some(path.clone()),
+        };
+        let points = args.resolve();
+        fs::remove_file(&path)?;
+        let points = points?;
+        assert_eq!(points.len(), 2);
+        assert_eq!(points[0].peer_id, a);
+        assert_eq!(points[0].addrs.len(), 2);
+        assert_eq!(points[1].peer_id, b);
+
+        let args = PointArgs {
+            rendezvous: vec![addr("/ip4/127.0.0.1/tcp/1")],
+            rendezvous_file: None,
+        };
+        assert!(args.resolve().is_err());
+        let args = PointArgs {
+            rendezvous: Vec::new(),
+            rendezvous_file: None,
+        };
+        assert!(args.resolve().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,319 @@
+//! Who may register in which namespace.
+//!
+//! Without a policy file (`--policy`) any peer may register in any
+//! namespace. A policy file restricts namespaces to the peers listed for
+//! them, and names the admins whose capability tokens the server accepts
+//! (see [`crate::capability`]):
+//!
+//! ```json
+//! {
+//!   "admins": ["12D3KooW..."],
+//!   "namespaces": {
+//!     "chat": ["12D3KooW...", "12D3KooW..."],
+//!     "files": []
+//!   },
+//!   "default": "allow",
+//!   "limits": {
+//!     "chat": { "min_ttl": 60, "max_ttl": 600, "max_registrations": 100 }
+//!   }
+//! }
+//! ```
+//!
+//! A listed namespace accepts its peers and the holders of a token for it.
+//! Namespaces that are not listed accept everybody with `"default": "allow"`,
+//! which is the default, and token holders only with `"default": "deny"`.
+//! Refused registrations are answered with `NotAuthorized` and counted per
+//! namespace; the admin API shows the counts.
+//!
+//! `limits` overrides the TTL bounds (in seconds) and the number of
+//! registrations of single namespaces, whether they are listed or not; what
+//! is left out keeps the server's `--min-ttl`, `--max-ttl` and
+//! `--max-registrations-per-namespace`.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{bail, Context};
+use libp2p::{
+    rendezvous::{ErrorCode, Namespace},
+    PeerId,
+};
+use serde::Deserialize;
+
+use crate::{
+    capability, namespace,
+    registry::{now_millis, NamespaceLimits},
+};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Rule {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    admins: Vec<String>,
+    #[serde(default)]
+    namespaces: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    default: Rule,
+    #[serde(default)]
+    limits: BTreeMap<String, NamespaceLimits>,
+}
+
+/// The namespace policy and the tokens peers presented.
+#[derive(Debug, Default)]
+pub struct Policy {
+    admins: HashSet<PeerId>,
+    namespaces: HashMap<String, HashSet<PeerId>>,
+    default: Rule,
+    /// Limits of namespaces that differ from the server's
+    limits: BTreeMap<String, NamespaceLimits>,
+    /// Expiry of the granted namespaces by peer, `None` if the token does not expire
+    grants: HashMap<PeerId, HashMap<String, Option<u64>>>,
+    /// Refused registrations by namespace
+    refused: BTreeMap<String, u64>,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file: PolicyFile = serde_json::from_slice(
+            &std::fs::read(path).with_context(|| format!("reading {}", path.display()))?,
+        )
+        .with_context(|| format!("{}: invalid policy", path.display()))?;
+        let mut policy = Self {
+            admins: parse_peers(&file.admins)
+                .with_context(|| format!("{}: admins", path.display()))?,
+            default: file.default,
+            ..Default::default()
+        };
+        for (name, peers) in file.namespaces {
+            if let Err(e) = namespace::parse(&name) {
+                bail!("{}: {}", path.display(), e);
+            }
+            let peers = parse_peers(&peers)
+                .with_context(|| format!("{}: namespace '{}'", path.display(), name))?;
+            policy.namespaces.insert(name, peers);
+        }
+        for (name, limits) in file.limits {
+            if let Err(e) = namespace::parse(&name) {
+                bail!("{}: limits: {}", path.display(), e);
+            }
+            policy.limits.insert(name, limits);
+        }
+        Ok(policy)
+    }
+
+    /// Limits of namespaces that differ from the server's.
+    pub fn limits(&self) -> &BTreeMap<String, NamespaceLimits> {
+        &self.limits
+    }
+
+    /// Namespaces restricted to listed peers and token holders.
+    pub fn restricted(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    pub fn admins(&self) -> usize {
+        self.admins.len()
+    }
+
+    /// Keeps the tokens `peer` presented that are valid for it; returns the
+    /// namespace granted by each token, or why it was refused.
+    pub fn present(&mut self, peer: PeerId, tokens: &[String]) -> Vec<Result<String, String>> {
+        tokens
+            .iter()
+            .map(|token| {
+                let capability = capability::verify(token)?;
+                if !self.admins.contains(&capability.issuer) {
+                    return Err(format!(
+                        "token for '{}' is not signed by an admin of this rendezvous point",
+                        capability.namespace
+                    ));
+                }
+                if capability.peer != peer {
+                    return Err(format!(
+                        "token for '{}' was issued to {}",
+                        capability.namespace, capability.peer
+                    ));
+                }
+                if capability.is_expired() {
+                    return Err(format!("token for '{}' has expired", capability.namespace));
+                }
+                let name = capability.namespace.to_string();
+                self.grants
+                    .entry(peer)
+                    .or_default()
+                    .insert(name.clone(), capability.expires);
+                Ok(name)
+            })
+            .collect()
+    }
+
+    /// Whether `peer` may register in `namespace`; refusals are counted.
+    pub fn check(&mut self, peer: PeerId, namespace: &Namespace) -> Result<(), ErrorCode> {
+        let name = namespace.to_string();
+        let listed = match self.namespaces.get(&name) {
+            Some(peers) => peers.contains(&peer),
+            None => self.default == Rule::Allow,
+        };
+        if listed || self.granted(peer, &name) {
+            return Ok(());
+        }
+        *self.refused.entry(name).or_default() += 1;
+        Err(ErrorCode::NotAuthorized)
+    }
+
+    /// Refused registrations by namespace.
+    pub fn refused(&self) -> &BTreeMap<String, u64> {
+        &self.refused
+    }
+
+    fn granted(&self, peer: PeerId, namespace: &str) -> bool {
+        let now = now_millis();
+        self.grants
+            .get(&peer)
+            .and_then(|grants| grants.get(namespace))
+            .is_some_and(|expires| expires.is_none_or(|t| t.saturating_mul(1000) > now))
+    }
+}
+
+fn parse_peers(peers: &[String]) -> anyhow::Result<HashSet<PeerId>> {
+    peers
+        .iter()
+        .map(|p| {
+            p.parse()
+                .with_context(|| format!("invalid peer id {:?}", p))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn load(json: &str) -> anyhow::Result<Policy> {
+        // 测试并行运行，每次用不同的文件
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "rzv-policy-{}-{}.json",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, json)?;
+        let policy = Policy::load(&path);
+        std::fs::remove_file(&path)?;
+        policy
+    }
+
+    fn ns(name: &'static str) -> Namespace {
+        Namespace::from_static(name)
+    }
+
+    #[test]
+    fn listed_namespaces_accept_their_peers_only() {
+        let (member, stranger) = (PeerId::random(), PeerId::random());
+        let mut policy = load(&format!(
+            r#"{{"namespaces": {{"chat": ["{}"], "closed": []}}}}"#,
+            member
+        ))
+        .unwrap();
+        assert_eq!(policy.check(member, &ns("chat")), Ok(()));
+        assert_eq!(
+            policy.check(stranger, &ns("chat")),
+            Err(ErrorCode::NotAuthorized)
+        );
+        assert_eq!(
+            policy.check(member, &ns("closed")),
+            Err(ErrorCode::NotAuthorized)
+        );
+        // 未列出的命名空间默认放行
+        assert_eq!(policy.check(stranger, &ns("open")), Ok(()));
+
+        let refused: Vec<(&str, u64)> = policy
+            .refused()
+            .iter()
+            .map(|(name, count)| (name.as_str(), *count))
+            .collect();
+        assert_eq!(refused, vec![("chat", 1), ("closed", 1)]);
+    }
+
+    #[test]
+    fn deny_by_default_closes_unlisted_namespaces() {
+        let mut policy = load(r#"{"default": "deny"}"#).unwrap();
+        assert_eq!(
+            policy.check(PeerId::random(), &ns("open")),
+            Err(ErrorCode::NotAuthorized)
+        );
+        assert_eq!(
+            Policy::default().check(PeerId::random(), &ns("open")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn tokens_of_admins_grant_their_namespace() {
+        let admin = Keypair::generate_ed25519();
+        let outsider = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let mut policy = load(&format!(
+            r#"{{"admins": ["{}"], "namespaces": {{"chat": [], "files": []}}, "default": "deny"}}"#,
+            admin.public().to_peer_id()
+        ))
+        .unwrap();
+
+        let tokens = [
+            capability::issue(&admin, &ns("chat"), peer, None).unwrap(),
+            capability::issue(&outsider, &ns("files"), peer, None).unwrap(),
+            capability::issue(&admin, &ns("files"), PeerId::random(), None).unwrap(),
+            capability::issue(&admin, &ns("files"), peer, Some(1)).unwrap(),
+            "not a token".to_string(),
+        ];
+        let results = policy.present(peer, &tokens);
+        assert_eq!(results[0], Ok("chat".to_string()));
+        assert!(results[1..].iter().all(Result::is_err), "{:?}", results);
+
+        assert_eq!(policy.check(peer, &ns("chat")), Ok(()));
+        assert_eq!(
+            policy.check(peer, &ns("files")),
+            Err(ErrorCode::NotAuthorized)
+        );
+        assert_eq!(
+            policy.check(PeerId::random(), &ns("chat")),
+            Err(ErrorCode::NotAuthorized)
+        );
+    }
+
+    #[test]
+    fn tokens_far_in_the_future_do_not_overflow() {
+        let admin = Keypair::generate_ed25519();
+        let peer = PeerId::random();
+        let mut policy = load(&format!(
+            r#"{{"admins": ["{}"], "default": "deny"}}"#,
+            admin.public().to_peer_id()
+        ))
+        .unwrap();
+        let token = capability::issue(&admin, &ns("chat"), peer, Some(u64::MAX)).unwrap();
+        assert!(policy.present(peer, &[token])[0].is_ok());
+        assert_eq!(policy.check(peer, &ns("chat")), Ok(()));
+    }
+
+    #[test]
+    fn invalid_files_are_refused() {
+        assert!(load(r#"{"namespaces": {"chat": ["not a peer"]}}"#).is_err());
+        assert!(load(r#"{"default": "maybe"}"#).is_err());
+        assert!(load(r#"{"unknown": 1}"#).is_err());
+        assert!(load(r#"{"limits": {"chat": {"max_ttl": "long"}}}"#).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! The rendezvous protocol as spoken by the server.
+//!
+//! libp2p's `rendezvous::server::Behaviour` keeps its registrations to
+//! itself: they can be neither listed, evicted, stored across restarts nor
+//! replicated to other points, and its codec and protobuf types are private.
+//! So the server answers `/rendezvous/1.0.0` on its own through a
+//! request-response behaviour with this codec and keeps the registrations in
+//! a [`crate::registry::Registry`]. The messages are the length-prefixed
+//! protobufs of the rendezvous spec, vendored in `generated/`, so the
+//! clients keep using `rendezvous::client::Behaviour`.
+
+use std::io;
+
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use libp2p::{
+    core::{PeerRecord, SignedEnvelope},
+    rendezvous::{ErrorCode, Namespace, Registration, Ttl},
+    request_response, StreamProtocol,
+};
+use quick_protobuf::{BytesReader, MessageRead, MessageWrite, Writer};
+
+use crate::generated::rendezvous::{mod_Message as pb, Message as ProtoMessage};
+
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/rendezvous/1.0.0");
+
+const MAX_MESSAGE_LEN: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Message {
+    Register(NewRegistration),
+    RegisterResponse(Result<Ttl, ErrorCode>),
+    Unregister(Namespace),
+    Discover {
+        namespace: Option<Namespace>,
+        cookie: Option<Cookie>,
+        limit: Option<u64>,
+    },
+    DiscoverResponse(Result<(Vec<Registration>, Cookie), ErrorCode>),
+}
+
+#[derive(Debug, Clone)]
+pub struct NewRegistration {
+    pub namespace: Namespace,
+    pub record: PeerRecord,
+    pub ttl: Option<Ttl>,
+}
+
+/// Position in the sequence of registrations of one namespace, or of all.
+///
+/// On the wire it is the big-endian `id` followed by the namespace, the same
+/// encoding as `rendezvous::Cookie`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cookie {
+    pub id: u64,
+    pub namespace: Option<Namespace>,
+}
+
+impl Cookie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.id.to_be_bytes().to_vec();
+        if let Some(namespace) = &self.namespace {
+            bytes.extend_from_slice(namespace.to_string().as_bytes());
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 8 {
+            return None;
+        }
+        let (id, namespace) = bytes.split_at(8);
+        let namespace = match namespace {
+            [] => None,
+            ns => Some(Namespace::new(String::from_utf8(ns.to_vec()).ok()?).ok()?),
+        };
+        Some(Self {
+            id: u64::from_be_bytes(id.try_into().ok()?),
+            namespace,
+        })
+    }
+}
+
+/// Request-response codec of the rendezvous protocol.
+#[derive(Debug, Clone, Default)]
+pub struct Codec;
+
+#[async_trait]
+impl request_response::Codec for Codec {
+    type Protocol = StreamProtocol;
+    type Request = Message;
+    type Response = Message;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Message>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<Message>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: Message,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: Message,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_message(io, response).await
+    }
+}
+
+async fn read_message<T: AsyncRead + Unpin + Send>(io: &mut T) -> io::Result<Message> {
+    // 无符号 varint 长度前缀
+    let mut len = 0usize;
+    for shift in (0..).step_by(7) {
+        let mut byte = [0u8];
+        io.read_exact(&mut byte).await?;
+        if shift > 28 {
+            return Err(invalid("length prefix too long"));
+        }
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(invalid("message too large"));
+    }
+    let mut bytes = vec![0u8; len];
+    io.read_exact(&mut bytes).await?;
+    let mut reader = BytesReader::from_bytes(&bytes);
+    let message = ProtoMessage::from_reader(&mut reader, &bytes).map_err(io::Error::other)?;
+    Message::try_from(message)
+}
+
+async fn write_message<T: AsyncWrite + Unpin + Send>(
+    io: &mut T,
+    message: Message,
+) -> io::Result<()> {
+    let message = ProtoMessage::from(message);
+    let mut bytes = Vec::with_capacity(message.get_size() + 4);
+    Writer::new(&mut bytes)
+        .write_message(&message)
+        .map_err(io::Error::other)?;
+    io.write_all(&bytes).await?;
+    io.flush().await
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+impl From<Message> for ProtoMessage {
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Register(registration) => ProtoMessage {
+                type_pb: Some(pb::MessageType::REGISTER),
+                register: Some(pb::Register {
+                    ns: Some(registration.namespace.to_string()),
+                    signedPeerRecord: Some(
+                        registration
+                            .record
+                            .into_signed_envelope()
+                            .into_protobuf_encoding(),
+                    ),
+                    ttl: registration.ttl,
+                }),
+                ..Default::default()
+            },
+            Message::RegisterResponse(result) => ProtoMessage {
+                type_pb: Some(pb::MessageType::REGISTER_RESPONSE),
+                registerResponse: Some(match result {
+                    Ok(ttl) => pb::RegisterResponse {
+                        status: Some(pb::ResponseStatus::OK),
+                        statusText: None,
+                        ttl: Some(ttl),
+                    },
+                    Err(code) => pb::RegisterResponse {
+                        status: Some(status(code)),
+                        statusText: None,
+                        ttl: None,
+                    },
+                }),
+                ..Default::default()
+            },
+            Message::Unregister(namespace) => ProtoMessage {
+                type_pb: Some(pb::MessageType::UNREGISTER),
+                unregister: Some(pb::Unregister {
+                    ns: Some(namespace.to_string()),
+                    id: None,
+                }),
+                ..Default::default()
+            },
+            Message::Discover {
+                namespace,
+                cookie,
+                limit,
+            } => ProtoMessage {
+                type_pb: Some(pb::MessageType::DISCOVER),
+                discover: Some(pb::Discover {
+                    ns: namespace.map(|ns| ns.to_string()),
+                    limit,
+                    cookie: cookie.map(|c| c.to_bytes()),
+                }),
+                ..Default::default()
+            },
+            Message::DiscoverResponse(result) => ProtoMessage {
+                type_pb: Some(pb::MessageType::DISCOVER_RESPONSE),
+                discoverResponse: Some(match result {
+                    Ok((registrations, cookie)) => pb::DiscoverResponse {
+                        registrations: registrations
+                            .into_iter()
+                            .map(|r| pb::Register {
+                                ns: Some(r.namespace.to_string()),
+                                signedPeerRecord: Some(
+                                    r.record.into_signed_envelope().into_protobuf_encoding(),
+                                ),
+                                ttl: Some(r.ttl),
+                            })
+                            .collect(),
+                        cookie: Some(cookie.to_bytes()),
+                        status: Some(pb::ResponseStatus::OK),
+                        statusText: None,
+                    },
+                    Err(code) => pb::DiscoverResponse {
+                        status: Some(status(code)),
+                        ..Default::default()
+                    },
+                }),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl TryFrom<ProtoMessage> for Message {
+    type Error = io::Error;
+
+    fn try_from(message: ProtoMessage) -> io::Result<Self> {
+        let message = match message {
+            ProtoMessage {
+                type_pb: Some(pb::MessageType::REGISTER),
+                register: Some(register),
+                ..
+            } => Message::Register(new_registration(register)?),
+            ProtoMessage {
+                type_pb: Some(pb::MessageType::REGISTER_RESPONSE),
+                registerResponse: Some(response),
+                ..
+            } => Message::RegisterResponse(match response.status {
+                Some(pb::ResponseStatus::OK) => {
+                    Ok(response.ttl.ok_or_else(|| invalid("missing TTL"))?)
+                }
+                status => Err(error_code(status)?),
+            }),
+            ProtoMessage {
+                type_pb: Some(pb::MessageType::UNREGISTER),
+                unregister: Some(unregister),
+                ..
+            } => Message::Unregister(namespace(unregister.ns)?),
+            ProtoMessage {
+                type_pb: Some(pb::MessageType::DISCOVER),
+                discover: Some(discover),
+                ..
+            } => Message::Discover {
+                namespace: discover.ns.map(|ns| namespace(Some(ns))).transpose()?,
+                cookie: discover
+                    .cookie
+                    .map(|c| Cookie::from_bytes(&c).ok_or_else(|| invalid("invalid cookie")))
+                    .transpose()?,
+                limit: discover.limit,
+            },
+            ProtoMessage {
+                type_pb: Some(pb::MessageType::DISCOVER_RESPONSE),
+                discoverResponse: Some(response),
+                ..
+            } => Message::DiscoverResponse(match response.status {
+                Some(pb::ResponseStatus::OK) => {
+                    let registrations = response
+                        .registrations
+                        .into_iter()
+                        .map(|r| {
+                            let registration = new_registration(r)?;
+                            Ok(Registration {
+                                namespace: registration.namespace,
+                                record: registration.record,
+                                ttl: registration.ttl.ok_or_else(|| invalid("missing TTL"))?,
+                            })
+                        })
+                        .collect::<io::Result<Vec<_>>>()?;
+                    let cookie = response
+                        .cookie
+                        .and_then(|c| Cookie::from_bytes(&c))
+                        .ok_or_else(|| invalid("invalid cookie"))?;
+                    Ok((registrations, cookie))
+                }
+                status => Err(error_code(status)?),
+            }),
+            _ => return Err(invalid("unexpected message")),
+        };
+        Ok(message)
+    }
+}
+
+fn namespace(ns: Option<String>) -> io::Result<Namespace> {
+    let ns = ns.ok_or_else(|| invalid("missing namespace"))?;
+    Namespace::new(ns).map_err(|_| invalid("namespace too long"))
+}
+
+fn new_registration(register: pb::Register) -> io::Result<NewRegistration> {
+    let envelope = register
+        .signedPeerRecord
+        .ok_or_else(|| invalid("missing signed peer record"))?;
+    let envelope = SignedEnvelope::from_protobuf_encoding(&envelope).map_err(io::Error::other)?;
+    Ok(NewRegistration {
+        namespace: namespace(register.ns)?,
+        record: PeerRecord::from_signed_envelope(envelope).map_err(io::Error::other)?,
+        ttl: register.ttl,
+    })
+}
+
+fn status(code: ErrorCode) -> pb::ResponseStatus {
+    match code {
+        ErrorCode::InvalidNamespace => pb::ResponseStatus::E_INVALID_NAMESPACE,
+        ErrorCode::InvalidSignedPeerRecord => pb::ResponseStatus::E_INVALID_SIGNED_PEER_RECORD,
+        ErrorCode::InvalidTtl => pb::ResponseStatus::E_INVALID_TTL,
+        ErrorCode::InvalidCookie => pb::ResponseStatus::E_INVALID_COOKIE,
+        ErrorCode::NotAuthorized => pb::ResponseStatus::E_NOT_AUTHORIZED,
+        ErrorCode::InternalError => pb::ResponseStatus::E_INTERNAL_ERROR,
+        ErrorCode::Unavailable => pb::ResponseStatus::E_UNAVAILABLE,
+    }
+}
+
+fn error_code(status: Option<pb::ResponseStatus>) -> io::Result<ErrorCode> {
+    Ok(match status.ok_or_else(|| invalid("missing status"))? {
+        pb::ResponseStatus::OK => return Err(invalid("unexpected status")),
+        pb::ResponseStatus::E_INVALID_NAMESPACE => ErrorCode::InvalidNamespace,
+        pb::ResponseStatus::E_INVALID_SIGNED_PEER_RECORD => ErrorCode::InvalidSignedPeerRecord,
+        pb::ResponseStatus::E_INVALID_TTL => ErrorCode::InvalidTtl,
+        pb::ResponseStatus::E_INVALID_COOKIE => ErrorCode::InvalidCookie,
+        pb::ResponseStatus::E_NOT_AUTHORIZED => ErrorCode::NotAuthorized,
+        pb::ResponseStatus::E_INTERNAL_ERROR => ErrorCode::InternalError,
+        pb::ResponseStatus::E_UNAVAILABLE => ErrorCode::Unavailable,
+    })
+}
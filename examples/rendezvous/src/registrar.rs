@@ -0,0 +1,689 @@
+//! Keeping registrations alive.
+//!
+//! The registrar registers this peer in each of its namespaces as soon as the
+//! rendezvous point is connected and the peer knows an external address, and
+//! renews each registration once [`RENEW_FRACTION`] of its granted TTL has
+//! passed. The
+//! connection to the rendezvous point may close in between, idle or not:
+//! the registrar dials again when the next renewal is due, and whenever a
+//! dial fails or the connection breaks it retries after a backoff doubling
+//! from [`INITIAL_BACKOFF`] up to [`MAX_BACKOFF`]. On shutdown it unregisters.
+//!
+//! On the first connection it also lists the namespaces of the rendezvous
+//! point to warn about misspelt ones, see [`crate::namespace::check`]. With
+//! capability tokens it presents them on every connection and registers once
+//! they were answered, see [`crate::capability`].
+//!
+//! With several rendezvous points, [`Registrars`] keeps one registrar per
+//! point and registers at the first `--points` of them, all by default. When
+//! a dial fails, the connection to one of those breaks or it does not take a
+//! registration, the next point standing by takes its place and the failed
+//! one stands by in turn; its registrations at the failed point are left to
+//! expire. A point refusing a registration for good, e.g. with
+//! `NotAuthorized`, ends the registrar if no other point stands by, or once
+//! every point refused since a registration was last accepted.
+
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure};
+use futures::StreamExt;
+use libp2p::{
+    rendezvous::{self, ErrorCode, Namespace, Ttl},
+    request_response,
+    swarm::SwarmEvent,
+    PeerId, Swarm,
+};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use crate::{
+    behaviour::{ClientBehaviour, ClientBehaviourEvent},
+    capability::PresentTokens,
+    namespace::{self, ListNamespaces},
+    point::Point,
+};
+
+/// Part of the granted TTL after which a registration is renewed
+pub const RENEW_FRACTION: f64 = 0.5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Time given to the unregistration to go out before exiting
+const UNREGISTER_GRACE: Duration = Duration::from_secs(1);
+
+struct Pending {
+    namespace: Namespace,
+    /// When to register next, `None` while a registration is under way
+    due: Option<Instant>,
+}
+
+pub struct Registrar {
+    point: Point,
+    ttl: Option<Ttl>,
+    registrations: Vec<Pending>,
+    tokens: Vec<String>,
+    /// Whether the tokens were answered on the current connection
+    presented: bool,
+    /// When to dial again after a failure
+    redial: Option<Instant>,
+    backoff: Duration,
+    connected: bool,
+    dialing: bool,
+    /// Whether the namespaces of the rendezvous point were listed
+    listed: bool,
+    /// What went wrong since the last [`Registrar::take_failure`]
+    failure: Option<Failure>,
+    /// Whether the point refused a registration since the last one accepted
+    /// by any point
+    refused: bool,
+}
+
+/// Why a registrar gives way to the next point standing by.
+enum Failure {
+    /// A dial failed, the connection broke or the point could not register
+    /// us for the moment
+    Unreachable,
+    /// The point refused a registration, which is not retried until the
+    /// registrar is active again
+    Refused(anyhow::Error),
+}
+
+impl Registrar {
+    pub fn new(
+        point: Point,
+        namespaces: Vec<Namespace>,
+        ttl: Option<Ttl>,
+        tokens: Vec<String>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            point,
+            ttl,
+            registrations: namespaces
+                .into_iter()
+                .map(|namespace| Pending {
+                    namespace,
+                    due: Some(now),
+                })
+                .collect(),
+            tokens,
+            presented: false,
+            redial: None,
+            backoff: INITIAL_BACKOFF,
+            connected: false,
+            dialing: false,
+            listed: false,
+            failure: None,
+            refused: false,
+        }
+    }
+
+    fn namespaces(&self) -> Vec<Namespace> {
+        self.registrations
+            .iter()
+            .map(|p| p.namespace.clone())
+            .collect()
+    }
+
+    fn pending(&mut self, namespace: &Namespace) -> Option<&mut Pending> {
+        self.registrations
+            .iter_mut()
+            .find(|p| &p.namespace == namespace)
+    }
+
+    /// When [`Registrar::run_due`] has something to do, if ever.
+    pub fn deadline(&self, swarm: &Swarm<ClientBehaviour>) -> Option<Instant> {
+        if self.redial.is_some() {
+            return self.redial;
+        }
+        // 拨号中、等待令牌应答或还没有外部地址时等待事件，而不是定时器
+        if self.dialing
+            || (self.connected && (!self.presented || swarm.external_addresses().next().is_none()))
+        {
+            return None;
+        }
+        self.registrations.iter().filter_map(|p| p.due).min()
+    }
+
+    /// Dials or registers if it is time to.
+    pub fn run_due(&mut self, swarm: &mut Swarm<ClientBehaviour>) -> anyhow::Result<()> {
+        let now = Instant::now();
+        if self.redial.is_some_and(|t| t <= now) {
+            self.redial = None;
+            self.dial(swarm);
+            return Ok(());
+        }
+        if !self
+            .registrations
+            .iter()
+            .any(|p| p.due.is_some_and(|t| t <= now))
+        {
+            return Ok(());
+        }
+        if !self.connected {
+            self.dial(swarm);
+            return Ok(());
+        }
+        if !self.presented || swarm.external_addresses().next().is_none() {
+            return Ok(());
+        }
+        for pending in &mut self.registrations {
+            if pending.due.is_some_and(|t| t <= now) {
+                swarm.behaviour_mut().rendezvous.register(
+                    pending.namespace.clone(),
+                    self.point.peer_id,
+                    self.ttl,
+                )?;
+                pending.due = None;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn on_event(
+        &mut self,
+        swarm: &mut Swarm<ClientBehaviour>,
+        event: &SwarmEvent<ClientBehaviourEvent>,
+    ) -> anyhow::Result<()> {
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if *peer_id == self.point.peer_id => {
+                info!("Connection established with rendezvous point {}", peer_id);
+                self.connected = true;
+                self.dialing = false;
+                self.backoff = INITIAL_BACKOFF;
+                if !self.listed {
+                    swarm
+                        .behaviour_mut()
+                        .namespaces
+                        .send_request(peer_id, ListNamespaces);
+                }
+                self.presented = self.tokens.is_empty();
+                if !self.presented {
+                    swarm
+                        .behaviour_mut()
+                        .capabilities
+                        .send_request(peer_id, PresentTokens(self.tokens.clone()));
+                }
+                self.run_due(swarm)?;
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } if *peer_id == self.point.peer_id => {
+                self.dialing = false;
+                warn!("Failed to reach rendezvous point {}: {}", peer_id, error);
+                self.back_off();
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                cause,
+                ..
+            } if *peer_id == self.point.peer_id => {
+                self.connected = false;
+                self.presented = false;
+                // 注册请求可能随连接丢失，重连后重新注册
+                for pending in &mut self.registrations {
+                    pending.due.get_or_insert_with(Instant::now);
+                }
+                if let Some(error) = cause {
+                    info!(
+                        "Connection closed with rendezvous point {}: {}",
+                        peer_id, error
+                    );
+                    self.back_off();
+                }
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                rendezvous::client::Event::Registered {
+                    rendezvous_node,
+                    ttl,
+                    namespace,
+                },
+            )) if *rendezvous_node == self.point.peer_id => {
+                let renew = Duration::from_secs(*ttl).mul_f64(RENEW_FRACTION);
+                info!(
+                    "Registered for namespace '{}' at rendezvous point {} for the next {} seconds, renewing in {}s",
+                    namespace,
+                    rendezvous_node,
+                    ttl,
+                    renew.as_secs()
+                );
+                if let Some(pending) = self.pending(namespace) {
+                    pending.due = Some(Instant::now() + renew);
+                }
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                rendezvous::client::Event::RegisterFailed {
+                    rendezvous_node,
+                    namespace,
+                    error,
+                },
+            )) if *rendezvous_node == self.point.peer_id => match error {
+                ErrorCode::Unavailable | ErrorCode::InternalError => {
+                    warn!(
+                        "Rendezvous point {} could not register us in namespace '{}' ({:?}), retrying in {}s",
+                        rendezvous_node,
+                        namespace,
+                        error,
+                        self.backoff.as_secs()
+                    );
+                    self.retry(namespace);
+                    self.failure.get_or_insert(Failure::Unreachable);
+                }
+                ErrorCode::NotAuthorized => {
+                    self.retry(namespace);
+                    self.failure = Some(Failure::Refused(anyhow!(
+                        "Not authorized to register in namespace '{}' at rendezvous point {}, it may need a capability token (--token)",
+                        namespace,
+                        rendezvous_node
+                    )));
+                }
+                _ => {
+                    self.retry(namespace);
+                    self.failure = Some(Failure::Refused(anyhow!(
+                        "Failed to register: rendezvous_node={}, namespace={}, error_code={:?}",
+                        rendezvous_node,
+                        namespace,
+                        error
+                    )));
+                }
+            },
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Namespaces(
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                },
+            )) if *peer == self.point.peer_id => {
+                self.listed = true;
+                namespace::check(&self.namespaces(), response);
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Capabilities(
+                request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Response { response, .. },
+                },
+            )) if *peer == self.point.peer_id => {
+                for result in &response.0 {
+                    match result {
+                        Ok(namespace) => info!(
+                            "Rendezvous point {} accepted the token for namespace '{}'",
+                            peer, namespace
+                        ),
+                        Err(e) => warn!("Rendezvous point {} refused a token: {}", peer, e),
+                    }
+                }
+                self.presented = true;
+                self.run_due(swarm)?;
+            }
+            SwarmEvent::Behaviour(ClientBehaviourEvent::Capabilities(
+                request_response::Event::OutboundFailure { peer, error, .. },
+            )) if *peer == self.point.peer_id => {
+                // 旧版服务器不支持令牌协议，直接注册，由其决定是否接受
+                warn!("Failed to present the tokens: {}", error);
+                self.presented = true;
+                self.run_due(swarm)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Unregisters from every namespace if connected.
+    fn unregister(&mut self, swarm: &mut Swarm<ClientBehaviour>) {
+        if !self.connected {
+            info!(
+                "Not connected to rendezvous point {}, leaving the registration to expire",
+                self.point.peer_id
+            );
+            return;
+        }
+        for namespace in self.namespaces() {
+            swarm
+                .behaviour_mut()
+                .rendezvous
+                .unregister(namespace.clone(), self.point.peer_id);
+            info!(
+                "Unregistered from namespace '{}' at rendezvous point {}",
+                namespace, self.point.peer_id
+            );
+        }
+    }
+
+    /// What went wrong since the last call, if anything.
+    fn take_failure(&mut self) -> Option<Failure> {
+        self.failure.take()
+    }
+
+    /// Registers in `namespace` again after a backoff.
+    fn retry(&mut self, namespace: &Namespace) {
+        let retry = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        if let Some(pending) = self.pending(namespace) {
+            pending.due = Some(retry);
+        }
+    }
+
+    fn dial(&mut self, swarm: &mut Swarm<ClientBehaviour>) {
+        if self.connected || self.dialing {
+            return;
+        }
+        match swarm.dial(self.point.dial_opts()) {
+            Ok(()) => self.dialing = true,
+            Err(e) => {
+                warn!(
+                    "Failed to dial rendezvous point {}: {}",
+                    self.point.peer_id, e
+                );
+                self.back_off();
+            }
+        }
+    }
+
+    fn back_off(&mut self) {
+        info!(
+            "Dialing rendezvous point {} again in {}s",
+            self.point.peer_id,
+            self.backoff.as_secs()
+        );
+        self.failure.get_or_insert(Failure::Unreachable);
+        self.redial = Some(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Registrars for several rendezvous points, of which the first `active`
+/// register and the others stand by.
+pub struct Registrars {
+    registrars: Vec<Registrar>,
+    active: usize,
+}
+
+impl Registrars {
+    /// Registers at `active` of `points`, or at all of them if `None`.
+    pub fn new(
+        points: Vec<Point>,
+        active: Option<usize>,
+        namespaces: Vec<Namespace>,
+        ttl: Option<Ttl>,
+        tokens: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let active = active.unwrap_or(points.len());
+        ensure!(active > 0, "--points must be at least 1");
+        if active > points.len() {
+            warn!(
+                "Asked to register at {} rendezvous points, but only {} were given",
+                active,
+                points.len()
+            );
+        }
+        Ok(Self {
+            active: active.min(points.len()),
+            registrars: points
+                .into_iter()
+                .map(|point| Registrar::new(point, namespaces.clone(), ttl, tokens.clone()))
+                .collect(),
+        })
+    }
+
+    pub fn is_point(&self, peer: &PeerId) -> bool {
+        self.registrars.iter().any(|r| r.point.peer_id == *peer)
+    }
+
+    /// When [`Registrars::run_due`] has something to do, if ever.
+    pub fn deadline(&self, swarm: &Swarm<ClientBehaviour>) -> Option<Instant> {
+        self.registrars[..self.active]
+            .iter()
+            .filter_map(|r| r.deadline(swarm))
+            .min()
+    }
+
+    pub fn run_due(&mut self, swarm: &mut Swarm<ClientBehaviour>) -> anyhow::Result<()> {
+        for registrar in &mut self.registrars[..self.active] {
+            registrar.run_due(swarm)?;
+        }
+        self.fail_over(swarm)
+    }
+
+    pub fn on_event(
+        &mut self,
+        swarm: &mut Swarm<ClientBehaviour>,
+        event: &SwarmEvent<ClientBehaviourEvent>,
+    ) -> anyhow::Result<()> {
+        if let SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+            rendezvous::client::Event::Registered { .. },
+        )) = event
+        {
+            for registrar in &mut self.registrars {
+                registrar.refused = false;
+            }
+        }
+        for registrar in &mut self.registrars {
+            registrar.on_event(swarm, event)?;
+        }
+        self.fail_over(swarm)
+    }
+
+    /// Unregisters at every rendezvous point and gives the requests a moment
+    /// to go out.
+    pub async fn shutdown(&mut self, swarm: &mut Swarm<ClientBehaviour>) {
+        for registrar in &mut self.registrars {
+            registrar.unregister(swarm);
+        }
+        let _ = tokio::time::timeout(UNREGISTER_GRACE, async {
+            loop {
+                swarm.select_next_some().await;
+            }
+        })
+        .await;
+    }
+
+    /// Replaces the active registrars that failed by the ones standing by.
+    /// Fails if a point refused a registration and none stands by, or once
+    /// every point refused since a registration was last accepted.
+    fn fail_over(&mut self, swarm: &mut Swarm<ClientBehaviour>) -> anyhow::Result<()> {
+        let mut i = 0;
+        let mut checked = self.active;
+        while i < checked {
+            let Some(failure) = self.registrars[i].take_failure() else {
+                i += 1;
+                continue;
+            };
+            if self.registrars.len() == self.active {
+                // 没有待命的注册点：不可达的稍后重试，拒绝注册的无可替代
+                if let Failure::Refused(e) = failure {
+                    return Err(e);
+                }
+                i += 1;
+                continue;
+            }
+            if let Failure::Refused(e) = failure {
+                self.registrars[i].refused = true;
+                // 所有注册点都拒绝过，轮换下去也不会成功
+                if self.registrars.iter().all(|r| r.refused) {
+                    return Err(e);
+                }
+                warn!("{}", e);
+            }
+            // 失败的注册器排到末尾待命，下一个待命的接替它
+            let failed = self.registrars.remove(i);
+            let next = &mut self.registrars[self.active - 1];
+            info!(
+                "Registering at rendezvous point {} instead of {}",
+                next.point.peer_id, failed.point.peer_id
+            );
+            // 待命期间记下的失败不算数
+            next.failure = None;
+            next.run_due(swarm)?;
+            self.registrars.push(failed);
+            checked -= 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{
+        core::{transport::PortUse, ConnectedPoint, Endpoint},
+        identity::Keypair,
+        swarm::ConnectionId,
+        Multiaddr,
+    };
+
+    use super::*;
+    use crate::behaviour::build_swarm;
+
+    fn swarm() -> Swarm<ClientBehaviour> {
+        let mut swarm = build_swarm(
+            Keypair::generate_ed25519(),
+            Duration::from_secs(5),
+            ClientBehaviour::new,
+        )
+        .unwrap();
+        swarm.add_external_address("/ip4/127.0.0.1/tcp/4001".parse().unwrap());
+        swarm
+    }
+
+    fn registrar() -> Registrar {
+        let point = Point {
+            peer_id: PeerId::random(),
+            addrs: vec!["/ip4/127.0.0.1/tcp/1".parse().unwrap()],
+        };
+        let namespaces = vec![Namespace::from_static("chat")];
+        Registrar::new(point, namespaces, None, Vec::new())
+    }
+
+    fn endpoint() -> ConnectedPoint {
+        ConnectedPoint::Dialer {
+            address: Multiaddr::empty(),
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        }
+    }
+
+    fn established(peer_id: PeerId) -> SwarmEvent<ClientBehaviourEvent> {
+        SwarmEvent::ConnectionEstablished {
+            peer_id,
+            connection_id: ConnectionId::new_unchecked(0),
+            endpoint: endpoint(),
+            num_established: 1.try_into().unwrap(),
+            concurrent_dial_errors: None,
+            established_in: Duration::ZERO,
+        }
+    }
+
+    fn closed(peer_id: PeerId, broken: bool) -> SwarmEvent<ClientBehaviourEvent> {
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            connection_id: ConnectionId::new_unchecked(0),
+            endpoint: endpoint(),
+            num_established: 0,
+            cause: broken.then_some(libp2p::swarm::ConnectionError::KeepAliveTimeout),
+        }
+    }
+
+    fn registered(registrar: &Registrar, ttl: Ttl) -> SwarmEvent<ClientBehaviourEvent> {
+        SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+            rendezvous::client::Event::Registered {
+                rendezvous_node: registrar.point.peer_id,
+                ttl,
+                namespace: registrar.registrations[0].namespace.clone(),
+            },
+        ))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut registrar = registrar();
+        let mut expected = INITIAL_BACKOFF;
+        for _ in 0..10 {
+            let before = Instant::now();
+            registrar.back_off();
+            let redial = registrar.redial.unwrap();
+            assert!(redial >= before + expected && redial <= Instant::now() + expected);
+            expected = (expected * 2).min(MAX_BACKOFF);
+            assert_eq!(registrar.backoff, expected);
+        }
+        assert_eq!(registrar.backoff, MAX_BACKOFF);
+        assert!(matches!(
+            registrar.take_failure(),
+            Some(Failure::Unreachable)
+        ));
+        assert!(registrar.take_failure().is_none());
+    }
+
+    #[tokio::test]
+    async fn registrations_are_renewed_after_a_fraction_of_the_ttl() {
+        let mut swarm = swarm();
+        let mut registrar = registrar();
+        let point = registrar.point.peer_id;
+
+        // 未连接时到期即拨号，拨号期间不再计时
+        assert!(registrar.deadline(&swarm).unwrap() <= Instant::now());
+        registrar.run_due(&mut swarm).unwrap();
+        assert!(registrar.dialing);
+        assert_eq!(registrar.deadline(&swarm), None);
+
+        registrar.on_event(&mut swarm, &established(point)).unwrap();
+        assert!(registrar.connected && !registrar.dialing);
+        assert_eq!(registrar.backoff, INITIAL_BACKOFF);
+        // 注册已发出，等待应答
+        assert_eq!(registrar.registrations[0].due, None);
+        assert_eq!(registrar.deadline(&swarm), None);
+
+        let before = Instant::now();
+        registrar
+            .on_event(&mut swarm, &registered(&registrar, 120))
+            .unwrap();
+        let renew = Duration::from_secs(120).mul_f64(RENEW_FRACTION);
+        let due = registrar.registrations[0].due.unwrap();
+        assert!(due >= before + renew && due <= Instant::now() + renew);
+        assert_eq!(registrar.deadline(&swarm), Some(due));
+        // 还没到续期的时候
+        registrar.run_due(&mut swarm).unwrap();
+        assert_eq!(registrar.registrations[0].due, Some(due));
+    }
+
+    #[tokio::test]
+    async fn broken_connections_are_redialed_and_registered_again() {
+        let mut swarm = swarm();
+        let mut registrar = registrar();
+        let point = registrar.point.peer_id;
+        // 连接由别处建立，注册器没有拨号
+        registrar.on_event(&mut swarm, &established(point)).unwrap();
+        assert_eq!(registrar.registrations[0].due, None);
+
+        // 注册的应答随连接丢失
+        let before = Instant::now();
+        registrar
+            .on_event(&mut swarm, &closed(point, true))
+            .unwrap();
+        assert!(!registrar.connected);
+        assert!(registrar.registrations[0].due.unwrap() <= Instant::now());
+        let redial = registrar.redial.unwrap();
+        assert!(redial >= before + INITIAL_BACKOFF);
+        assert_eq!(registrar.deadline(&swarm), Some(redial));
+        assert_eq!(registrar.backoff, INITIAL_BACKOFF * 2);
+
+        // 重连后立即重新注册，退避复位
+        registrar.redial = Some(Instant::now());
+        registrar.run_due(&mut swarm).unwrap();
+        assert!(registrar.dialing && registrar.redial.is_none());
+        registrar.on_event(&mut swarm, &established(point)).unwrap();
+        assert_eq!(registrar.registrations[0].due, None);
+        assert_eq!(registrar.backoff, INITIAL_BACKOFF);
+
+        // 空闲关闭不退避，续期时再拨号
+        registrar
+            .on_event(&mut swarm, &registered(&registrar, 120))
+            .unwrap();
+        let due = registrar.registrations[0].due.unwrap();
+        registrar
+            .on_event(&mut swarm, &closed(point, false))
+            .unwrap();
+        assert_eq!(registrar.redial, None);
+        assert_eq!(registrar.registrations[0].due, Some(due));
+        assert_eq!(registrar.deadline(&swarm), Some(due));
+    }
+}
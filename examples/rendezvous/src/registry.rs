@@ -0,0 +1,884 @@
+//! Registrations held by the server, and the file they are kept in.
+//!
+//! With a store (`--store`) the registrations are written to it together with
+//! their expiry times. Changes only mark the registry dirty, and the server
+//! writes the store once per expiry tick if anything changed, so a burst of
+//! registrations costs one write rather than one per registration. On
+//! startup the server loads the store, drops what expired in the meantime and
+//! serves the rest with their remaining TTL, so discoveries keep working
+//! across restarts and peers need not register again.
+//!
+//! Registrations are accepted within [`Limits`]: the requested TTL has to lie
+//! between `--min-ttl` and `--max-ttl`, and a new registration is refused
+//! with `Unavailable` while its namespace, its peer or the number of
+//! namespaces is at its quota. Renewals always fit. The policy file may
+//! override the TTL bounds and the size of single namespaces, see
+//! [`NamespaceLimits`].
+//!
+//! With federation, registrations made at other rendezvous points are held as
+//! replicas next to the ones made here, see [`crate::federation`].
+//!
+//! Peers banned through the admin API are kept in the store as well, so a ban
+//! outlives restarts; their registrations, replicas included, are refused.
+//!
+//! Cookies are positions in the sequence of registrations: every registration
+//! and renewal gets the next sequence number, and a discovery with a cookie
+//! only returns what was registered after it. The sequence is stored as well,
+//! so cookies handed out before a restart stay valid.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use libp2p::{
+    core::{PeerRecord, SignedEnvelope},
+    rendezvous::{ErrorCode, Namespace, Registration, Ttl},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    namespace::{self, NamespaceInfo},
+    protocol::{Cookie, NewRegistration},
+};
+
+pub const DEFAULT_TTL: Ttl = 2 * 60 * 60;
+pub const MIN_TTL: Ttl = 2 * 60 * 60;
+pub const MAX_TTL: Ttl = 72 * 60 * 60;
+pub const MAX_REGISTRATIONS_PER_NAMESPACE: usize = 10_000;
+pub const MAX_REGISTRATIONS_PER_PEER: usize = 64;
+pub const MAX_NAMESPACES: usize = 1_000;
+
+/// What a new registration may ask for.
+#[derive(Debug, Clone, clap::Args)]
+pub struct Limits {
+    /// Shortest TTL a peer may register for, in seconds
+    #[arg(long, default_value_t = MIN_TTL)]
+    pub min_ttl: Ttl,
+    /// Longest TTL a peer may register for, in seconds
+    #[arg(long, default_value_t = MAX_TTL)]
+    pub max_ttl: Ttl,
+    /// Registrations one namespace holds at most
+    #[arg(long, default_value_t = MAX_REGISTRATIONS_PER_NAMESPACE)]
+    pub max_registrations_per_namespace: usize,
+    /// Namespaces one peer is registered in at most
+    #[arg(long, default_value_t = MAX_REGISTRATIONS_PER_PEER)]
+    pub max_registrations_per_peer: usize,
+    /// Namespaces the server holds registrations in at most
+    #[arg(long, default_value_t = MAX_NAMESPACES)]
+    pub max_namespaces: usize,
+    /// Overrides by namespace, from the policy file
+    #[arg(skip)]
+    pub namespaces: BTreeMap<String, NamespaceLimits>,
+}
+
+/// Limits of one namespace that differ from the server's, as given under
+/// `"limits"` in the policy file, see [`crate::policy`].
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NamespaceLimits {
+    pub min_ttl: Option<Ttl>,
+    pub max_ttl: Option<Ttl>,
+    pub max_registrations: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            min_ttl: MIN_TTL,
+            max_ttl: MAX_TTL,
+            max_registrations_per_namespace: MAX_REGISTRATIONS_PER_NAMESPACE,
+            max_registrations_per_peer: MAX_REGISTRATIONS_PER_PEER,
+            max_namespaces: MAX_NAMESPACES,
+            namespaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl Limits {
+    /// Shortest and longest TTL in `namespace`.
+    fn ttl_bounds(&self, namespace: &str) -> (Ttl, Ttl) {
+        let limits = self.namespaces.get(namespace);
+        (
+            limits.and_then(|l| l.min_ttl).unwrap_or(self.min_ttl),
+            limits.and_then(|l| l.max_ttl).unwrap_or(self.max_ttl),
+        )
+    }
+
+    /// TTL of registrations in `namespace` that do not ask for one.
+    fn default_ttl(&self, namespace: &str) -> Ttl {
+        let (min, max) = self.ttl_bounds(namespace);
+        DEFAULT_TTL.clamp(min, max)
+    }
+
+    /// Registrations `namespace` holds at most.
+    fn max_registrations(&self, namespace: &str) -> usize {
+        self.namespaces
+            .get(namespace)
+            .and_then(|l| l.max_registrations)
+            .unwrap_or(self.max_registrations_per_namespace)
+    }
+
+    fn check(&self) -> anyhow::Result<()> {
+        if self.min_ttl > self.max_ttl {
+            bail!(
+                "--min-ttl {} is above --max-ttl {}",
+                self.min_ttl,
+                self.max_ttl
+            );
+        }
+        for name in self.namespaces.keys() {
+            let (min, max) = self.ttl_bounds(name);
+            if min > max {
+                bail!(
+                    "namespace '{}': min_ttl {} is above max_ttl {}",
+                    name,
+                    min,
+                    max
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Registrations returned by one discovery at most
+const MAX_DISCOVER_LIMIT: u64 = 1000;
+
+/// How much later a replica of the same peer record has to expire to replace
+/// the one held, in milliseconds; covers the time replicas are in transit
+const REFRESH_TOLERANCE_MS: u64 = 2000;
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub namespace: Namespace,
+    pub record: PeerRecord,
+    /// TTL granted at registration, in seconds
+    pub ttl: Ttl,
+    /// Unix timestamp in milliseconds
+    pub expires: u64,
+    /// Rendezvous point the registration was made at, `None` if here
+    pub origin: Option<PeerId>,
+    seq: u64,
+}
+
+impl Entry {
+    pub fn peer(&self) -> PeerId {
+        self.record.peer_id()
+    }
+
+    /// Seconds until the registration expires.
+    pub fn remaining(&self) -> Ttl {
+        self.expires.saturating_sub(now_millis()) / 1000
+    }
+
+    fn registration(&self) -> Registration {
+        Registration {
+            namespace: self.namespace.clone(),
+            record: self.record.clone(),
+            ttl: self.remaining(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    seq: u64,
+    registrations: Vec<StoredEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bans: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    namespace: String,
+    /// Signed envelope of the peer record, base64 encoded
+    record: String,
+    ttl: Ttl,
+    expires: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    origin: Option<String>,
+    seq: u64,
+}
+
+impl StoredEntry {
+    fn new(entry: &Entry) -> Self {
+        Self {
+            namespace: entry.namespace.to_string(),
+            record: encode_record(&entry.record),
+            ttl: entry.ttl,
+            expires: entry.expires,
+            origin: entry.origin.map(|p| p.to_string()),
+            seq: entry.seq,
+        }
+    }
+
+    fn load(self) -> anyhow::Result<Entry> {
+        Ok(Entry {
+            namespace: Namespace::new(self.namespace)?,
+            record: decode_record(&self.record)?,
+            ttl: self.ttl,
+            expires: self.expires,
+            origin: self.origin.map(|p| p.parse()).transpose()?,
+            seq: self.seq,
+        })
+    }
+}
+
+/// Registrations by namespace and peer.
+#[derive(Debug, Default)]
+pub struct Registry {
+    entries: BTreeMap<(String, PeerId), Entry>,
+    /// Last sequence number handed out
+    seq: u64,
+    /// Peers banned through the admin API
+    bans: BTreeSet<PeerId>,
+    path: Option<PathBuf>,
+    /// Changed since the store was last written
+    dirty: bool,
+    limits: Limits,
+}
+
+impl Registry {
+    /// Loads the registrations in `path` that have not expired yet; they are
+    /// kept even if they exceed `limits`.
+    pub fn open(path: Option<PathBuf>, limits: Limits) -> anyhow::Result<Self> {
+        limits.check()?;
+        let mut registry = Self {
+            path,
+            limits,
+            ..Default::default()
+        };
+        let Some(path) = registry.path.clone().filter(|p| p.exists()) else {
+            return Ok(registry);
+        };
+        let stored: Stored = serde_json::from_slice(
+            &std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?,
+        )
+        .with_context(|| format!("{}: invalid registration store", path.display()))?;
+        registry.seq = stored.seq;
+        for peer in stored.bans {
+            match peer.parse() {
+                Ok(peer) => {
+                    registry.bans.insert(peer);
+                }
+                Err(_) => warn!("Dropping an invalid stored ban {:?}", peer),
+            }
+        }
+        let now = now_millis();
+        for stored in stored.registrations {
+            if stored.expires <= now {
+                continue;
+            }
+            match stored.load() {
+                Ok(entry) => {
+                    registry.seq = registry.seq.max(entry.seq);
+                    registry
+                        .entries
+                        .insert((entry.namespace.to_string(), entry.peer()), entry);
+                }
+                Err(e) => warn!("Dropping a stored registration: {:#}", e),
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.entries.values()
+    }
+
+    /// Registers or renews `peer` in a namespace; returns the granted TTL.
+    pub fn register(
+        &mut self,
+        peer: PeerId,
+        registration: NewRegistration,
+    ) -> Result<Ttl, ErrorCode> {
+        // 只接受节点为自己签名的记录
+        if registration.record.peer_id() != peer || self.bans.contains(&peer) {
+            return Err(ErrorCode::NotAuthorized);
+        }
+        if namespace::parse(&registration.namespace.to_string()).is_err() {
+            return Err(ErrorCode::InvalidNamespace);
+        }
+        let key = (registration.namespace.to_string(), peer);
+        let ttl = registration
+            .ttl
+            .unwrap_or_else(|| self.limits.default_ttl(&key.0));
+        let (min_ttl, max_ttl) = self.limits.ttl_bounds(&key.0);
+        if !(min_ttl..=max_ttl).contains(&ttl) {
+            return Err(ErrorCode::InvalidTtl);
+        }
+        if !self.entries.contains_key(&key) {
+            self.check_quotas(&key.0, peer)?;
+        }
+        self.seq += 1;
+        let entry = Entry {
+            namespace: registration.namespace,
+            record: registration.record,
+            ttl,
+            expires: now_millis().saturating_add(ttl.saturating_mul(1000)),
+            origin: None,
+            seq: self.seq,
+        };
+        self.entries.insert(key, entry);
+        self.dirty = true;
+        Ok(ttl)
+    }
+
+    /// Whether a new registration of `peer` in `namespace` fits the quotas.
+    fn check_quotas(&self, namespace: &str, peer: PeerId) -> Result<(), ErrorCode> {
+        let limits = &self.limits;
+        let in_namespace = self
+            .entries
+            .keys()
+            .filter(|(ns, _)| ns == namespace)
+            .count();
+        if in_namespace == 0 && self.namespace_count() >= limits.max_namespaces {
+            info!(
+                "No room for namespace '{}', {} namespaces in use",
+                namespace, limits.max_namespaces
+            );
+            return Err(ErrorCode::Unavailable);
+        }
+        if in_namespace >= limits.max_registrations(namespace) {
+            info!(
+                "Namespace '{}' is full with {} registrations",
+                namespace, in_namespace
+            );
+            return Err(ErrorCode::Unavailable);
+        }
+        let of_peer = self.entries.keys().filter(|(_, p)| *p == peer).count();
+        if of_peer >= limits.max_registrations_per_peer {
+            info!("Peer {} already holds {} registrations", peer, of_peer);
+            return Err(ErrorCode::Unavailable);
+        }
+        Ok(())
+    }
+
+    fn namespace_count(&self) -> usize {
+        let mut names: Vec<&String> = self.entries.keys().map(|(ns, _)| ns).collect();
+        names.dedup();
+        names.len()
+    }
+
+    pub fn get(&self, namespace: &Namespace, peer: PeerId) -> Option<&Entry> {
+        self.entries.get(&(namespace.to_string(), peer))
+    }
+
+    /// Removes and returns the registration of `peer` in `namespace`.
+    pub fn unregister(&mut self, peer: PeerId, namespace: &Namespace) -> Option<Entry> {
+        let removed = self.entries.remove(&(namespace.to_string(), peer));
+        self.dirty |= removed.is_some();
+        removed
+    }
+
+    /// Holds a registration made at the rendezvous point `origin` that expires
+    /// in `remaining_ms`, unless the peer is banned, a new one does not fit the
+    /// quotas or the one held for the peer is as recent; returns whether it
+    /// was taken. The TTL is cut to the longest one of the namespace.
+    pub fn replicate(
+        &mut self,
+        origin: PeerId,
+        namespace: Namespace,
+        record: PeerRecord,
+        ttl: Ttl,
+        remaining_ms: u64,
+    ) -> bool {
+        let peer = record.peer_id();
+        if self.bans.contains(&peer) {
+            return false;
+        }
+        let key = (namespace.to_string(), peer);
+        if !self.entries.contains_key(&key) && self.check_quotas(&key.0, peer).is_err() {
+            return false;
+        }
+        // 对方的上限可能比本地宽，剩余时间也不能超过截短后的 TTL
+        let ttl = ttl.min(self.limits.ttl_bounds(&key.0).1);
+        let expires = now_millis().saturating_add(remaining_ms.min(ttl.saturating_mul(1000)));
+        if let Some(held) = self.entries.get(&key) {
+            // 同一条记录只在明显更晚过期时才替换，避免重复同步反复刷新序号
+            let newer = record.seq() > held.record.seq()
+                || (record.seq() == held.record.seq()
+                    && expires > held.expires + REFRESH_TOLERANCE_MS);
+            if !newer {
+                return false;
+            }
+        }
+        self.seq += 1;
+        let entry = Entry {
+            namespace,
+            record,
+            ttl,
+            expires,
+            origin: Some(origin),
+            seq: self.seq,
+        };
+        self.entries.insert(key, entry);
+        self.dirty = true;
+        true
+    }
+
+    /// Removes the replica of `peer` in `namespace` made at `origin`; a
+    /// registration held from elsewhere stays.
+    pub fn remove_replica(&mut self, origin: PeerId, namespace: &Namespace, peer: PeerId) -> bool {
+        let key = (namespace.to_string(), peer);
+        if self.entries.get(&key).and_then(|e| e.origin) != Some(origin) {
+            return false;
+        }
+        self.entries.remove(&key);
+        self.dirty = true;
+        true
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = &PeerId> {
+        self.bans.iter()
+    }
+
+    /// Bans `peer`; removes and returns all its registrations.
+    pub fn ban(&mut self, peer: PeerId) -> Vec<Entry> {
+        self.bans.insert(peer);
+        let keys: Vec<(String, PeerId)> = self
+            .entries
+            .keys()
+            .filter(|(_, p)| *p == peer)
+            .cloned()
+            .collect();
+        let removed: Vec<Entry> = keys
+            .into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .collect();
+        self.dirty = true;
+        removed
+    }
+
+    /// Lifts the ban of `peer`; returns whether it was banned.
+    pub fn unban(&mut self, peer: PeerId) -> bool {
+        let unbanned = self.bans.remove(&peer);
+        self.dirty |= unbanned;
+        unbanned
+    }
+
+    /// Namespaces with registrations that have not expired, sorted by name.
+    pub fn namespaces(&self) -> Vec<NamespaceInfo> {
+        let now = now_millis();
+        let mut namespaces: Vec<NamespaceInfo> = Vec::new();
+        // 条目按 (命名空间, 节点) 排序，同一命名空间相邻
+        for ((name, _), entry) in &self.entries {
+            if entry.expires <= now {
+                continue;
+            }
+            match namespaces.last_mut() {
+                Some(info) if &info.name == name => info.registrations += 1,
+                _ => namespaces.push(NamespaceInfo {
+                    name: name.clone(),
+                    registrations: 1,
+                }),
+            }
+        }
+        namespaces
+    }
+
+    /// Registrations in `namespace`, or in all namespaces, made after `cookie`.
+    pub fn discover(
+        &self,
+        namespace: Option<&Namespace>,
+        cookie: Option<&Cookie>,
+        limit: Option<u64>,
+    ) -> Result<(Vec<Registration>, Cookie), ErrorCode> {
+        if let Some(cookie) = cookie {
+            if cookie.namespace.as_ref() != namespace || cookie.id > self.seq {
+                return Err(ErrorCode::InvalidCookie);
+            }
+        }
+        let after = cookie.map_or(0, |c| c.id);
+        let now = now_millis();
+        let mut found: Vec<&Entry> = self
+            .entries
+            .values()
+            .filter(|e| namespace.is_none_or(|ns| &e.namespace == ns))
+            .filter(|e| e.seq > after && e.expires > now)
+            .collect();
+        found.sort_by_key(|e| e.seq);
+        let limit = limit.unwrap_or(MAX_DISCOVER_LIMIT).min(MAX_DISCOVER_LIMIT) as usize;
+        // 截断时 cookie 停在最后返回的注册，下次从其后继续
+        let id = if found.len() > limit {
+            found.truncate(limit);
+            found.last().map_or(after, |e| e.seq)
+        } else {
+            self.seq
+        };
+        let cookie = Cookie {
+            id,
+            namespace: namespace.cloned(),
+        };
+        Ok((found.into_iter().map(Entry::registration).collect(), cookie))
+    }
+
+    /// Removes and returns the registrations that expired.
+    pub fn expire(&mut self) -> Vec<Entry> {
+        let now = now_millis();
+        let expired: Vec<(String, PeerId)> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.expires <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let expired: Vec<Entry> = expired
+            .into_iter()
+            .filter_map(|key| self.entries.remove(&key))
+            .collect();
+        self.dirty |= !expired.is_empty();
+        expired
+    }
+
+    /// Writes the store if anything changed since it was last written.
+    pub fn flush(&mut self) {
+        if !std::mem::take(&mut self.dirty) {
+            return;
+        }
+        let Some(path) = &self.path else {
+            return;
+        };
+        let stored = Stored {
+            seq: self.seq,
+            registrations: self.entries.values().map(StoredEntry::new).collect(),
+            bans: self.bans.iter().map(|p| p.to_string()).collect(),
+        };
+        if let Err(e) = write_atomically(path, &serde_json::to_vec_pretty(&stored).unwrap()) {
+            error!("Failed to save the registrations: {:?}", e);
+        }
+    }
+}
+
+/// Signed envelope of `record`, base64 encoded.
+pub fn encode_record(record: &PeerRecord) -> String {
+    BASE64.encode(record.to_signed_envelope().into_protobuf_encoding())
+}
+
+/// Decodes a record of [`encode_record`] and checks its signature.
+pub fn decode_record(record: &str) -> anyhow::Result<PeerRecord> {
+    let envelope = SignedEnvelope::from_protobuf_encoding(&BASE64.decode(record)?)?;
+    Ok(PeerRecord::from_signed_envelope(envelope)?)
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, bytes).with_context(|| format!("writing {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("renaming to {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn registration(
+        keypair: &Keypair,
+        namespace: &'static str,
+        ttl: Option<Ttl>,
+    ) -> NewRegistration {
+        let addr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        NewRegistration {
+            namespace: Namespace::from_static(namespace),
+            record: PeerRecord::new(keypair, vec![addr]).unwrap(),
+            ttl,
+        }
+    }
+
+    /// Registers a new peer in `namespace`; returns its peer id.
+    fn register(registry: &mut Registry, namespace: &'static str) -> Result<PeerId, ErrorCode> {
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        registry.register(peer, registration(&keypair, namespace, None))?;
+        Ok(peer)
+    }
+
+    fn peers(registrations: &[Registration]) -> Vec<PeerId> {
+        registrations.iter().map(|r| r.record.peer_id()).collect()
+    }
+
+    #[test]
+    fn cookie_returns_only_later_registrations() {
+        let mut registry = Registry::default();
+        let namespace = Namespace::from_static("chat");
+        let first = register(&mut registry, "chat").unwrap();
+        register(&mut registry, "other").unwrap();
+
+        let (found, cookie) = registry.discover(Some(&namespace), None, None).unwrap();
+        assert_eq!(peers(&found), vec![first]);
+        let (found, cookie) = registry
+            .discover(Some(&namespace), Some(&cookie), None)
+            .unwrap();
+        assert!(found.is_empty());
+
+        let second = register(&mut registry, "chat").unwrap();
+        let (found, _) = registry
+            .discover(Some(&namespace), Some(&cookie), None)
+            .unwrap();
+        assert_eq!(peers(&found), vec![second]);
+    }
+
+    #[test]
+    fn truncated_discovery_continues_after_the_last_returned() {
+        let mut registry = Registry::default();
+        let namespace = Namespace::from_static("chat");
+        let registered: Vec<PeerId> = (0..3)
+            .map(|_| register(&mut registry, "chat").unwrap())
+            .collect();
+
+        let (found, cookie) = registry.discover(Some(&namespace), None, Some(2)).unwrap();
+        assert_eq!(peers(&found), registered[..2]);
+        let (found, _) = registry
+            .discover(Some(&namespace), Some(&cookie), Some(2))
+            .unwrap();
+        assert_eq!(peers(&found), registered[2..]);
+    }
+
+    #[test]
+    fn foreign_cookies_are_refused() {
+        let mut registry = Registry::default();
+        register(&mut registry, "chat").unwrap();
+        let chat = Namespace::from_static("chat");
+        let (_, cookie) = registry.discover(Some(&chat), None, None).unwrap();
+
+        let other = Namespace::from_static("other");
+        assert!(matches!(
+            registry.discover(Some(&other), Some(&cookie), None),
+            Err(ErrorCode::InvalidCookie)
+        ));
+        assert!(matches!(
+            registry.discover(None, Some(&cookie), None),
+            Err(ErrorCode::InvalidCookie)
+        ));
+        // 来自未来的 cookie 不是这个服务器发的
+        let ahead = Cookie {
+            id: cookie.id + 1,
+            namespace: Some(chat.clone()),
+        };
+        assert!(matches!(
+            registry.discover(Some(&chat), Some(&ahead), None),
+            Err(ErrorCode::InvalidCookie)
+        ));
+    }
+
+    #[test]
+    fn records_of_other_peers_are_refused() {
+        let mut registry = Registry::default();
+        let registration = registration(&Keypair::generate_ed25519(), "chat", None);
+        assert!(matches!(
+            registry.register(PeerId::random(), registration),
+            Err(ErrorCode::NotAuthorized)
+        ));
+    }
+
+    #[test]
+    fn store_keeps_registrations_cookies_and_bans() {
+        let path = std::env::temp_dir().join(format!("rzv-registry-{}.json", std::process::id()));
+        let namespace = Namespace::from_static("chat");
+        let banned = PeerId::random();
+        let (peer, cookie) = {
+            let mut registry = Registry::open(Some(path.clone()), Limits::default()).unwrap();
+            let peer = register(&mut registry, "chat").unwrap();
+            registry.ban(banned);
+            let (_, cookie) = registry.discover(Some(&namespace), None, None).unwrap();
+            // 变更只标记为脏，定时写入
+            assert!(!path.exists());
+            registry.flush();
+            (peer, cookie)
+        };
+
+        let mut registry = Registry::open(Some(path.clone()), Limits::default()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(registry.len(), 1);
+        assert!(registry.get(&namespace, peer).is_some());
+        assert_eq!(registry.bans().collect::<Vec<_>>(), vec![&banned]);
+        let (found, _) = registry
+            .discover(Some(&namespace), Some(&cookie), None)
+            .unwrap();
+        assert!(found.is_empty());
+
+        let later = register(&mut registry, "chat").unwrap();
+        let (found, _) = registry
+            .discover(Some(&namespace), Some(&cookie), None)
+            .unwrap();
+        assert_eq!(peers(&found), vec![later]);
+    }
+
+    fn limits(namespace: usize, peer: usize, namespaces: usize) -> Limits {
+        Limits {
+            max_registrations_per_namespace: namespace,
+            max_registrations_per_peer: peer,
+            max_namespaces: namespaces,
+            ..Limits::default()
+        }
+    }
+
+    #[test]
+    fn new_registrations_fit_the_quotas_and_renewals_always_do() {
+        let mut registry = Registry::open(None, limits(2, 64, 2)).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        registry
+            .register(peer, registration(&keypair, "chat", None))
+            .unwrap();
+        register(&mut registry, "chat").unwrap();
+        // 命名空间已满
+        assert!(matches!(
+            register(&mut registry, "chat"),
+            Err(ErrorCode::Unavailable)
+        ));
+        assert!(registry
+            .register(peer, registration(&keypair, "chat", None))
+            .is_ok());
+
+        register(&mut registry, "files").unwrap();
+        // 命名空间个数已满
+        assert!(matches!(
+            register(&mut registry, "third"),
+            Err(ErrorCode::Unavailable)
+        ));
+    }
+
+    #[test]
+    fn peers_hold_a_limited_number_of_registrations() {
+        let mut registry = Registry::open(None, limits(100, 2, 100)).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        for namespace in ["a", "b"] {
+            registry
+                .register(peer, registration(&keypair, namespace, None))
+                .unwrap();
+        }
+        assert!(matches!(
+            registry.register(peer, registration(&keypair, "c", None)),
+            Err(ErrorCode::Unavailable)
+        ));
+        assert!(register(&mut registry, "c").is_ok());
+    }
+
+    #[test]
+    fn ttl_has_to_lie_within_the_bounds() {
+        let mut registry = Registry::default();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        for ttl in [MIN_TTL - 1, MAX_TTL + 1] {
+            assert!(matches!(
+                registry.register(peer, registration(&keypair, "chat", Some(ttl))),
+                Err(ErrorCode::InvalidTtl)
+            ));
+        }
+        assert_eq!(
+            registry.register(peer, registration(&keypair, "chat", None)),
+            Ok(DEFAULT_TTL)
+        );
+    }
+
+    #[test]
+    fn huge_ttls_saturate() {
+        let limits = Limits {
+            max_ttl: Ttl::MAX,
+            ..Limits::default()
+        };
+        let mut registry = Registry::open(None, limits).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        let registration = registration(&keypair, "chat", Some(Ttl::MAX));
+        let namespace = registration.namespace.clone();
+        assert_eq!(registry.register(peer, registration), Ok(Ttl::MAX));
+        assert_eq!(registry.get(&namespace, peer).unwrap().expires, u64::MAX);
+    }
+
+    #[test]
+    fn namespaces_may_override_the_limits() {
+        let mut limits = limits(100, 64, 100);
+        limits.namespaces.insert(
+            "small".to_string(),
+            NamespaceLimits {
+                min_ttl: Some(60),
+                max_ttl: Some(600),
+                max_registrations: Some(1),
+            },
+        );
+        let mut registry = Registry::open(None, limits.clone()).unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        assert!(matches!(
+            registry.register(peer, registration(&keypair, "small", Some(601))),
+            Err(ErrorCode::InvalidTtl)
+        ));
+        // 默认 TTL 截到命名空间的上限
+        assert_eq!(
+            registry.register(peer, registration(&keypair, "small", None)),
+            Ok(600)
+        );
+        assert!(matches!(
+            register(&mut registry, "small"),
+            Err(ErrorCode::Unavailable)
+        ));
+        // 其他命名空间仍用服务器的限制
+        assert!(matches!(
+            registry.register(peer, registration(&keypair, "big", Some(600))),
+            Err(ErrorCode::InvalidTtl)
+        ));
+        assert!(register(&mut registry, "big").is_ok());
+
+        limits.namespaces.get_mut("small").unwrap().min_ttl = Some(601);
+        assert!(Registry::open(None, limits).is_err());
+    }
+
+    #[test]
+    fn replicas_are_cut_to_the_local_bounds() {
+        let mut limits = Limits::default();
+        limits.namespaces.insert(
+            "small".to_string(),
+            NamespaceLimits {
+                min_ttl: Some(60),
+                max_ttl: Some(600),
+                max_registrations: None,
+            },
+        );
+        let mut registry = Registry::open(None, limits).unwrap();
+        let origin = PeerId::random();
+        let keypair = Keypair::generate_ed25519();
+        let registration = registration(&keypair, "small", None);
+        let (namespace, peer) = (
+            registration.namespace.clone(),
+            keypair.public().to_peer_id(),
+        );
+        assert!(registry.replicate(
+            origin,
+            namespace.clone(),
+            registration.record.clone(),
+            MAX_TTL,
+            u64::MAX
+        ));
+        let entry = registry.get(&namespace, peer).unwrap();
+        assert_eq!(entry.ttl, 600);
+        assert!(entry.remaining() <= 600);
+
+        registry.ban(peer);
+        assert!(!registry.replicate(origin, namespace, registration.record, 600, 1000));
+    }
+}
@@ -0,0 +1,412 @@
+//! The rendezvous point.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    path::PathBuf,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use libp2p::{
+    multiaddr::Protocol,
+    request_response,
+    swarm::{ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    admin::{self, AdminError, AdminRequest, PeerInfo, RegistrationInfo},
+    behaviour::{build_swarm, ServerBehaviour, ServerBehaviourEvent},
+    capability::TokenResults,
+    federation::{self, Federation},
+    keyfile,
+    policy::Policy,
+    protocol::Message,
+    registry::{Limits, Registry},
+};
+
+/// How often expired registrations are removed and the store is written
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub listen: Multiaddr,
+    /// Key file of the server's identity, see [`keyfile`]
+    pub key_file: PathBuf,
+    /// File to write the server's full addresses to, for the clients
+    pub export_addr: Option<PathBuf>,
+    /// File the registrations are kept in, see [`crate::registry`]
+    pub store: Option<PathBuf>,
+    /// TTLs and quotas of registrations, see [`crate::registry`]
+    pub limits: Limits,
+    /// Namespace policy file, see [`crate::policy`]
+    pub policy: Option<PathBuf>,
+    /// Address of the HTTP admin API, see [`admin`]
+    pub admin: Option<SocketAddr>,
+    /// Other rendezvous points to share registrations with, see [`federation`]
+    pub federation: Vec<Multiaddr>,
+    pub idle_timeout: Duration,
+}
+
+/// Connections of a peer and the addresses they come from
+type Connected = BTreeMap<PeerId, HashMap<ConnectionId, Multiaddr>>;
+
+/// Serves registrations and discoveries until the swarm ends.
+pub async fn run(options: ServerOptions) -> anyhow::Result<()> {
+    let keypair = keyfile::load_or_generate(&options.key_file)?;
+    let local_peer_id = keypair.public().to_peer_id();
+    info!("Local peer id: {}", local_peer_id);
+
+    let mut policy = match &options.policy {
+        Some(path) => {
+            let policy = Policy::load(path)?;
+            info!(
+                "Loaded a policy restricting {} namespaces with {} admins",
+                policy.restricted(),
+                policy.admins()
+            );
+            policy
+        }
+        None => Policy::default(),
+    };
+    let mut limits = options.limits;
+    limits.namespaces = policy.limits().clone();
+    let mut registry = Registry::open(options.store, limits)?;
+    if !registry.is_empty() {
+        info!("Loaded {} registrations", registry.len());
+    }
+
+    let mut federation = Federation::new(options.federation)?;
+    if !federation.is_empty() {
+        info!("Federated with {} rendezvous points", federation.len());
+    }
+
+    let mut swarm = build_swarm(keypair, options.idle_timeout, ServerBehaviour::new)?;
+    swarm.listen_on(options.listen)?;
+    for peer in registry.bans() {
+        swarm.behaviour_mut().blocked.block_peer(*peer);
+    }
+
+    // 管理接口运行在独立任务中，请求通过 channel 交给事件循环处理
+    let (admin_tx, mut admin_rx) = mpsc::channel(64);
+    if let Some(addr) = options.admin {
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(addr, admin_tx).await {
+                error!("Admin API error: {:?}", e);
+            }
+        });
+    }
+
+    let mut exported: Vec<Multiaddr> = Vec::new();
+    let mut connected = Connected::new();
+    let mut expiry_tick = tokio::time::interval(EXPIRY_INTERVAL);
+    let mut sync_tick = tokio::time::interval(federation::SYNC_INTERVAL);
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    let address = address.with(Protocol::P2p(local_peer_id));
+                    info!("Listening on {}", address);
+                    if let Some(path) = &options.export_addr {
+                        exported.push(address);
+                        let lines: Vec<String> = exported.iter().map(|a| a.to_string()).collect();
+                        std::fs::write(path, lines.join("\n") + "\n")?;
+                    }
+                }
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    connection_id,
+                    endpoint,
+                    num_established,
+                    ..
+                } => {
+                    info!("Connected to {}", peer_id);
+                    connected
+                        .entry(peer_id)
+                        .or_default()
+                        .insert(connection_id, endpoint.get_remote_address().clone());
+                    // 成员（重新）连上时推送全部本地注册，补上它离线期间错过的
+                    if num_established.get() == 1 && federation.is_member(&peer_id) {
+                        federation.sync(&mut swarm, &registry, Some(peer_id));
+                    }
+                }
+                SwarmEvent::ConnectionClosed {
+                    peer_id,
+                    connection_id,
+                    ..
+                } => {
+                    info!("Disconnected from {}", peer_id);
+                    if let Some(connections) = connected.get_mut(&peer_id) {
+                        connections.remove(&connection_id);
+                        if connections.is_empty() {
+                            connected.remove(&peer_id);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Rendezvous(
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { request, channel, .. },
+                    },
+                )) => {
+                    if let Some(response) =
+                        handle_request(&mut registry, &mut policy, &mut federation, peer, request)
+                    {
+                        if swarm
+                            .behaviour_mut()
+                            .rendezvous
+                            .send_response(channel, response)
+                            .is_err()
+                        {
+                            debug!("Peer {} left before its response", peer);
+                        }
+                    }
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Namespaces(
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { channel, .. },
+                    },
+                )) => {
+                    let namespaces = registry.namespaces();
+                    info!("Listed {} namespaces for peer {}", namespaces.len(), peer);
+                    let _ = swarm
+                        .behaviour_mut()
+                        .namespaces
+                        .send_response(channel, namespaces);
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Capabilities(
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { request, channel, .. },
+                    },
+                )) => {
+                    let results = policy.present(peer, &request.0);
+                    for result in &results {
+                        match result {
+                            Ok(namespace) => info!("Peer {} holds a token for namespace '{}'", peer, namespace),
+                            Err(e) => info!("Refused a token of {}: {}", peer, e),
+                        }
+                    }
+                    let _ = swarm
+                        .behaviour_mut()
+                        .capabilities
+                        .send_response(channel, TokenResults(results));
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Federation(
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Request { request, channel, .. },
+                    },
+                )) => {
+                    let response = federation.apply(&mut registry, peer, request);
+                    match &response {
+                        Ok(0) => debug!("Nothing new from federation member {}", peer),
+                        Ok(taken) => info!("Took {} registrations from federation member {}", taken, peer),
+                        Err(e) => warn!("Refused a federation request of {}: {}", peer, e),
+                    }
+                    let _ = swarm
+                        .behaviour_mut()
+                        .federation
+                        .send_response(channel, response);
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Federation(
+                    request_response::Event::Message {
+                        peer,
+                        message: request_response::Message::Response { response: Err(e), .. },
+                    },
+                )) => {
+                    warn!("Federation member {} refused our registrations: {}", peer, e);
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Federation(
+                    request_response::Event::OutboundFailure { peer, error, .. },
+                )) => {
+                    // 同步请求会在下次连接或定时同步时重发
+                    warn!("Failed to reach federation member {}: {}", peer, error);
+                }
+                SwarmEvent::Behaviour(ServerBehaviourEvent::Rendezvous(
+                    request_response::Event::InboundFailure { peer, error, .. },
+                )) => {
+                    warn!("Bad request from {}: {}", peer, error);
+                }
+                other => {
+                    debug!("Unhandled {:?}", other);
+                }
+            },
+            Some(request) = admin_rx.recv(), if options.admin.is_some() => {
+                handle_admin(
+                    &mut swarm,
+                    &mut registry,
+                    &policy,
+                    &mut federation,
+                    &connected,
+                    request,
+                );
+            }
+            _ = sync_tick.tick(), if !federation.is_empty() => {
+                federation.sync(&mut swarm, &registry, None);
+            }
+            _ = expiry_tick.tick() => {
+                for entry in registry.expire() {
+                    info!(
+                        "Registration of {} in namespace '{}' expired",
+                        entry.peer(),
+                        entry.namespace
+                    );
+                }
+                registry.flush();
+            }
+        }
+        federation.flush(&mut swarm);
+    }
+}
+
+/// Answers a request of `peer`; unregistrations get no response.
+fn handle_request(
+    registry: &mut Registry,
+    policy: &mut Policy,
+    federation: &mut Federation,
+    peer: PeerId,
+    request: Message,
+) -> Option<Message> {
+    match request {
+        Message::Register(registration) => {
+            let namespace = registration.namespace.clone();
+            let result = policy
+                .check(peer, &namespace)
+                .and_then(|()| registry.register(peer, registration));
+            match result {
+                Ok(ttl) => {
+                    info!(
+                        "Peer {} registered for namespace '{}' for {} seconds",
+                        peer, namespace, ttl
+                    );
+                    if let Some(entry) = registry.get(&namespace, peer) {
+                        federation.registered(entry);
+                    }
+                }
+                Err(code) => info!(
+                    "Refused registration of {} in namespace '{}': {:?}",
+                    peer, namespace, code
+                ),
+            }
+            Some(Message::RegisterResponse(result))
+        }
+        Message::Unregister(namespace) => {
+            if let Some(entry) = registry.unregister(peer, &namespace) {
+                info!("Peer {} unregistered from namespace '{}'", peer, namespace);
+                federation.unregistered(&entry);
+            }
+            None
+        }
+        Message::Discover {
+            namespace,
+            cookie,
+            limit,
+        } => {
+            let result = registry.discover(namespace.as_ref(), cookie.as_ref(), limit);
+            match &result {
+                Ok((registrations, _)) => info!(
+                    "Served peer {} with {} registrations",
+                    peer,
+                    registrations.len()
+                ),
+                Err(code) => info!("Refused discovery of {}: {:?}", peer, code),
+            }
+            Some(Message::DiscoverResponse(result))
+        }
+        Message::RegisterResponse(_) | Message::DiscoverResponse(_) => {
+            warn!("Peer {} sent a response as a request", peer);
+            None
+        }
+    }
+}
+
+/// Answers a request of the admin API.
+fn handle_admin(
+    swarm: &mut Swarm<ServerBehaviour>,
+    registry: &mut Registry,
+    policy: &Policy,
+    federation: &mut Federation,
+    connected: &Connected,
+    request: AdminRequest,
+) {
+    match request {
+        AdminRequest::Namespaces { reply } => {
+            let _ = reply.send(Ok(registry.namespaces()));
+        }
+        AdminRequest::Registrations { namespace, reply } => {
+            let registrations = registry
+                .iter()
+                .filter(|e| e.namespace == namespace)
+                .map(|e| RegistrationInfo {
+                    peer_id: e.peer().to_string(),
+                    addresses: e.record.addresses().iter().map(|a| a.to_string()).collect(),
+                    ttl: e.ttl,
+                    remaining: e.remaining(),
+                })
+                .collect();
+            let _ = reply.send(Ok(registrations));
+        }
+        AdminRequest::Evict {
+            namespace,
+            peer,
+            reply,
+        } => {
+            let result = match registry.unregister(peer, &namespace) {
+                Some(entry) => {
+                    info!("Evicted {} from namespace '{}'", peer, namespace);
+                    federation.unregistered(&entry);
+                    Ok(())
+                }
+                None => Err(AdminError::NotFound),
+            };
+            let _ = reply.send(result);
+        }
+        AdminRequest::Peers { reply } => {
+            let peers = connected
+                .iter()
+                .map(|(peer, connections)| PeerInfo {
+                    peer_id: peer.to_string(),
+                    addresses: connections.values().map(|a| a.to_string()).collect(),
+                    registrations: registry.iter().filter(|e| e.peer() == *peer).count(),
+                })
+                .collect();
+            let _ = reply.send(Ok(peers));
+        }
+        AdminRequest::Refused { reply } => {
+            let _ = reply.send(Ok(policy.refused().clone()));
+        }
+        AdminRequest::Bans { reply } => {
+            let _ = reply.send(Ok(registry.bans().map(|p| p.to_string()).collect()));
+        }
+        AdminRequest::Ban { peer, reply } => {
+            // 封禁会断开该节点的连接并拒绝新连接，其注册一并删除；
+            // 封禁名单随注册一起保存，其他成员同步来的副本也被拒绝
+            swarm.behaviour_mut().blocked.block_peer(peer);
+            let removed = registry.ban(peer);
+            for entry in &removed {
+                federation.unregistered(entry);
+            }
+            info!(
+                "Banned {} and dropped {} registrations",
+                peer,
+                removed.len()
+            );
+            let _ = reply.send(Ok(()));
+        }
+        AdminRequest::Unban { peer, reply } => {
+            let result = if registry.unban(peer) {
+                swarm.behaviour_mut().blocked.unblock_peer(peer);
+                info!("Unbanned {}", peer);
+                Ok(())
+            } else {
+                Err(AdminError::NotFound)
+            };
+            let _ = reply.send(result);
+        }
+    }
+}
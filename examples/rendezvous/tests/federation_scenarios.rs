@@ -0,0 +1,794 @@
+//! Scenarios for a federation of rendezvous points, see `rzv::federation`.
+//!
+//! ```text
+//! cargo test -p rendezvous --test federation_scenarios -- --nocapture
+//! ```
+//!
+//! Every scenario starts three servers in this process, each federated with
+//! the other two, and talks to them as clients.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    core::PeerRecord,
+    identity::Keypair,
+    rendezvous::{Namespace, Registration, Ttl},
+    request_response::{self, OutboundRequestId, ProtocolSupport},
+    swarm::{NetworkBehaviour, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Mutex,
+    task::JoinHandle,
+};
+
+use rzv::{
+    behaviour::{self, build_swarm},
+    federation::{self, FederationRequest, FederationResponse, Replica},
+    keyfile,
+    point::Point,
+    protocol::{self, Message, NewRegistration},
+    registrar::Registrars,
+    registry::{self, Limits, MIN_TTL},
+    server::{self, ServerOptions},
+};
+
+const SERVERS: usize = 3;
+/// First of the loopback ports the servers listen on
+const BASE_PORT: u16 = 64400;
+/// Port of the admin API of server 0, when a scenario enables it
+const ADMIN_PORT: u16 = 64410;
+const OP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the servers get to agree on a registration
+const CONVERGE_TIMEOUT: Duration = Duration::from_secs(15);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The servers listen on fixed ports, so the scenarios run one at a time
+static SERIAL: Mutex<()> = Mutex::const_new(());
+
+/// Runs `scenario` on a fresh cluster.
+async fn run<F>(name: &str, scenario: F) -> Result<()>
+where
+    F: AsyncFnOnce(&mut Cluster) -> Result<()>,
+{
+    let _serial = SERIAL.lock().await;
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .try_init();
+
+    let dir = std::env::temp_dir().join(format!("rzv-federation-{}-{}", std::process::id(), name));
+    std::fs::create_dir_all(&dir)?;
+    let mut cluster = Cluster::start(&dir, BASE_PORT).await?;
+    let result = scenario(&mut cluster).await;
+    cluster.shutdown().await;
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+/// Register at one server, discover at the others
+#[tokio::test(flavor = "multi_thread")]
+async fn registrations_replicate() -> Result<()> {
+    run("replicate", replicate).await
+}
+
+/// Renew at another server with a longer TTL
+#[tokio::test(flavor = "multi_thread")]
+async fn renewals_replicate() -> Result<()> {
+    run("refresh", refresh).await
+}
+
+/// Unregister at the server registered at
+#[tokio::test(flavor = "multi_thread")]
+async fn unregistrations_replicate() -> Result<()> {
+    run("unregister", unregister).await
+}
+
+/// Push registrations to a server without being a member
+#[tokio::test(flavor = "multi_thread")]
+async fn outsiders_are_refused() -> Result<()> {
+    run("outsider", outsider).await
+}
+
+/// Register while a server is down, then restart it
+#[tokio::test(flavor = "multi_thread")]
+async fn restarted_servers_catch_up() -> Result<()> {
+    run("catch-up", catch_up).await
+}
+
+/// Replicate to servers with a shorter --max-ttl or a ban of the peer
+#[tokio::test(flavor = "multi_thread")]
+async fn replicas_keep_local_limits() -> Result<()> {
+    run("bounded", bounded).await
+}
+
+/// Register through `rzv register --points 1` while its first point is down
+/// or refuses the namespace
+#[tokio::test(flavor = "multi_thread")]
+async fn registrars_fail_over() -> Result<()> {
+    run("fail-over", fail_over).await
+}
+
+/// Evict and ban through the admin API of server 0
+#[tokio::test(flavor = "multi_thread")]
+async fn admin_api_operates_the_federation() -> Result<()> {
+    run("admin", admin_api).await
+}
+
+async fn replicate(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("replicate");
+    let mut peer = Client::new()?;
+    let ttl = peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    println!("registered at server 0 for {} seconds", ttl);
+    cluster
+        .converge(&mut peer, &[1, 2], &namespace, |r| r.is_some())
+        .await?;
+    println!("servers 1 and 2 serve the registration");
+    Ok(())
+}
+
+async fn refresh(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("refresh");
+    let mut peer = Client::new()?;
+    peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    cluster
+        .converge(&mut peer, &[1, 2], &namespace, |r| r.is_some())
+        .await?;
+
+    let ttl = peer
+        .register(cluster.point(1), &namespace, 2 * MIN_TTL)
+        .await?;
+    println!("renewed at server 1 for {} seconds", ttl);
+    cluster
+        .converge(&mut peer, &[0, 1, 2], &namespace, |r| {
+            r.is_some_and(|r| r.ttl > MIN_TTL)
+        })
+        .await?;
+    println!("all servers serve the renewal");
+    Ok(())
+}
+
+async fn unregister(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("unregister");
+    let mut peer = Client::new()?;
+    peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    cluster
+        .converge(&mut peer, &[1, 2], &namespace, |r| r.is_some())
+        .await?;
+
+    peer.unregister(cluster.point(0), &namespace).await?;
+    println!("unregistered at server 0");
+    cluster
+        .converge(&mut peer, &[0, 1, 2], &namespace, |r| r.is_none())
+        .await?;
+    println!("no server serves the registration");
+    Ok(())
+}
+
+async fn outsider(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("outsider");
+    let mut outsider = Client::new()?;
+    let record = PeerRecord::new(&outsider.keypair, vec![outsider.addr()])?;
+    let replica = Replica {
+        namespace: namespace.to_string(),
+        record: registry::encode_record(&record),
+        ttl: MIN_TTL,
+        remaining_ms: MIN_TTL * 1000,
+    };
+    let response = outsider
+        .federate(
+            cluster.point(0),
+            FederationRequest::Registered(vec![replica]),
+        )
+        .await?;
+    match response {
+        Err(e) => println!("server 0 refused: {}", e),
+        Ok(taken) => bail!("server 0 took {} registrations from an outsider", taken),
+    }
+    let peer_id = outsider.peer_id();
+    if outsider
+        .discover(cluster.point(0), &namespace)
+        .await?
+        .iter()
+        .any(|r| r.record.peer_id() == peer_id)
+    {
+        bail!("server 0 serves the outsider's registration");
+    }
+    Ok(())
+}
+
+async fn catch_up(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("catch-up");
+    println!("stopping server 2");
+    cluster.stop(2).await;
+
+    let mut peer = Client::new()?;
+    peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    cluster
+        .converge(&mut peer, &[1], &namespace, |r| r.is_some())
+        .await?;
+    println!("registered at server 0 while server 2 was down");
+
+    println!("restarting server 2 without its store");
+    cluster.start_server(2)?;
+    cluster
+        .converge(&mut peer, &[2], &namespace, |r| r.is_some())
+        .await?;
+    println!("server 2 caught up");
+    Ok(())
+}
+
+async fn bounded(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("bounded");
+    let mut peer = Client::new()?;
+
+    println!("restarting server 1 with --max-ttl {}", MIN_TTL);
+    cluster.stop(1).await;
+    cluster.servers[1].limits.max_ttl = MIN_TTL;
+    cluster.start_server(1)?;
+    println!("restarting server 2 with a stored ban of the peer");
+    cluster.stop(2).await;
+    let store = cluster.dir.join("server-2.json");
+    let stored = serde_json::json!({
+        "seq": 0,
+        "registrations": [],
+        "bans": [peer.peer_id().to_string()],
+    });
+    std::fs::write(&store, serde_json::to_vec(&stored)?)?;
+    cluster.servers[2].store = Some(store);
+    cluster.start_server(2)?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let ttl = peer
+        .register(cluster.point(0), &namespace, 3 * MIN_TTL)
+        .await?;
+    println!("registered at server 0 for {} seconds", ttl);
+    cluster
+        .converge(&mut peer, &[1], &namespace, |r| {
+            r.is_some_and(|r| r.ttl <= MIN_TTL)
+        })
+        .await?;
+    println!("server 1 holds the replica for at most {} seconds", MIN_TTL);
+
+    // 服务器 0 同时推送给两个成员，服务器 1 收到后再给服务器 2 留出时间
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    // 服务器 2 拒绝被封禁节点的连接，由另一个节点来查询
+    let peer_id = peer.peer_id();
+    let mut observer = Client::new()?;
+    if observer
+        .discover(cluster.point(2), &namespace)
+        .await?
+        .iter()
+        .any(|r| r.record.peer_id() == peer_id)
+    {
+        bail!("server 2 serves the replica of a peer it banned");
+    }
+    println!("server 2 refused the replica of the banned peer");
+    Ok(())
+}
+
+async fn fail_over(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("fail-over");
+    println!("stopping server 0");
+    cluster.stop(0).await;
+    let mut peer = Client::new()?;
+    let registrar = cluster.registrar(&peer, &[0, 1], &namespace);
+    let result = cluster
+        .converge(&mut peer, &[1], &namespace, |r| r.is_some())
+        .await;
+    registrar.abort();
+    result?;
+    println!("the registrar moved from server 0 to server 1");
+
+    println!("restarting server 0 with a policy refusing the namespace");
+    let policy = cluster.dir.join("policy-0.json");
+    let rules = serde_json::json!({ "namespaces": { namespace.to_string(): [] } });
+    std::fs::write(&policy, serde_json::to_vec(&rules)?)?;
+    cluster.servers[0].policy = Some(policy.clone());
+    cluster.start_server(0)?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut peer = Client::new()?;
+    if peer
+        .register(cluster.point(0), &namespace, MIN_TTL)
+        .await
+        .is_ok()
+    {
+        bail!("server 0 took a registration its policy refuses");
+    }
+    let registrar = cluster.registrar(&peer, &[0, 2], &namespace);
+    let result = cluster
+        .converge(&mut peer, &[2], &namespace, |r| r.is_some())
+        .await;
+    registrar.abort();
+    result?;
+    println!("the registrar moved from server 0 to server 2");
+
+    println!("restarting server 2 with the same policy");
+    cluster.stop(2).await;
+    cluster.servers[2].policy = Some(policy);
+    cluster.start_server(2)?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let registrar = cluster.registrar(&peer, &[0, 2], &namespace);
+    match with_timeout(async { Ok(registrar.await?) }).await? {
+        Ok(()) => bail!("the registrar stopped without an error"),
+        Err(e) => println!("the registrar gave up once both servers refused: {}", e),
+    }
+    Ok(())
+}
+
+async fn admin_api(cluster: &mut Cluster) -> Result<()> {
+    let namespace = Namespace::from_static("admin");
+    println!("restarting server 0 with its admin API");
+    cluster.stop(0).await;
+    let admin: SocketAddr = ([127, 0, 0, 1], ADMIN_PORT).into();
+    cluster.servers[0].admin = Some(admin);
+    cluster.start_server(0)?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let mut peer = Client::new()?;
+    let peer_id = peer.peer_id().to_string();
+    peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    cluster
+        .converge(&mut peer, &[1, 2], &namespace, |r| r.is_some())
+        .await?;
+
+    let (status, namespaces) = http(admin, "GET", "/namespaces").await?;
+    ensure_eq(status, 200)?;
+    ensure_eq(
+        json(&namespaces)?,
+        serde_json::json!([{ "name": "admin", "registrations": 1 }]),
+    )?;
+    let (status, registrations) = http(admin, "GET", "/namespaces/admin").await?;
+    ensure_eq(status, 200)?;
+    let registrations = json(&registrations)?;
+    ensure_eq(registrations[0]["peer_id"].clone(), peer_id.clone().into())?;
+    ensure_eq(
+        registrations[0]["addresses"].clone(),
+        serde_json::json!([peer.addr().to_string()]),
+    )?;
+    ensure_eq(registrations[0]["ttl"].clone(), MIN_TTL.into())?;
+    if registrations[0]["remaining"]
+        .as_u64()
+        .is_none_or(|r| r > MIN_TTL)
+    {
+        bail!("unexpected registrations {}", registrations);
+    }
+    let (status, peers) = http(admin, "GET", "/peers").await?;
+    ensure_eq(status, 200)?;
+    let peers = json(&peers)?;
+    let shaped = peers.as_array().is_some_and(|peers| {
+        peers.iter().all(|p| {
+            p["peer_id"].is_string() && p["addresses"].is_array() && p["registrations"].is_u64()
+        })
+    });
+    if !shaped {
+        bail!("unexpected peers {}", peers);
+    }
+    let (status, refused) = http(admin, "GET", "/refused").await?;
+    ensure_eq((status, json(&refused)?), (200, serde_json::json!({})))?;
+    println!("the admin API lists namespaces, registrations, peers and refusals");
+
+    let stranger = PeerId::random();
+    let evict_stranger = format!("/namespaces/admin/{}", stranger);
+    ensure_eq(http(admin, "DELETE", &evict_stranger).await?.0, 404)?;
+    ensure_eq(
+        http(admin, "DELETE", &format!("/bans/{}", stranger))
+            .await?
+            .0,
+        404,
+    )?;
+    ensure_eq(
+        http(admin, "DELETE", "/namespaces/admin/nobody").await?.0,
+        400,
+    )?;
+    ensure_eq(http(admin, "GET", "/nowhere").await?.0, 404)?;
+    println!("unknown peers and routes are not found, invalid peer ids bad requests");
+
+    ensure_eq(
+        http(admin, "PUT", &format!("/bans/{}", peer_id)).await?.0,
+        204,
+    )?;
+    let (status, bans) = http(admin, "GET", "/bans").await?;
+    ensure_eq((status, json(&bans)?), (200, serde_json::json!([peer_id])))?;
+    let (_, registrations) = http(admin, "GET", "/namespaces/admin").await?;
+    ensure_eq(json(&registrations)?, serde_json::json!([]))?;
+    cluster
+        .converge(&mut peer, &[1, 2], &namespace, |r| r.is_none())
+        .await?;
+    println!("the ban dropped the registration at every server");
+
+    ensure_eq(
+        http(admin, "DELETE", &format!("/bans/{}", peer_id))
+            .await?
+            .0,
+        204,
+    )?;
+    let (_, bans) = http(admin, "GET", "/bans").await?;
+    ensure_eq(json(&bans)?, serde_json::json!([]))?;
+    peer.register(cluster.point(0), &namespace, MIN_TTL).await?;
+    println!("the unbanned peer registers again");
+    Ok(())
+}
+
+/// Three federated servers on loopback ports.
+struct Cluster {
+    dir: PathBuf,
+    points: Vec<(PeerId, Multiaddr)>,
+    servers: Vec<Server>,
+}
+
+/// How a server of the cluster is started, and its task while it runs.
+struct Server {
+    key_file: PathBuf,
+    limits: Limits,
+    store: Option<PathBuf>,
+    policy: Option<PathBuf>,
+    admin: Option<SocketAddr>,
+    task: Option<JoinHandle<Result<()>>>,
+}
+
+impl Cluster {
+    async fn start(dir: &std::path::Path, base_port: u16) -> Result<Self> {
+        let mut cluster = Cluster {
+            dir: dir.to_path_buf(),
+            points: Vec::new(),
+            servers: Vec::new(),
+        };
+        for i in 0..SERVERS {
+            let key_file = dir.join(format!("server-{}.key", i));
+            let peer_id = keyfile::load_or_generate(&key_file)?.public().to_peer_id();
+            let addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", base_port + i as u16).parse()?;
+            cluster.points.push((peer_id, addr));
+            cluster.servers.push(Server {
+                key_file,
+                limits: Limits::default(),
+                store: None,
+                policy: None,
+                admin: None,
+                task: None,
+            });
+        }
+        for i in 0..SERVERS {
+            cluster.start_server(i)?;
+        }
+        // 等服务器开始监听
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        Ok(cluster)
+    }
+
+    fn point(&self, server: usize) -> (PeerId, Multiaddr) {
+        self.points[server].clone()
+    }
+
+    /// Starts `server` with its old identity, limits and store; without a
+    /// store it starts with an empty registry.
+    fn start_server(&mut self, server: usize) -> Result<()> {
+        let federation = (0..SERVERS)
+            .filter(|&i| i != server)
+            .map(|i| {
+                let (peer, addr) = &self.points[i];
+                addr.clone()
+                    .with_p2p(*peer)
+                    .map_err(|a| anyhow!("{} has a peer id", a))
+            })
+            .collect::<Result<_>>()?;
+        let config = &self.servers[server];
+        let options = ServerOptions {
+            listen: self.points[server].1.clone(),
+            key_file: config.key_file.clone(),
+            export_addr: None,
+            store: config.store.clone(),
+            limits: config.limits.clone(),
+            policy: config.policy.clone(),
+            admin: config.admin,
+            federation,
+            idle_timeout: IDLE_TIMEOUT,
+        };
+        self.servers[server].task = Some(tokio::spawn(server::run(options)));
+        Ok(())
+    }
+
+    /// Registers `peer` in `namespace` at the first of `servers` that takes
+    /// it, the others standing by, until aborted.
+    fn registrar(
+        &self,
+        peer: &Client,
+        servers: &[usize],
+        namespace: &Namespace,
+    ) -> JoinHandle<Result<()>> {
+        let points = servers
+            .iter()
+            .map(|&server| {
+                let (peer_id, addr) = self.point(server);
+                Point {
+                    peer_id,
+                    addrs: vec![addr],
+                }
+            })
+            .collect();
+        let keypair = peer.keypair.clone();
+        let external_addr = peer.addr();
+        let namespace = namespace.clone();
+        tokio::spawn(async move {
+            let mut swarm = build_swarm(keypair, IDLE_TIMEOUT, behaviour::ClientBehaviour::new)?;
+            swarm.add_external_address(external_addr);
+            let mut registrars =
+                Registrars::new(points, Some(1), vec![namespace], Some(MIN_TTL), Vec::new())?;
+            loop {
+                let deadline = registrars.deadline(&swarm);
+                tokio::select! {
+                    event = swarm.select_next_some() => registrars.on_event(&mut swarm, &event)?,
+                    _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                        if deadline.is_some() => registrars.run_due(&mut swarm)?,
+                }
+            }
+        })
+    }
+
+    async fn stop(&mut self, server: usize) {
+        if let Some(handle) = self.servers[server].task.take() {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
+
+    async fn shutdown(mut self) {
+        for server in 0..SERVERS {
+            self.stop(server).await;
+        }
+    }
+
+    /// Waits until the registration of `peer` in `namespace` satisfies
+    /// `expected` at each of `servers`.
+    async fn converge(
+        &self,
+        peer: &mut Client,
+        servers: &[usize],
+        namespace: &Namespace,
+        expected: impl Fn(Option<&Registration>) -> bool,
+    ) -> Result<()> {
+        let peer_id = peer.peer_id();
+        for &server in servers {
+            let deadline = Instant::now() + CONVERGE_TIMEOUT;
+            loop {
+                if let Some(handle) = &self.servers[server].task {
+                    if handle.is_finished() {
+                        bail!("server {} has stopped", server);
+                    }
+                }
+                let registrations = peer.discover(self.point(server), namespace).await?;
+                let registration = registrations.iter().find(|r| r.record.peer_id() == peer_id);
+                if expected(registration) {
+                    break;
+                }
+                if Instant::now() > deadline {
+                    bail!(
+                        "server {} did not converge, it holds {:?}",
+                        server,
+                        registration.map(|r| r.ttl)
+                    );
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct ClientBehaviour {
+    rendezvous: request_response::Behaviour<protocol::Codec>,
+    federation: request_response::json::Behaviour<FederationRequest, FederationResponse>,
+}
+
+/// A peer talking to the servers, as a rendezvous client or as a would-be
+/// federation member.
+struct Client {
+    keypair: Keypair,
+    swarm: Swarm<ClientBehaviour>,
+}
+
+impl Client {
+    fn new() -> Result<Self> {
+        let keypair = Keypair::generate_ed25519();
+        let swarm = build_swarm(keypair.clone(), IDLE_TIMEOUT, |_| ClientBehaviour {
+            rendezvous: request_response::Behaviour::with_codec(
+                protocol::Codec,
+                [(protocol::PROTOCOL, ProtocolSupport::Outbound)],
+                request_response::Config::default(),
+            ),
+            federation: request_response::json::Behaviour::new(
+                [(federation::PROTOCOL, ProtocolSupport::Outbound)],
+                request_response::Config::default(),
+            ),
+        })?;
+        Ok(Self { keypair, swarm })
+    }
+
+    fn peer_id(&self) -> PeerId {
+        self.keypair.public().to_peer_id()
+    }
+
+    /// The address put in the peer records; nobody dials it.
+    fn addr(&self) -> Multiaddr {
+        "/ip4/127.0.0.1/tcp/9".parse().expect("valid address")
+    }
+
+    async fn register(
+        &mut self,
+        (point, addr): (PeerId, Multiaddr),
+        namespace: &Namespace,
+        ttl: Ttl,
+    ) -> Result<Ttl> {
+        let record = PeerRecord::new(&self.keypair, vec![self.addr()])?;
+        self.swarm.add_peer_address(point, addr);
+        let request = Message::Register(NewRegistration {
+            namespace: namespace.clone(),
+            record,
+            ttl: Some(ttl),
+        });
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .rendezvous
+            .send_request(&point, request);
+        match self.rendezvous_response(id).await? {
+            Message::RegisterResponse(Ok(ttl)) => Ok(ttl),
+            Message::RegisterResponse(Err(code)) => bail!("registration refused: {:?}", code),
+            other => bail!("unexpected response {:?}", other),
+        }
+    }
+
+    async fn unregister(
+        &mut self,
+        (point, addr): (PeerId, Multiaddr),
+        namespace: &Namespace,
+    ) -> Result<()> {
+        self.swarm.add_peer_address(point, addr);
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .rendezvous
+            .send_request(&point, Message::Unregister(namespace.clone()));
+        // 注销没有应答，服务器关闭流即表示已处理
+        match self.rendezvous_response(id).await {
+            Ok(other) => bail!("unexpected response {:?}", other),
+            Err(_) => Ok(()),
+        }
+    }
+
+    async fn discover(
+        &mut self,
+        (point, addr): (PeerId, Multiaddr),
+        namespace: &Namespace,
+    ) -> Result<Vec<Registration>> {
+        self.swarm.add_peer_address(point, addr);
+        let request = Message::Discover {
+            namespace: Some(namespace.clone()),
+            cookie: None,
+            limit: None,
+        };
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .rendezvous
+            .send_request(&point, request);
+        match self.rendezvous_response(id).await? {
+            Message::DiscoverResponse(Ok((registrations, _))) => Ok(registrations),
+            Message::DiscoverResponse(Err(code)) => bail!("discovery refused: {:?}", code),
+            other => bail!("unexpected response {:?}", other),
+        }
+    }
+
+    async fn federate(
+        &mut self,
+        (point, addr): (PeerId, Multiaddr),
+        request: FederationRequest,
+    ) -> Result<FederationResponse> {
+        self.swarm.add_peer_address(point, addr);
+        let id = self
+            .swarm
+            .behaviour_mut()
+            .federation
+            .send_request(&point, request);
+        with_timeout(async {
+            loop {
+                match self.swarm.select_next_some().await {
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Federation(
+                        request_response::Event::Message {
+                            message:
+                                request_response::Message::Response {
+                                    request_id,
+                                    response,
+                                },
+                            ..
+                        },
+                    )) if request_id == id => return Ok(response),
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Federation(
+                        request_response::Event::OutboundFailure {
+                            request_id, error, ..
+                        },
+                    )) if request_id == id => bail!("federation request failed: {}", error),
+                    _ => {}
+                }
+            }
+        })
+        .await
+    }
+
+    async fn rendezvous_response(&mut self, id: OutboundRequestId) -> Result<Message> {
+        with_timeout(async {
+            loop {
+                match self.swarm.select_next_some().await {
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                        request_response::Event::Message {
+                            message:
+                                request_response::Message::Response {
+                                    request_id,
+                                    response,
+                                },
+                            ..
+                        },
+                    )) if request_id == id => return Ok(response),
+                    SwarmEvent::Behaviour(ClientBehaviourEvent::Rendezvous(
+                        request_response::Event::OutboundFailure {
+                            request_id, error, ..
+                        },
+                    )) if request_id == id => bail!("request failed: {}", error),
+                    _ => {}
+                }
+            }
+        })
+        .await
+    }
+}
+
+/// Sends a bodyless HTTP request, returns the status and the body.
+async fn http(addr: SocketAddr, method: &str, path: &str) -> Result<(u16, String)> {
+    with_timeout(async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            method, path, addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .context("response without a head")?;
+        let status = head
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .context("response without a status")?;
+        Ok((status, body.to_string()))
+    })
+    .await
+}
+
+fn json(body: &str) -> Result<serde_json::Value> {
+    serde_json::from_str(body).with_context(|| format!("not JSON: {:?}", body))
+}
+
+fn ensure_eq<T: PartialEq + std::fmt::Debug>(actual: T, expected: T) -> Result<()> {
+    if actual != expected {
+        bail!("expected {:?}, got {:?}", expected, actual);
+    }
+    Ok(())
+}
+
+async fn with_timeout<T>(future: impl Future<Output = Result<T>>) -> Result<T> {
+    tokio::time::timeout(OP_TIMEOUT, future)
+        .await
+        .context("no response in time")?
+}